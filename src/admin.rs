@@ -0,0 +1,218 @@
+use std::time::Instant;
+use tide::{log, Request, Response, StatusCode};
+use tide::prelude::*;
+use crate::auth;
+use crate::letterboxd;
+use crate::spotify;
+use crate::pause;
+
+/// Outcome of refreshing one source
+#[derive(Debug, serde::Serialize)]
+struct RebuildResult {
+    source: &'static str,
+    status: &'static str,
+    item_count: usize,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl RebuildResult {
+    fn new(source: &'static str, start_time: Instant, result: Result<usize, String>) -> Self {
+        let duration_ms = start_time.elapsed().as_millis();
+        match result {
+            Ok(item_count) => {
+                log::info!("Rebuilt {} cache with {} items in {}ms", source, item_count, duration_ms);
+                RebuildResult { source, status: "ok", item_count, duration_ms, error: None }
+            },
+            Err(e) => {
+                log::error!("Failed to rebuild {} cache: {}", source, e);
+                RebuildResult { source, status: "error", item_count: 0, duration_ms, error: Some(e) }
+            }
+        }
+    }
+
+    /// A paused source is left alone rather than refetched
+    fn paused(source: &'static str) -> Self {
+        log::info!("Skipping rebuild of paused {} source", source);
+        RebuildResult { source, status: "paused", item_count: 0, duration_ms: 0, error: None }
+    }
+}
+
+async fn rebuild_letterboxd(feed_url: &str) -> RebuildResult {
+    let start_time = Instant::now();
+    if pause::is_paused("letterboxd") {
+        return RebuildResult::paused("letterboxd");
+    }
+    letterboxd::invalidate_feed(feed_url).await;
+    let result = letterboxd::fetch_letterboxd_feed(feed_url).await
+        .map(|movies| movies.len());
+    RebuildResult::new("letterboxd", start_time, result)
+}
+
+async fn rebuild_spotify() -> RebuildResult {
+    let start_time = Instant::now();
+    if pause::is_paused("spotify") {
+        return RebuildResult::paused("spotify");
+    }
+    spotify::invalidate_caches().await;
+    let result = spotify::fetch_recently_played().await
+        .map(|recently_played| recently_played.tracks.len());
+    RebuildResult::new("spotify", start_time, result)
+}
+
+/// Refetch every source concurrently, repopulating the caches, and report the
+/// result for each. Requires the API key.
+pub async fn rebuild(req: Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    log::info!("Rebuilding all caches");
+    let (letterboxd, spotify) = futures::join!(rebuild_letterboxd(letterboxd::DEFAULT_FEED_URL), rebuild_spotify());
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "results": [letterboxd, spotify] }));
+    Ok(res)
+}
+
+/// Pause the source named in the path, so its endpoint returns 503 and the
+/// aggregator skips it without contacting upstream. Requires the API key.
+pub async fn pause_source(req: Request<()>) -> tide::Result<Response> {
+    set_paused(req, true)
+}
+
+/// Resume a paused source. Requires the API key.
+pub async fn resume_source(req: Request<()>) -> tide::Result<Response> {
+    set_paused(req, false)
+}
+
+/// Replace the genres excluded from Spotify tracks with `{"genres": [...]}`, until
+/// the next restart. The cached tracks are dropped so the list applies to the next
+/// fetch. Requires the API key.
+pub async fn set_excluded_genres(mut req: Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    let body: serde_json::Value = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => {
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body(json!({"error": "Request body is not valid JSON"}))
+                .build());
+        }
+    };
+    let Some(genres) = body.get("genres").and_then(|value| serde_json::from_value::<Vec<String>>(value.clone()).ok()) else {
+        return Ok(Response::builder(StatusCode::BadRequest)
+            .body(json!({"error": "'genres' must be an array of strings"}))
+            .build());
+    };
+
+    let genres = spotify::set_excluded_genres(&genres).await;
+    log::info!("Excluded genres set to {:?}", genres);
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "genres": genres }));
+    Ok(res)
+}
+
+fn set_paused(req: Request<()>, paused: bool) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    let name = req.param("name").unwrap_or_default();
+    if !pause::set_paused(name, paused) {
+        let mut res = Response::new(StatusCode::NotFound);
+        res.set_content_type("application/json");
+        res.set_body(json!({ "error": format!("Unknown source '{}'", name) }));
+        return Ok(res);
+    }
+
+    log::info!("{} source {}", if paused { "Paused" } else { "Resumed" }, name);
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "source": name, "paused": paused }));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockUpstream};
+
+    // Pausing is global, so tests that pause a source or depend on one not being paused take turns
+    static PAUSE_LOCK: async_std::sync::Mutex<()> = async_std::sync::Mutex::new(());
+
+    #[async_std::test]
+    async fn rebuild_reports_each_source_and_repopulates_the_cache() {
+        let _guard = PAUSE_LOCK.lock().await;
+        let body = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel><title>Films</title>
+<item><title>One</title><letterboxd:filmTitle>One</letterboxd:filmTitle></item>
+<item><title>Two</title><letterboxd:filmTitle>Two</letterboxd:filmTitle></item>
+</channel></rss>"#;
+        let feed = MockUpstream::always(test_support::ok("application/rss+xml", body)).await;
+        let feed_url = feed.url("/rss");
+
+        let (letterboxd_result, spotify_result) = futures::join!(rebuild_letterboxd(&feed_url), rebuild_spotify());
+
+        assert_eq!(serde_json::to_value(&letterboxd_result).unwrap()["status"], "ok");
+        assert_eq!(letterboxd_result.item_count, 2);
+        assert_eq!(letterboxd::cached_movies(&feed_url).await.map(|movies| movies.len()), Some(2));
+
+        // Spotify has no credentials in tests, which is reported rather than hidden
+        assert_eq!(spotify_result.source, "spotify");
+        assert_eq!(spotify_result.status, "error");
+        assert!(spotify_result.error.is_some());
+    }
+
+    #[async_std::test]
+    async fn a_paused_source_answers_503_until_resumed() {
+        let _guard = PAUSE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/spotify").get(spotify::get_spotify_tracks);
+        app.at("/admin/source/:name/pause").post(pause_source);
+        app.at("/admin/source/:name/resume").post(resume_source);
+
+        // Only Spotify is paused here, since it is never fetched in tests anyway
+        let mut req = tide::http::Request::post("http://localhost/admin/source/spotify/pause");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body, json!({ "source": "spotify", "paused": true }));
+
+        let mut req = tide::http::Request::get("http://localhost/spotify");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["paused"], true);
+
+        let mut req = tide::http::Request::post("http://localhost/admin/source/spotify/resume");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(!pause::is_paused("spotify"));
+
+        // Resumed, the endpoint goes on to its usual checks (Spotify has no credentials in tests)
+        let mut req = tide::http::Request::get("http://localhost/spotify");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body.get("paused"), None);
+
+        let mut req = tide::http::Request::post("http://localhost/admin/source/myspace/pause");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let req = tide::http::Request::post("http://localhost/admin/source/spotify/pause");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(!pause::is_paused("spotify"));
+    }
+}
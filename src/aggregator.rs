@@ -1,89 +1,889 @@
 use tide::{log, Request, Response, StatusCode};
 use tide::prelude::*;
+use std::collections::BTreeMap;
 use std::time::Instant;
-use crate::url_handlers::LAST_READ_URLS;
+use chrono::{DateTime, Datelike, NaiveDate, SecondsFormat, Utc};
+use crate::auth;
+use crate::url_handlers;
 use crate::letterboxd;
 use crate::spotify;
+use crate::params::{self, QueryParams};
+use crate::select;
+use crate::pause;
+use crate::stats;
+use crate::profiles::{self, Profile};
 
-/// Aggregated data response structure
+/// Outcome of loading a source, so clients can tell an empty source from a failed or disabled one
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SourceStatus {
+    Ok,
+    Error,
+    Unconfigured,
+    Paused,
+}
+
+/// Aggregated data response structure. Sources left out of the request are omitted.
 #[derive(Debug, serde::Serialize)]
 struct AggregatedData {
-    urls: Vec<String>,
-    movies: Vec<letterboxd::LetterboxdMovie>,
-    tracks: Vec<spotify::SpotifyTrack>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urls: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    movies: Option<Vec<letterboxd::LetterboxdMovie>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracks: Option<Vec<spotify::SpotifyTrack>>,
+    /// Outcome of each included source, keyed `urls`, `movies` and `tracks`
+    status: BTreeMap<&'static str, SourceStatus>,
+    /// Time of the newest item per source, for sources with dated items
+    last_modified: BTreeMap<&'static str, String>,
+}
+
+/// The same data serialized with the field names some frontends expect, selected with `naming=frontend`
+#[derive(Debug, serde::Serialize)]
+struct FrontendAggregatedData<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_links: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_films: Option<&'a [letterboxd::LetterboxdMovie]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_tracks: Option<&'a [spotify::SpotifyTrack]>,
+    status: &'a BTreeMap<&'static str, SourceStatus>,
+    last_modified: &'a BTreeMap<&'static str, String>,
+}
+
+impl<'a> From<&'a AggregatedData> for FrontendAggregatedData<'a> {
+    fn from(data: &'a AggregatedData) -> Self {
+        FrontendAggregatedData {
+            recent_links: data.urls.as_deref(),
+            recent_films: data.movies.as_deref(),
+            recent_tracks: data.tracks.as_deref(),
+            status: &data.status,
+            last_modified: &data.last_modified,
+        }
+    }
+}
+
+/// Query parameters accepted by `/aggregated` and `/aggregated/{profile}`
+const AGGREGATED_PARAMS: &[&str] = &["feed_url", "limit", "spotify_limit", "movies_limit", "url_limit", "no_cache", "naming", "select", "exclude_now_playing", "modified_since", "show_spoilers", "sources"];
+
+/// Most movies a request can ask for, about the length of a Letterboxd feed
+const MAX_MOVIE_LIMIT: usize = 50;
+
+/// Query parameters accepted by `/recent`
+const RECENT_PARAMS: &[&str] = &["total"];
+
+/// Items returned by `/recent` by default, and the most a request can ask for
+const DEFAULT_RECENT_TOTAL: usize = 10;
+const MAX_RECENT_TOTAL: usize = 50;
+
+/// Query parameters accepted by `/counts`
+const COUNTS_PARAMS: &[&str] = &["feed_url", "fetch"];
+
+/// An item of any source in the merged `/recent` list, serialized as the item's own
+/// fields plus a `type` field (`url`, `movie` or `track`) saying which kind it is
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum RecentItem {
+    Url(url_handlers::UrlEntry),
+    Movie(letterboxd::LetterboxdMovie),
+    Track(spotify::SpotifyTrack),
+}
+
+impl RecentItem {
+    /// When the item was published or played, if known. URLs have no timestamps.
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+        match self {
+            RecentItem::Url(_) => None,
+            RecentItem::Movie(movie) => DateTime::parse_from_rfc2822(movie.pub_date.as_deref()?).ok()
+                .map(|date| date.with_timezone(&Utc)),
+            RecentItem::Track(track) => DateTime::parse_from_rfc3339(&track.played_at).ok()
+                .map(|date| date.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// Which sources and how many items of each to include in an aggregated response
+#[derive(Debug, Clone)]
+struct AggregateOptions {
+    feed_url: String,
+    include_urls: bool,
+    include_movies: bool,
+    include_tracks: bool,
+    url_limit: Option<usize>,
+    movie_limit: usize,
+    track_limit: usize,
+    show_spoilers: bool,
+}
+
+impl Default for AggregateOptions {
+    fn default() -> Self {
+        AggregateOptions {
+            feed_url: letterboxd::DEFAULT_FEED_URL.to_string(),
+            include_urls: true,
+            include_movies: true,
+            include_tracks: true,
+            url_limit: None,
+            movie_limit: *letterboxd::NUMBER_OF_MOVIES_TO_SHOW,
+            track_limit: 6,
+            show_spoilers: false,
+        }
+    }
+}
+
+impl AggregateOptions {
+    /// Options for a configured profile, starting from the defaults
+    fn from_profile(profile: &Profile) -> Self {
+        let defaults = AggregateOptions::default();
+        let includes = |source: &str| profile.sources.as_ref()
+            .is_none_or(|sources| sources.iter().any(|s| s == source));
+
+        AggregateOptions {
+            feed_url: profile.feed.clone().unwrap_or(defaults.feed_url),
+            include_urls: includes("urls"),
+            include_movies: includes("letterboxd"),
+            include_tracks: includes("spotify"),
+            url_limit: profile.limits.urls,
+            movie_limit: profile.limits.letterboxd.unwrap_or(defaults.movie_limit).min(MAX_MOVIE_LIMIT),
+            track_limit: profile.limits.spotify.unwrap_or(defaults.track_limit).min(*spotify::MAX_LIMIT),
+            show_spoilers: defaults.show_spoilers,
+        }
+    }
+
+    /// Override options with any given in the query string, or describe the invalid one
+    fn with_params(mut self, params: &QueryParams) -> Result<Self, String> {
+        // Only the listed sources are fetched and included, named as in the response or as in profiles
+        if let Some(sources) = params.get("sources") {
+            self.include_urls = false;
+            self.include_movies = false;
+            self.include_tracks = false;
+            for source in sources.split(',').map(str::trim).filter(|source| !source.is_empty()) {
+                match source {
+                    "urls" => self.include_urls = true,
+                    "movies" | "letterboxd" => self.include_movies = true,
+                    "tracks" | "spotify" => self.include_tracks = true,
+                    other => return Err(format!("Unknown source '{}', expected urls, movies or tracks", other)),
+                }
+            }
+        }
+        if let Some(feed_url) = params.get("feed_url") {
+            self.feed_url = feed_url.to_string();
+        }
+        // `limit` is the older name for `spotify_limit`
+        if let Some(limit) = params.parse::<usize>("spotify_limit").or_else(|| params.parse("limit")) {
+            self.track_limit = limit.min(*spotify::MAX_LIMIT);
+        }
+        if let Some(limit) = params.parse::<usize>("movies_limit") {
+            self.movie_limit = limit.min(MAX_MOVIE_LIMIT);
+        }
+        if let Some(url_limit) = params.parse::<usize>("url_limit") {
+            self.url_limit = Some(url_limit);
+        }
+        self.show_spoilers = params.flag("show_spoilers");
+        Ok(self)
+    }
 }
 
 /// Endpoint that aggregates data from URLs, Letterboxd, and Spotify
 /// This endpoint does not require authentication
 pub async fn get_aggregated_data(req: Request<()>) -> tide::Result<Response> {
+    respond(req, AggregateOptions::default()).await
+}
+
+/// Aggregated data shaped by a named profile from the `PROFILES` configuration.
+/// Unknown profiles return 404. This endpoint does not require authentication.
+pub async fn get_profile_data(req: Request<()>) -> tide::Result<Response> {
+    let name = req.param("profile").unwrap_or_default();
+    match profiles::get(name) {
+        Some(profile) => respond(req, AggregateOptions::from_profile(profile)).await,
+        None => {
+            let mut res = Response::new(StatusCode::NotFound);
+            res.set_content_type("application/json");
+            res.set_body(json!({ "error": format!("Unknown profile '{}'", name) }));
+            Ok(res)
+        }
+    }
+}
+
+async fn respond(req: Request<()>, options: AggregateOptions) -> tide::Result<Response> {
     let start_time = Instant::now();
     log::info!("Processing aggregated data request");
 
     // Get optional parameters from query
-    let letterboxd_feed = req.url().query_pairs()
-        .find(|(k, _)| k == "feed_url")
-        .map(|(_, v)| v.to_string())
-        .unwrap_or_else(|| "https://letterboxd.com/atropos_Dad/rss".to_string());
-        
-    let spotify_limit = req.url().query_pairs()
-        .find(|(k, _)| k == "limit")
-        .and_then(|(_, v)| v.parse::<usize>().ok())
-        .unwrap_or(6);
+    let params = match QueryParams::from_request(&req, AGGREGATED_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    let options = match options.with_params(&params) {
+        Ok(options) => options,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
         
-    let no_cache = req.url().query_pairs()
-        .find(|(k, _)| k == "no_cache")
-        .map(|(_, v)| v == "true")
-        .unwrap_or(false);
+    let no_cache = params.flag("no_cache");
+    
+    // Field naming scheme for the response, defaulting to the Rust field names
+    let frontend_naming = match params.get("naming") {
+        None | Some("default") => false,
+        Some("frontend") => true,
+        Some(other) => return Ok(params::bad_request(&format!("Unknown naming scheme '{}'", other))),
+    };
+
+    // Only return sources with items newer than this RFC 3339 timestamp, for delta sync
+    let modified_since = match params.get("modified_since") {
+        None => None,
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(timestamp) => Some(timestamp.with_timezone(&Utc)),
+            Err(_) => return Ok(params::bad_request(&format!("Invalid modified_since '{}', expected an RFC 3339 timestamp", value))),
+        },
+    };
 
-    // Log cache preferences
-    if no_cache {
-        log::info!("Request to bypass cache, but this is not fully implemented in the aggregated endpoint");
+    // Drop the cached data of the included sources, and the Spotify token, so they are fetched fresh and cached
+    // again for later requests. Only for authenticated callers, so anonymous requests can't force upstream fetches.
+    if no_cache && auth::validate_api_key(&req) {
+        if options.include_movies {
+            letterboxd::invalidate_feed(&options.feed_url).await;
+        }
+        if options.include_tracks {
+            spotify::invalidate_caches().await;
+        }
+        log::info!("Caches cleared for aggregated request due to no_cache parameter");
+    } else if no_cache {
+        log::info!("Ignoring no_cache on aggregated request without a valid API key");
     }
 
-    // Fetch URLs from the static queue
-    let urls = {
-        let urls_lock = LAST_READ_URLS.lock().unwrap();
-        urls_lock.iter().cloned().collect::<Vec<String>>()
-    };
-    log::info!("Retrieved {} URLs", urls.len());
+    let mut source_status = BTreeMap::new();
 
-    // Fetch Letterboxd movies
-    let movies = match letterboxd::fetch_letterboxd_feed(&letterboxd_feed).await {
-        Ok(movies) => {
-            log::info!("Retrieved {} Letterboxd movies", movies.len());
-            movies
-        },
-        Err(e) => {
-            log::error!("Error fetching Letterboxd data: {}", e);
-            vec![]
+    // Fetch URLs from the static queue, keeping only the most recent url_limit
+    let urls = options.include_urls.then(|| {
+        let urls = url_handlers::current_urls(options.url_limit).into_iter()
+            .map(|entry| entry.url)
+            .collect::<Vec<String>>();
+        log::info!("Retrieved {} URLs", urls.len());
+        source_status.insert("urls", SourceStatus::Ok);
+        urls
+    });
+
+    // Fetch Letterboxd movies and Spotify tracks concurrently. Each yields its items
+    // and status, with an empty list when the source fails, so one outage doesn't blank the other.
+    let feed_urls = [options.feed_url.clone()];
+    let load_movies = async {
+        if !options.include_movies {
+            return (None, None);
+        }
+        if pause::is_paused("letterboxd") {
+            return (Some(vec![]), Some(SourceStatus::Paused));
+        }
+        match letterboxd::fetch_movies(&feed_urls, letterboxd::DedupScope::Feed, options.movie_limit, None).await {
+            Ok(mut movies) => {
+                log::info!("Retrieved {} Letterboxd movies", movies.len());
+                if !options.show_spoilers {
+                    movies.iter_mut().for_each(letterboxd::LetterboxdMovie::hide_spoilers);
+                }
+                (Some(movies), Some(SourceStatus::Ok))
+            },
+            Err(e) => {
+                log::error!("Error fetching Letterboxd data: {}", e);
+                (Some(vec![]), Some(SourceStatus::Error))
+            }
         }
     };
 
-    // Fetch Spotify tracks
-    let tracks = match spotify::get_recently_played(spotify_limit).await {
-        Ok(tracks) => {
-            log::info!("Retrieved {} Spotify tracks", tracks.len());
-            tracks
-        },
-        Err(e) => {
-            log::error!("Error fetching Spotify data: {}", e);
-            vec![]
+    let market = spotify::resolve_market(&req);
+    let exclude_now_playing = params.flag("exclude_now_playing");
+    let load_tracks = async {
+        if !options.include_tracks {
+            return (None, None);
+        }
+        if !spotify::is_configured() {
+            return (Some(vec![]), Some(SourceStatus::Unconfigured));
+        }
+        if pause::is_paused("spotify") {
+            return (Some(vec![]), Some(SourceStatus::Paused));
+        }
+        // One extra track makes up for the one dropped as currently playing
+        let fetch_limit = options.track_limit + usize::from(exclude_now_playing);
+        match spotify::get_recently_played(fetch_limit, market.as_deref()).await {
+            Ok(mut tracks) => {
+                if exclude_now_playing {
+                    remove_now_playing(&mut tracks).await;
+                }
+                tracks.truncate(options.track_limit);
+                log::info!("Retrieved {} Spotify tracks", tracks.len());
+                (Some(tracks), Some(SourceStatus::Ok))
+            },
+            Err(e) => {
+                log::error!("Error fetching Spotify data: {}", e);
+                (Some(vec![]), Some(SourceStatus::Error))
+            }
         }
     };
 
+    let ((movies, movies_status), (tracks, tracks_status)) = futures::join!(load_movies, load_tracks);
+    if let Some(status) = movies_status {
+        source_status.insert("movies", status);
+    }
+    if let Some(status) = tracks_status {
+        source_status.insert("tracks", status);
+    }
+
+    // Newest item per source; sources with nothing newer than modified_since are left out
+    let newest_movie = movies.iter().flatten()
+        .filter_map(|movie| DateTime::parse_from_rfc2822(movie.pub_date.as_deref()?).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .max();
+    let newest_track = tracks.iter().flatten()
+        .filter_map(|track| DateTime::parse_from_rfc3339(&track.played_at).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .max();
+    let mut last_modified = BTreeMap::new();
+    if let Some(newest) = newest_movie {
+        last_modified.insert("letterboxd", newest.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    if let Some(newest) = newest_track {
+        last_modified.insert("spotify", newest.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    let unchanged = |newest: Option<DateTime<Utc>>| modified_since.is_some_and(|since| newest.is_none_or(|newest| newest <= since));
+    let movies = movies.filter(|_| !unchanged(newest_movie));
+    let tracks = tracks.filter(|_| !unchanged(newest_track));
+
+    stats::urls_served(urls.as_ref().map_or(0, Vec::len));
+    stats::movies_served(movies.as_ref().map_or(0, Vec::len));
+    stats::tracks_served(tracks.as_ref().map_or(0, Vec::len));
+
     // Combine all data into response
     let aggregated_data = AggregatedData {
         urls,
         movies,
         tracks,
+        status: source_status,
+        last_modified,
     };
 
+    let mut body = if frontend_naming {
+        json!(FrontendAggregatedData::from(&aggregated_data))
+    } else {
+        json!(aggregated_data)
+    };
+
+    // Optionally project the response down to a single path
+    if let Some(path) = params.get("select") {
+        body = match select::select(&body, path) {
+            Ok(selected) => selected,
+            Err(e) => return Ok(params::bad_request(&e)),
+        };
+    }
+
     let mut res = Response::new(StatusCode::Ok);
     res.set_content_type("application/json");
-    res.set_body(json!(aggregated_data));
+    res.set_body(body);
+
+    // Flag expired data served because a refetch failed
+    if (options.include_movies && letterboxd::served_stale(&feed_urls).await)
+        || (options.include_tracks && spotify::served_stale().await) {
+        res.insert_header("Warning", crate::upstream::STALE_WARNING);
+    }
+
+    // Age of the stalest cached source in the response
+    let movies_age = letterboxd::cached_age(&feed_urls).await.filter(|_| options.include_movies);
+    let tracks_age = spotify::cached_age().await.filter(|_| options.include_tracks);
+    if let Some(age) = movies_age.max(tracks_age) {
+        res.insert_header("Age", age.as_secs().to_string());
+    }
 
     let elapsed = start_time.elapsed();
     log::info!("Aggregated data request processed in {:?}", elapsed);
 
     Ok(res)
-} 
\ No newline at end of file
+}
+
+// Drop the currently playing track from the recently played list, if it's there
+async fn remove_now_playing(tracks: &mut Vec<spotify::SpotifyTrack>) {
+    match spotify::fetch_now_playing().await {
+        Ok(Some(now_playing)) => exclude_track(tracks, &now_playing),
+        Ok(None) => {},
+        Err(e) => log::warn!("Could not fetch currently playing track, keeping all tracks: {}", e),
+    }
+}
+
+// Drop the entry for the currently playing track from a recently played list
+fn exclude_track(tracks: &mut Vec<spotify::SpotifyTrack>, now_playing: &spotify::NowPlaying) {
+    if let Some(index) = tracks.iter().position(|track| track.spotify_url == now_playing.spotify_url) {
+        log::info!("Excluding currently playing track {} from recently played", now_playing.track_name);
+        tracks.remove(index);
+    }
+}
+
+/// Lightweight endpoint returning only the number of items per source, for UI badges.
+/// Counts come from the caches and queue; upstreams are only fetched when a cache
+/// is empty, and never with `fetch=false`. This endpoint does not require authentication.
+pub async fn get_counts(req: Request<()>) -> tide::Result<Response> {
+    let params = match QueryParams::from_request(&req, COUNTS_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    let letterboxd_feed = params.get("feed_url").unwrap_or(letterboxd::DEFAULT_FEED_URL);
+    let fetch = params.get("fetch") != Some("false");
+
+    let urls = url_handlers::current_urls(None).len();
+
+    let movies = match letterboxd::cached_movies(letterboxd_feed).await {
+        Some(movies) => movies.len().min(*letterboxd::NUMBER_OF_MOVIES_TO_SHOW),
+        None if fetch && !pause::is_paused("letterboxd") => match letterboxd::fetch_letterboxd_feed(letterboxd_feed).await {
+            Ok(movies) => movies.len().min(*letterboxd::NUMBER_OF_MOVIES_TO_SHOW),
+            Err(e) => {
+                log::error!("Error fetching Letterboxd data for counts: {}", e);
+                0
+            }
+        },
+        None => 0,
+    };
+
+    let tracks = match spotify::cached_recently_played().await {
+        Some(recently_played) => recently_played.tracks.len(),
+        None if fetch && spotify::is_configured() && !pause::is_paused("spotify") => match spotify::fetch_recently_played().await {
+            Ok(recently_played) => recently_played.tracks.len(),
+            Err(e) => {
+                log::error!("Error fetching Spotify data for counts: {}", e);
+                0
+            }
+        },
+        None => 0,
+    };
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "urls": urls, "movies": movies, "tracks": tracks }));
+    Ok(res)
+}
+
+/// Endpoint returning the newest `total` items across all sources as one list, most
+/// recent first, for layouts that show a fixed number of items. Items without a
+/// timestamp (URLs) come last. Sources that fail, are paused or are unconfigured are
+/// left out. This endpoint does not require authentication.
+pub async fn get_recent(req: Request<()>) -> tide::Result<Response> {
+    let start_time = Instant::now();
+
+    let params = match QueryParams::from_request(&req, RECENT_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    let total = params.parse::<usize>("total").unwrap_or(DEFAULT_RECENT_TOTAL).min(MAX_RECENT_TOTAL);
+
+    // Each source can fill the whole list on its own, so fetch up to `total` of each
+    let mut items: Vec<RecentItem> = url_handlers::current_urls(Some(total)).into_iter()
+        .map(RecentItem::Url)
+        .collect();
+
+    if !pause::is_paused("letterboxd") {
+        match letterboxd::fetch_movies(&[letterboxd::DEFAULT_FEED_URL.to_string()], letterboxd::DedupScope::Feed, total, None).await {
+            Ok(movies) => items.extend(movies.into_iter().map(|mut movie| {
+                movie.hide_spoilers();
+                RecentItem::Movie(movie)
+            })),
+            Err(e) => log::error!("Error fetching Letterboxd data for recent items: {}", e),
+        }
+    }
+
+    if spotify::is_configured() && !pause::is_paused("spotify") {
+        let market = spotify::resolve_market(&req);
+        match spotify::get_recently_played(total, market.as_deref()).await {
+            Ok(tracks) => items.extend(tracks.into_iter().map(RecentItem::Track)),
+            Err(e) => log::error!("Error fetching Spotify data for recent items: {}", e),
+        }
+    }
+
+    let items = newest_items(items, total);
+
+    for item in &items {
+        match item {
+            RecentItem::Url(_) => stats::urls_served(1),
+            RecentItem::Movie(_) => stats::movies_served(1),
+            RecentItem::Track(_) => stats::tracks_served(1),
+        }
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "items": items }));
+    log::info!("Recent items request processed in {:?}", start_time.elapsed());
+    Ok(res)
+}
+
+// The newest `total` items, newest first. `None` sorts below any timestamp, so undated
+// items go last in their original order.
+fn newest_items(mut items: Vec<RecentItem>, total: usize) -> Vec<RecentItem> {
+    items.sort_by_key(|item| std::cmp::Reverse(item.timestamp()));
+    items.truncate(total);
+    items
+}
+
+/// Whether `date` falls on the month and day of `today` in an earlier year
+fn on_this_day(date: NaiveDate, today: NaiveDate) -> bool {
+    date.year() < today.year() && date.month() == today.month() && date.day() == today.day()
+}
+
+// The movies published on this day in an earlier year, most recent first
+fn movies_on_this_day(mut movies: Vec<letterboxd::LetterboxdMovie>, today: NaiveDate) -> Vec<letterboxd::LetterboxdMovie> {
+    movies.retain(|movie| movie.pub_date.as_deref()
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+        .is_some_and(|date| on_this_day(date.with_timezone(&Utc).date_naive(), today)));
+    letterboxd::sort_by_recency(&mut movies);
+    movies
+}
+
+// The tracks played on this day in an earlier year, most recent first
+fn tracks_on_this_day(mut tracks: Vec<spotify::SpotifyTrack>, today: NaiveDate) -> Vec<spotify::SpotifyTrack> {
+    tracks.retain(|track| DateTime::parse_from_rfc3339(&track.played_at)
+        .is_ok_and(|date| on_this_day(date.with_timezone(&Utc).date_naive(), today)));
+    // ISO 8601 timestamps in UTC sort chronologically as strings
+    tracks.sort_by(|a, b| b.played_at.cmp(&a.played_at));
+    tracks
+}
+
+/// Endpoint returning the movies and tracks from earlier years whose date falls on today's
+/// month and day (in UTC), grouped by source, most recent first. Movies are matched on their
+/// feed publication date and tracks on when they were played. Sources that fail, are paused
+/// or are unconfigured give empty lists. This endpoint does not require authentication.
+pub async fn get_on_this_day(_req: Request<()>) -> tide::Result<Response> {
+    let today = Utc::now().date_naive();
+
+    let movies = if pause::is_paused("letterboxd") {
+        vec![]
+    } else {
+        letterboxd::fetch_letterboxd_feed(letterboxd::DEFAULT_FEED_URL).await.unwrap_or_else(|e| {
+            log::error!("Error fetching Letterboxd data for on this day: {}", e);
+            vec![]
+        })
+    };
+    let mut movies = movies_on_this_day(movies, today);
+    movies.iter_mut().for_each(letterboxd::LetterboxdMovie::hide_spoilers);
+
+    let tracks = if !spotify::is_configured() || pause::is_paused("spotify") {
+        vec![]
+    } else {
+        match spotify::fetch_recently_played().await {
+            Ok(recently_played) => recently_played.tracks,
+            Err(e) => {
+                log::error!("Error fetching Spotify data for on this day: {}", e);
+                vec![]
+            }
+        }
+    };
+    let tracks = tracks_on_this_day(tracks, today);
+
+    stats::movies_served(movies.len());
+    stats::tracks_served(tracks.len());
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "date": today.format("%m-%d").to_string(), "movies": movies, "tracks": tracks }));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn on_this_day_matches_earlier_years_only() {
+        let today = NaiveDate::from_ymd_opt(2025, 10, 16).unwrap();
+
+        assert!(on_this_day(NaiveDate::from_ymd_opt(2024, 10, 16).unwrap(), today));
+        assert!(on_this_day(NaiveDate::from_ymd_opt(2019, 10, 16).unwrap(), today));
+        assert!(!on_this_day(today, today));
+        assert!(!on_this_day(NaiveDate::from_ymd_opt(2024, 10, 15).unwrap(), today));
+        assert!(!on_this_day(NaiveDate::from_ymd_opt(2024, 9, 16).unwrap(), today));
+    }
+
+    #[test]
+    fn both_naming_schemes_serialize_the_same_data() {
+        let data = AggregatedData {
+            urls: Some(vec!["https://example.com/".to_string()]),
+            movies: None,
+            tracks: Some(vec![]),
+            status: BTreeMap::from([("urls", SourceStatus::Ok), ("tracks", SourceStatus::Unconfigured)]),
+            last_modified: BTreeMap::new(),
+        };
+
+        assert_eq!(serde_json::to_value(&data).unwrap(), json!({
+            "urls": ["https://example.com/"],
+            "tracks": [],
+            "status": { "tracks": "unconfigured", "urls": "ok" },
+            "last_modified": {},
+        }));
+        assert_eq!(serde_json::to_value(FrontendAggregatedData::from(&data)).unwrap(), json!({
+            "recent_links": ["https://example.com/"],
+            "recent_tracks": [],
+            "status": { "tracks": "unconfigured", "urls": "ok" },
+            "last_modified": {},
+        }));
+    }
+
+    #[async_std::test]
+    async fn no_cache_refetches_and_later_requests_use_the_refilled_cache() {
+        let feed = crate::test_support::MockUpstream::start(|number, _| crate::test_support::ok("application/rss+xml", &format!(r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel><title>Films</title>
+<item><title>Fetch {0}</title><letterboxd:filmTitle>Fetch {0}</letterboxd:filmTitle></item>
+</channel></rss>"#, number))).await;
+        let feed_url = feed.url("/rss");
+        let titles = |movies: Option<Vec<letterboxd::LetterboxdMovie>>| -> Vec<String> {
+            movies.unwrap_or_default().into_iter().map(|movie| movie.title).collect()
+        };
+
+        let mut app = tide::new();
+        app.at("/aggregated").get(get_aggregated_data);
+        let request = |query: &str, authenticated: bool| {
+            let mut req = tide::http::Request::get(format!("http://localhost/aggregated?sources=letterboxd&feed_url={}{}", feed_url, query).as_str());
+            if authenticated {
+                req.insert_header(auth::API_KEY_HEADER.as_str(), crate::test_support::credential());
+            }
+            req
+        };
+
+        app.respond::<_, tide::http::Response>(request("", false)).await.unwrap();
+        assert_eq!(titles(letterboxd::cached_movies(&feed_url).await), vec!["Fetch 0"]);
+
+        let mut res: tide::http::Response = app.respond(request("&no_cache=true", true)).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"][0]["title"], "Fetch 1");
+        assert_eq!(titles(letterboxd::cached_movies(&feed_url).await), vec!["Fetch 1"]);
+
+        // Without no_cache, or without a key, the refilled cache is served
+        for req in [request("", true), request("&no_cache=true", false)] {
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            let body: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(body["movies"][0]["title"], "Fetch 1");
+        }
+        assert_eq!(feed.requests(), 2);
+    }
+
+    #[test]
+    fn url_limit_leaves_the_other_limits_alone() {
+        let url = url::Url::parse("http://localhost/aggregated?url_limit=2").unwrap();
+        let params = QueryParams::from_url(&url, AGGREGATED_PARAMS, true).unwrap();
+        let options = AggregateOptions::default().with_params(&params).unwrap();
+
+        assert_eq!(options.url_limit, Some(2));
+        assert_eq!(options.movie_limit, *letterboxd::NUMBER_OF_MOVIES_TO_SHOW);
+        assert_eq!(options.track_limit, 6);
+    }
+
+    #[test]
+    fn profiles_choose_their_own_sources_and_limits() {
+        let profiles: std::collections::HashMap<String, Profile> = serde_json::from_str(r#"{
+            "blog": {"sources": ["letterboxd", "spotify"], "limits": {"spotify": 3}},
+            "links": {"sources": ["urls"], "limits": {"urls": 2}, "feed": "https://letterboxd.com/someone/rss"}
+        }"#).unwrap();
+
+        let blog = AggregateOptions::from_profile(&profiles["blog"]);
+        assert_eq!((blog.include_urls, blog.include_movies, blog.include_tracks), (false, true, true));
+        assert_eq!(blog.track_limit, 3);
+        assert_eq!(blog.url_limit, None);
+        assert_eq!(blog.feed_url, letterboxd::DEFAULT_FEED_URL);
+
+        let links = AggregateOptions::from_profile(&profiles["links"]);
+        assert_eq!((links.include_urls, links.include_movies, links.include_tracks), (true, false, false));
+        assert_eq!(links.url_limit, Some(2));
+        assert_eq!(links.feed_url, "https://letterboxd.com/someone/rss");
+    }
+
+    #[async_std::test]
+    async fn counts_come_from_the_cache_and_fetch_false_stays_offline() {
+        let cached_feed = "https://example.com/counts-cached/rss";
+        let movies: Vec<letterboxd::LetterboxdMovie> = (0..2).map(|i| serde_json::from_value(json!({
+            "title": format!("Film {}", i), "link": "https://letterboxd.com/film/", "description": "",
+            "pub_date": null, "film_title": null, "rating": null, "rewatch": null,
+        })).unwrap()).collect();
+        letterboxd::FEED_CACHE.insert(cached_feed.to_string(), movies).await;
+
+        let mut app = tide::new();
+        app.at("/counts").with(crate::upstream::UpstreamCallCounter).get(get_counts);
+
+        let req = tide::http::Request::get(format!("http://localhost/counts?fetch=false&feed_url={}", cached_feed).as_str());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"], 2);
+        assert_eq!(body["tracks"], 0);
+
+        let req = tide::http::Request::get("http://localhost/counts?fetch=false&feed_url=https://example.com/counts-uncached/rss");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.header("X-Upstream-Calls").unwrap().as_str(), "0");
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"], 0);
+    }
+
+    #[async_std::test]
+    async fn status_tells_empty_failed_and_unconfigured_apart() {
+        let empty_feed = "https://example.com/status-empty/rss";
+        letterboxd::FEED_CACHE.insert(empty_feed.to_string(), vec![]).await;
+        let failing_feed = crate::test_support::refused_url("/rss").await;
+
+        let mut app = tide::new();
+        app.at("/aggregated").get(get_aggregated_data);
+
+        let req = tide::http::Request::get(format!("http://localhost/aggregated?sources=letterboxd,spotify&feed_url={}", empty_feed).as_str());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"], json!([]));
+        assert_eq!(body["status"], json!({ "movies": "ok", "tracks": "unconfigured" }));
+
+        let req = tide::http::Request::get(format!("http://localhost/aggregated?sources=letterboxd&feed_url={}", failing_feed).as_str());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"], json!([]));
+        assert_eq!(body["status"], json!({ "movies": "error" }));
+    }
+
+    #[test]
+    fn items_from_this_day_in_earlier_years_are_selected_newest_first() {
+        let today = NaiveDate::from_ymd_opt(2025, 10, 16).unwrap();
+        let movies: Vec<letterboxd::LetterboxdMovie> = [
+            ("Two years ago", Some("Sun, 16 Oct 2022 20:00:00 +0000")),
+            ("Today", Some("Thu, 16 Oct 2025 09:00:00 +0000")),
+            ("Last year", Some("Wed, 16 Oct 2024 21:30:00 +0000")),
+            ("The day before", Some("Tue, 15 Oct 2024 20:00:00 +0000")),
+            ("Undated", None),
+        ].into_iter().map(|(title, pub_date)| serde_json::from_value(json!({
+            "title": title, "link": "https://letterboxd.com/film/", "description": "",
+            "pub_date": pub_date, "film_title": null, "rating": null, "rewatch": null,
+        })).unwrap()).collect();
+
+        let selected = movies_on_this_day(movies, today);
+
+        let titles: Vec<&str> = selected.iter().map(|movie| movie.title.as_str()).collect();
+        assert_eq!(titles, vec!["Last year", "Two years ago"]);
+        assert!(movies_on_this_day(vec![], today).is_empty());
+
+        let tracks: Vec<spotify::SpotifyTrack> = [
+            ("Three years ago", "2022-10-16T08:00:00Z"),
+            ("Today", "2025-10-16T07:00:00Z"),
+            ("Last year", "2024-10-16T23:59:59.500Z"),
+            ("The day after", "2024-10-17T00:00:00Z"),
+            ("Unparseable", "16/10/2024"),
+        ].into_iter().map(|(name, played_at)| serde_json::from_value(json!({
+            "track_name": name, "artist": "A", "album_name": "X", "played_at": played_at,
+            "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": [],
+        })).unwrap()).collect();
+
+        let selected = tracks_on_this_day(tracks, today);
+
+        let names: Vec<&str> = selected.iter().map(|track| track.track_name.as_str()).collect();
+        assert_eq!(names, vec!["Last year", "Three years ago"]);
+        assert!(tracks_on_this_day(vec![], today).is_empty());
+    }
+
+    #[test]
+    fn spotify_and_movie_limits_apply_independently() {
+        let options = |query: &str| {
+            let url = url::Url::parse(&format!("http://localhost/aggregated?{}", query)).unwrap();
+            let params = QueryParams::from_url(&url, AGGREGATED_PARAMS, true).unwrap();
+            AggregateOptions::default().with_params(&params).unwrap()
+        };
+        let defaults = AggregateOptions::default();
+
+        let movies_only = options("movies_limit=3");
+        assert_eq!((movies_only.movie_limit, movies_only.track_limit), (3, defaults.track_limit));
+
+        let tracks_only = options("spotify_limit=2");
+        assert_eq!((tracks_only.movie_limit, tracks_only.track_limit), (defaults.movie_limit, 2));
+
+        // `limit` still sets the Spotify limit, unless spotify_limit is also given
+        assert_eq!(options("limit=4").track_limit, 4);
+        assert_eq!(options("limit=4&spotify_limit=1").track_limit, 1);
+
+        // Each limit is clamped to its own cap
+        let huge = options("movies_limit=100000&spotify_limit=100000");
+        assert_eq!((huge.movie_limit, huge.track_limit), (MAX_MOVIE_LIMIT, *spotify::MAX_LIMIT));
+    }
+
+    #[test]
+    fn the_now_playing_track_is_dropped_from_the_top_of_the_list() {
+        let mut tracks: Vec<spotify::SpotifyTrack> = serde_json::from_value(json!([
+            { "track_name": "Playing", "artist": "A", "album_name": "X", "played_at": "2025-01-02T10:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": [] },
+            { "track_name": "Earlier", "artist": "B", "album_name": "Y", "played_at": "2025-01-02T09:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/2", "album_image_url": null, "genres": [] },
+        ])).unwrap();
+        let now_playing: spotify::NowPlaying = serde_json::from_value(json!({
+            "track_name": "Playing", "artist": "A", "album_name": "X", "spotify_url": "https://open.spotify.com/track/1",
+            "album_image_url": null, "progress_ms": 1000, "is_playing": true,
+        })).unwrap();
+
+        exclude_track(&mut tracks, &now_playing);
+        let names: Vec<&str> = tracks.iter().map(|track| track.track_name.as_str()).collect();
+        assert_eq!(names, vec!["Earlier"]);
+
+        // A track that isn't in the list leaves it alone
+        exclude_track(&mut tracks, &now_playing);
+        assert_eq!(tracks.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn modified_since_keeps_only_sources_with_newer_items() {
+        let feed_url = "https://example.com/modified-since/rss";
+        let movies: Vec<letterboxd::LetterboxdMovie> = vec![serde_json::from_value(json!({
+            "title": "Watched", "link": "https://letterboxd.com/film/", "description": "",
+            "pub_date": "Wed, 16 Oct 2024 21:30:00 +0000", "film_title": null, "rating": null, "rewatch": null,
+        })).unwrap()];
+        letterboxd::FEED_CACHE.insert(feed_url.to_string(), movies).await;
+
+        let mut app = tide::new();
+        app.at("/aggregated").get(get_aggregated_data);
+        let aggregated = |since: &str| tide::http::Request::get(format!(
+            "http://localhost/aggregated?sources=letterboxd,spotify&feed_url={}&modified_since={}", feed_url, since,
+        ).as_str());
+
+        // Letterboxd has a newer item; Spotify has none at all
+        let mut res: tide::http::Response = app.respond(aggregated("2024-10-01T00:00:00Z")).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["movies"][0]["title"], "Watched");
+        assert!(body.get("tracks").is_none());
+        assert_eq!(body["last_modified"], json!({ "letterboxd": "2024-10-16T21:30:00Z" }));
+
+        // Nothing is newer, but last_modified still reports the newest item
+        let mut res: tide::http::Response = app.respond(aggregated("2024-10-16T21:30:00Z")).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert!(body.get("movies").is_none());
+        assert_eq!(body["last_modified"], json!({ "letterboxd": "2024-10-16T21:30:00Z" }));
+
+        let res: tide::http::Response = app.respond(aggregated("yesterday")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn recent_items_are_capped_in_time_order_across_sources() {
+        let movie = |title: &str, pub_date: &str| RecentItem::Movie(serde_json::from_value(json!({
+            "title": title, "link": "https://letterboxd.com/film/", "description": "",
+            "pub_date": pub_date, "film_title": null, "rating": null, "rewatch": null,
+        })).unwrap());
+        let track = |name: &str, played_at: &str| RecentItem::Track(serde_json::from_value(json!({
+            "track_name": name, "artist": "A", "album_name": "X", "played_at": played_at,
+            "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": [],
+        })).unwrap());
+        let url = |url: &str| RecentItem::Url(url_handlers::UrlEntry { url: url.to_string(), source: None, headers: Default::default() });
+        let items = || vec![
+            url("https://example.com/first"),
+            movie("Old film", "Mon, 06 Jan 2025 20:00:00 +0000"),
+            track("Latest track", "2025-01-08T09:00:00Z"),
+            url("https://example.com/second"),
+            movie("New film", "Tue, 07 Jan 2025 21:00:00 +0000"),
+            track("Old track", "2025-01-05T09:00:00Z"),
+        ];
+        let names = |items: &[RecentItem]| -> Vec<String> {
+            items.iter().map(|item| match item {
+                RecentItem::Url(entry) => entry.url.clone(),
+                RecentItem::Movie(movie) => movie.title.clone(),
+                RecentItem::Track(track) => track.track_name.clone(),
+            }).collect()
+        };
+
+        assert_eq!(names(&newest_items(items(), 3)), vec!["Latest track", "New film", "Old film"]);
+        assert_eq!(names(&newest_items(items(), 10)), vec![
+            "Latest track", "New film", "Old film", "Old track", "https://example.com/first", "https://example.com/second",
+        ]);
+        assert!(newest_items(items(), 0).is_empty());
+    }
+}
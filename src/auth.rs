@@ -1,11 +1,163 @@
 use std::sync::LazyLock;
-use tide::Request;
+use tide::{log, Request, Response, StatusCode};
+use tide::prelude::*;
 
-pub static API_KEY: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("API_KEY").expect("API_KEY must be set.")
+/// Accepted API keys: `API_KEY` first, then the comma-separated `API_KEYS`, so each
+/// client can have its own key and be revoked without affecting the others
+pub static API_KEYS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let keys = parse_keys(std::env::var("API_KEY").ok(), &std::env::var("API_KEYS").unwrap_or_default());
+    assert!(!keys.is_empty(), "API_KEY or API_KEYS must be set.");
+    keys
 });
 
+// The single key followed by the comma-separated ones, trimmed and without empty entries
+fn parse_keys(single: Option<String>, multiple: &str) -> Vec<String> {
+    single.into_iter()
+        .chain(multiple.split(',').map(str::to_string))
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect()
+}
+
+/// Header carrying the API key, from `API_KEY_HEADER` (default `Authorization`). Only this
+/// header is checked. `Authorization` takes `Bearer <key>`; any other header takes the bare key.
+pub static API_KEY_HEADER: LazyLock<String> = LazyLock::new(|| header_name(std::env::var("API_KEY_HEADER").ok()));
+
+// The configured header name, trimmed, or `Authorization` if unset or blank
+fn header_name(configured: Option<String>) -> String {
+    configured
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Authorization".to_string())
+}
+
+/// The value of `API_KEY_HEADER` that presents a key
+pub fn credential(key: &str) -> String {
+    credential_for(&API_KEY_HEADER, key)
+}
+
+// The value of a header that presents a key: `Bearer <key>` for `Authorization`, the bare key otherwise
+fn credential_for(header: &str, key: &str) -> String {
+    if header.eq_ignore_ascii_case("Authorization") {
+        format!("Bearer {}", key)
+    } else {
+        key.to_string()
+    }
+}
+
+/// Compare a candidate against the expected secret in time that depends only on the
+/// secret's length, so neither the position of the first mismatch nor the candidate's
+/// length can be learned from response times
+fn constant_time_eq(candidate: &[u8], expected: &[u8]) -> bool {
+    let mut diff = candidate.len() ^ expected.len();
+    for (i, byte) in expected.iter().enumerate() {
+        diff |= (candidate.get(i).copied().unwrap_or(0) ^ byte) as usize;
+    }
+    diff == 0
+}
+
+/// Position in `API_KEYS` of the key presented by a request, if it presents a valid one
+pub fn matching_key<State>(req: &Request<State>) -> Option<usize> {
+    let auth_header = req.header(API_KEY_HEADER.as_str())?;
+    matching_key_in(auth_header.as_str().as_bytes(), &API_KEYS)
+}
+
+// Position of the key a header value presents among `keys`
+fn matching_key_in(candidate: &[u8], keys: &[String]) -> Option<usize> {
+    // Every key is compared, so the time taken doesn't reveal which one matched
+    let mut matched = None;
+    for (index, key) in keys.iter().enumerate() {
+        if constant_time_eq(candidate, credential(key).as_bytes()) {
+            matched = Some(index);
+        }
+    }
+    matched
+}
+
 pub fn validate_api_key(req: &Request<()>) -> bool {
-    let auth_header = req.header("Authorization");
-    auth_header.is_some() && auth_header.unwrap().as_str().eq(&format!("Bearer {}", *API_KEY))
-} 
\ No newline at end of file
+    let matched = matching_key(req);
+    if let Some(index) = matched {
+        log::debug!("Request authenticated with API key #{}", index);
+    }
+    matched.is_some()
+}
+
+/// 401 response for a request without a valid API key, with the usual JSON error body
+pub fn unauthorized_response() -> Response {
+    let mut res = Response::new(StatusCode::Unauthorized);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "error": "Invalid or missing API key" }));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_an_identical_secret_compares_equal() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secreT", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secre", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secrets", b"Bearer secret"));
+        assert!(!constant_time_eq(b"", b"Bearer secret"));
+    }
+
+    #[async_std::test]
+    async fn a_request_without_the_header_is_rejected() {
+        let mut app = tide::new();
+        app.at("/").get(|req: Request<()>| async move { Ok(json!(validate_api_key(&req))) });
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/")).await.unwrap();
+        assert!(!res.body_json::<bool>().await.unwrap());
+
+        let mut req = tide::http::Request::get("http://localhost/");
+        req.insert_header(API_KEY_HEADER.as_str(), crate::test_support::credential());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert!(res.body_json::<bool>().await.unwrap());
+    }
+
+    #[test]
+    fn any_configured_key_is_accepted() {
+        let keys = parse_keys(Some(" legacy ".to_string()), "phone, laptop,,");
+        assert_eq!(keys, vec!["legacy", "phone", "laptop"]);
+
+        assert_eq!(matching_key_in(credential("legacy").as_bytes(), &keys), Some(0));
+        assert_eq!(matching_key_in(credential("laptop").as_bytes(), &keys), Some(2));
+        assert_eq!(matching_key_in(credential("revoked").as_bytes(), &keys), None);
+
+        // A lone API_KEY still works on its own
+        assert_eq!(parse_keys(Some("only".to_string()), ""), vec!["only"]);
+        assert!(parse_keys(None, " , ").is_empty());
+    }
+
+    #[async_std::test]
+    async fn a_rejected_request_gets_a_json_401() {
+        let mut app = tide::new();
+        app.at("/spotify").get(crate::spotify::get_spotify_tracks);
+        app.at("/letterboxd").get(crate::letterboxd::get_letterboxd_movies);
+        app.at("/url-webhook").get(crate::url_handlers::get_urls);
+        crate::test_support::use_test_key();
+
+        for path in ["/spotify", "/letterboxd", "/url-webhook"] {
+            let mut req = tide::http::Request::get(format!("http://localhost{}", path).as_str());
+            req.insert_header(API_KEY_HEADER.as_str(), credential("wrong-key"));
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{}", path);
+            assert_eq!(res.content_type().unwrap().essence(), "application/json");
+            let body: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(body, json!({ "error": "Invalid or missing API key" }));
+        }
+    }
+
+    #[test]
+    fn a_custom_header_takes_the_bare_key() {
+        assert_eq!(header_name(None), "Authorization");
+        assert_eq!(header_name(Some("  ".to_string())), "Authorization");
+        assert_eq!(header_name(Some(" X-API-Key ".to_string())), "X-API-Key");
+
+        assert_eq!(credential_for("Authorization", "secret"), "Bearer secret");
+        assert_eq!(credential_for("authorization", "secret"), "Bearer secret");
+        assert_eq!(credential_for("X-API-Key", "secret"), "secret");
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Instant;
+use http_types::{Method, Request, Url};
+use tide::log;
+use crate::{auth, letterboxd};
+use tide::prelude::*;
+
+/// Endpoints timed by bench mode, in order. Caches are cold for the first of each source.
+const ENDPOINTS: &[&str] = &["/letterboxd", "/spotify", "/aggregated", "/counts", "/url-webhook"];
+
+/// Endpoints among `ENDPOINTS` that read a Letterboxd feed, and are told which one
+const FEED_ENDPOINTS: &[&str] = &["/letterboxd", "/aggregated", "/counts"];
+
+/// Whether to run in bench mode (`BENCH=true`) instead of serving
+pub fn enabled() -> bool {
+    std::env::var("BENCH").is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Issue one in-process request to each endpoint, without a listening socket,
+/// and print each one's status and latency as a JSON object on stdout
+pub async fn run(app: &tide::Server<()>, prefix: &str) -> Result<(), String> {
+    if !["API_KEY", "API_KEYS"].iter().any(|name| std::env::var(name).is_ok()) {
+        return Err("Bench mode requires API_KEY or API_KEYS to be set".to_string());
+    }
+    println!("{}", measure(app, prefix, letterboxd::DEFAULT_FEED_URL).await?);
+    Ok(())
+}
+
+// Status and latency of one request to each endpoint under `prefix`, keyed by path
+async fn measure(app: &tide::Server<()>, prefix: &str, feed_url: &str) -> Result<serde_json::Value, String> {
+    let api_key = &auth::API_KEYS[0];
+
+    let mut results = serde_json::Map::new();
+    for path in ENDPOINTS {
+        let mut url = Url::parse(&format!("http://localhost{}", crate::prefixed(prefix, path))).map_err(|e| e.to_string())?;
+        if FEED_ENDPOINTS.contains(path) {
+            url.query_pairs_mut().append_pair("feed_url", feed_url);
+        }
+        let mut req = Request::new(Method::Get, url);
+        req.insert_header(auth::API_KEY_HEADER.as_str(), auth::credential(api_key));
+
+        let start_time = Instant::now();
+        let status = match app.respond::<_, http_types::Response>(req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.status(),
+        };
+        let latency_ms = start_time.elapsed().as_secs_f64() * 1000.0;
+
+        log::info!("Bench {} returned {} in {:.1}ms", path, status, latency_ms);
+        results.insert(path.to_string(), json!({ "status": u16::from(status), "latency_ms": latency_ms }));
+    }
+
+    Ok(json!(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::test_support::{self, MockUpstream};
+
+    #[async_std::test]
+    async fn every_endpoint_reports_a_status_and_latency() {
+        // A feed that takes a while to answer shows up in the latency of the cold request only
+        let body = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel><title>Films</title>
+<item><title>Film, 2020</title><letterboxd:filmTitle>Film</letterboxd:filmTitle></item>
+</channel></rss>"#;
+        let response = test_support::ok("application/rss+xml", body);
+        let feed = MockUpstream::start_with_delay(Duration::from_millis(20), move |_, _| response.clone()).await;
+        let app = crate::build_app("*".to_string(), "/api");
+
+        test_support::use_test_key();
+        let results = measure(&app, "/api", &feed.url("/rss")).await.unwrap();
+
+        // Spotify has no credentials in tests, which the bench reports like any other status
+        for path in ENDPOINTS {
+            let expected = if *path == "/spotify" { 503 } else { 200 };
+            assert_eq!(results[path]["status"], expected, "{}", path);
+            assert!(results[path]["latency_ms"].as_f64().is_some_and(|ms| ms >= 0.0), "{}", path);
+        }
+        assert!(results["/letterboxd"]["latency_ms"].as_f64().unwrap() >= 20.0);
+        assert_eq!(feed.requests(), 1);
+    }
+}
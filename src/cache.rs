@@ -1,57 +1,429 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::hash::Hash;
-use std::sync::{LazyLock, Mutex};
+use std::marker::PhantomData;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "redis")]
+use std::sync::{Arc, Mutex};
+use async_std::sync::RwLock;
 use std::time::{Duration, SystemTime};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use tide::log;
 
 /// Default cache duration of 1 hour
 pub const DEFAULT_CACHE_DURATION_SECS: u64 = 3600;
 
+/// Approximate memory budget per in-memory cache, in bytes (unbounded if unset)
+static CACHE_MAX_BYTES: LazyLock<Option<usize>> = LazyLock::new(|| {
+    std::env::var("CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok())
+});
+
+/// How often expired entries are purged from the caches (default 5 minutes, 0 disables)
+static CACHE_SWEEP_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("CACHE_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300);
+    Duration::from_secs(secs)
+});
+
+/// Whether an expired entry may be served when refetching it fails (enabled unless set to "false")
+static SERVE_EXPIRED_ON_ERROR: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("SERVE_EXPIRED_ON_ERROR").map_or(true, |v| !v.trim().eq_ignore_ascii_case("false"))
+});
+
+/// How long past its TTL an expired entry may still be served on error (default 1 day)
+static SERVE_EXPIRED_GRACE: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("SERVE_EXPIRED_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(86400);
+    Duration::from_secs(secs)
+});
+
+/// How long past its TTL an entry may be served while it is refreshed in the background
+/// (default 0, disabled)
+static STALE_WHILE_REVALIDATE: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("STALE_WHILE_REVALIDATE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    Duration::from_secs(secs)
+});
+
+// How long an entry is retained: its TTL, plus the longest time it may be served once expired
+fn retention(ttl: Duration) -> Duration {
+    let grace = if *SERVE_EXPIRED_ON_ERROR { *SERVE_EXPIRED_GRACE } else { Duration::ZERO };
+    ttl + grace.max(*STALE_WHILE_REVALIDATE)
+}
+
 /// Generic cache entry that stores a value with a timestamp
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheEntry<T> {
     pub value: T,
     pub timestamp: SystemTime,
 }
 
+/// Counters describing how effective a cache is since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of stored entries, including expired ones; `None` if the backend can't tell
+    pub entries: Option<usize>,
+}
+
+/// Result of a lookup that may return an expired entry for the caller to refresh
+#[derive(Debug, Clone)]
+pub enum CacheState<V> {
+    /// Within the TTL
+    Fresh(V),
+    /// Past the TTL but within `STALE_WHILE_REVALIDATE_SECS`; serve it and refresh
+    Stale(V),
+    /// Absent, or too old to serve
+    Missing,
+}
+
+/// Storage behind a `Cache`. Entries are keyed by the string form of the cache key;
+/// expiry is decided by the `Cache` itself from the entry timestamp. Every operation
+/// is async, so a backend never blocks the executor while waiting for its storage.
+#[tide::utils::async_trait]
+pub trait CacheBackend<V>: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CacheEntry<V>>;
+    async fn insert(&self, key: &str, entry: CacheEntry<V>);
+    async fn remove(&self, key: &str);
+    async fn clear(&self);
+
+    /// Remove entries stored longer than `max_age` ago, returning how many were removed.
+    /// Backends that expire entries on their own need not do anything.
+    async fn sweep(&self, _max_age: Duration) -> usize {
+        0
+    }
+
+    /// Number of stored entries, if the backend can count them cheaply
+    async fn len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Number of entries evicted to stay within a size bound
+    async fn evictions(&self) -> u64 {
+        0
+    }
+}
+
+/// In-process backend, the default when no external cache is configured.
+/// Optionally bounded by an approximate byte budget, estimated from each
+/// value's serialized size, and by a number of entries; the oldest entries
+/// are evicted to stay within both. All entries of a cache share its TTL, so
+/// expired entries are always the oldest and go first. Lookups share a read
+/// lock, and waiting for the lock yields to the executor instead of blocking it.
+pub struct MemoryBackend<V> {
+    state: RwLock<MemoryState<V>>,
+    max_bytes: Option<usize>,
+    max_entries: Option<usize>,
+}
+
+struct MemoryState<V> {
+    entries: HashMap<String, (CacheEntry<V>, usize)>,
+    total_bytes: usize,
+    evictions: u64,
+}
+
+impl<V> MemoryBackend<V> {
+    pub fn new(max_bytes: Option<usize>, max_entries: Option<usize>) -> Self {
+        MemoryBackend {
+            state: RwLock::new(MemoryState {
+                entries: HashMap::new(),
+                total_bytes: 0,
+                evictions: 0,
+            }),
+            max_bytes,
+            max_entries,
+        }
+    }
+}
+
+impl<V> MemoryState<V> {
+    fn remove(&mut self, key: &str) {
+        if let Some((_, size)) = self.entries.remove(key) {
+            self.total_bytes -= size;
+        }
+    }
+
+    // Evict the oldest entries other than `keep` until the total fits the budget
+    fn evict_to(&mut self, max_bytes: usize, keep: &str) {
+        while self.total_bytes > max_bytes {
+            match self.oldest_except(keep) {
+                Some(key) => {
+                    log::info!("Evicting cache entry {} to stay within {} bytes", key, max_bytes);
+                    self.remove(&key);
+                    self.evictions += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    // Evict the oldest entries other than `keep` until at most `max_entries` remain
+    fn evict_to_count(&mut self, max_entries: usize, keep: &str) {
+        while self.entries.len() > max_entries {
+            match self.oldest_except(keep) {
+                Some(key) => {
+                    log::info!("Evicting cache entry {} to stay within {} entries", key, max_entries);
+                    self.remove(&key);
+                    self.evictions += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    // Key of the least recently stored entry other than `keep`
+    fn oldest_except(&self, keep: &str) -> Option<String> {
+        self.entries.iter()
+            .filter(|(key, _)| key.as_str() != keep)
+            .min_by_key(|(_, (entry, _))| entry.timestamp)
+            .map(|(key, _)| key.clone())
+    }
+}
+
+#[tide::utils::async_trait]
+impl<V: Clone + Serialize + Send + Sync + 'static> CacheBackend<V> for MemoryBackend<V> {
+    async fn get(&self, key: &str) -> Option<CacheEntry<V>> {
+        self.state.read().await.entries.get(key).map(|(entry, _)| entry.clone())
+    }
+
+    async fn insert(&self, key: &str, entry: CacheEntry<V>) {
+        let size = serde_json::to_vec(&entry.value).map(|bytes| bytes.len()).unwrap_or(0);
+        let mut state = self.state.write().await;
+        state.remove(key);
+        state.entries.insert(key.to_string(), (entry, size));
+        state.total_bytes += size;
+
+        if let Some(max_entries) = self.max_entries {
+            state.evict_to_count(max_entries, key);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            state.evict_to(max_bytes, key);
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        self.state.write().await.remove(key);
+    }
+
+    async fn clear(&self) {
+        let mut state = self.state.write().await;
+        state.entries.clear();
+        state.total_bytes = 0;
+    }
+
+    async fn sweep(&self, max_age: Duration) -> usize {
+        let mut state = self.state.write().await;
+        let expired: Vec<String> = state.entries.iter()
+            .filter(|(_, (entry, _))| entry.timestamp.elapsed().is_ok_and(|elapsed| elapsed >= max_age))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            state.remove(key);
+        }
+        expired.len()
+    }
+
+    async fn len(&self) -> Option<usize> {
+        Some(self.state.read().await.entries.len())
+    }
+
+    async fn evictions(&self) -> u64 {
+        self.state.read().await.evictions
+    }
+}
+
+/// Redis backend shared between instances. Entries are stored as JSON under
+/// `<namespace>:<key>` and expire in Redis after the given retention period.
+/// Commands use the blocking client, so they run on the blocking thread pool.
+#[cfg(feature = "redis")]
+pub struct RedisBackend {
+    client: redis::Client,
+    connection: Arc<Mutex<Option<redis::Connection>>>,
+    namespace: String,
+    ttl: Duration,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBackend {
+    pub fn new(redis_url: &str, namespace: &str, ttl: Duration) -> Result<Self, String> {
+        let client = redis::Client::open(redis_url)
+            .map_err(|e| format!("Invalid REDIS_URL: {}", e))?;
+        Ok(RedisBackend {
+            client,
+            connection: Arc::new(Mutex::new(None)),
+            namespace: namespace.to_string(),
+            ttl,
+        })
+    }
+
+    fn namespaced(&self, key: &str) -> String {
+        format!("{}:{}", self.namespace, key)
+    }
+
+    // Run a command on the shared connection off the executor, reconnecting if the previous one failed
+    async fn with_connection<T, F>(&self, op: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut redis::Connection) -> redis::RedisResult<T> + Send + 'static,
+    {
+        let client = self.client.clone();
+        let connection = Arc::clone(&self.connection);
+        let namespace = self.namespace.clone();
+        async_std::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            if connection.is_none() {
+                match client.get_connection() {
+                    Ok(conn) => *connection = Some(conn),
+                    Err(e) => {
+                        log::error!("Failed to connect to Redis: {}", e);
+                        return None;
+                    }
+                }
+            }
+
+            match op(connection.as_mut()?) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    log::error!("Redis command failed for cache {}: {}", namespace, e);
+                    *connection = None;
+                    None
+                }
+            }
+        }).await
+    }
+}
+
+#[cfg(feature = "redis")]
+#[tide::utils::async_trait]
+impl<V: Serialize + DeserializeOwned + Send + Sync + 'static> CacheBackend<V> for RedisBackend {
+    async fn get(&self, key: &str) -> Option<CacheEntry<V>> {
+        use redis::Commands;
+        let redis_key = self.namespaced(key);
+        let raw: Option<String> = self.with_connection(move |conn| conn.get(redis_key)).await?;
+        raw.and_then(|json| match serde_json::from_str(&json) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Ignoring unreadable Redis entry for key {}: {}", key, e);
+                None
+            }
+        })
+    }
+
+    async fn insert(&self, key: &str, entry: CacheEntry<V>) {
+        use redis::Commands;
+        match serde_json::to_string(&entry) {
+            Ok(json) => {
+                let redis_key = self.namespaced(key);
+                let ttl_secs = self.ttl.as_secs().max(1);
+                self.with_connection(move |conn| conn.set_ex::<_, _, ()>(redis_key, json, ttl_secs)).await;
+            },
+            Err(e) => log::error!("Failed to serialize cache entry for key {}: {}", key, e),
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        use redis::Commands;
+        let redis_key = self.namespaced(key);
+        self.with_connection(move |conn| conn.del::<_, ()>(redis_key)).await;
+    }
+
+    async fn clear(&self) {
+        use redis::Commands;
+        // SCAN walks the keyspace incrementally, unlike KEYS which blocks Redis while it runs
+        let pattern = format!("{}:*", self.namespace);
+        self.with_connection(move |conn| {
+            let keys: Vec<String> = conn.scan_match(&pattern)?.collect();
+            if keys.is_empty() {
+                return Ok(());
+            }
+            conn.del::<_, ()>(keys)
+        }).await;
+    }
+}
+
 /// Generic cache for any serializable type
-pub struct Cache<K, V> 
-where 
+pub struct Cache<K, V>
+where
     K: Eq + Hash + Clone + ToString,
-    V: Clone,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    cache: Mutex<HashMap<K, CacheEntry<V>>>,
+    backend: Box<dyn CacheBackend<V>>,
     ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    _key: PhantomData<fn(K)>,
 }
 
-impl<K, V> Cache<K, V> 
-where 
-    K: Eq + Hash + Clone + ToString, 
-    V: Clone,
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + ToString,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    /// Create a new cache with the specified TTL
-    pub fn new(ttl_secs: u64) -> Self {
+    /// Create a new in-memory cache with the specified TTL, holding at most `max_entries`
+    /// entries if given and bounded by `CACHE_MAX_BYTES` if set
+    pub fn new(ttl_secs: u64, max_entries: Option<usize>) -> Self {
+        Self::with_backend(Box::new(MemoryBackend::new(*CACHE_MAX_BYTES, max_entries)), ttl_secs)
+    }
+
+    /// Create a new cache on top of the given backend
+    pub fn with_backend(backend: Box<dyn CacheBackend<V>>, ttl_secs: u64) -> Self {
         Cache {
-            cache: Mutex::new(HashMap::new()),
+            backend,
             ttl: Duration::from_secs(ttl_secs),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            _key: PhantomData,
         }
     }
-    
-    /// Create a new cache with the default TTL (1 hour)
-    pub fn default() -> Self {
-        Self::new(DEFAULT_CACHE_DURATION_SECS)
+
+    /// Create a cache using the backend selected by the environment: Redis when
+    /// `REDIS_URL` is set and the `redis` feature is enabled, in-memory otherwise.
+    /// `max_entries` bounds the in-memory cache; Redis entries expire on their own.
+    pub fn from_env(namespace: &str, ttl_secs: u64, max_entries: Option<usize>) -> Self {
+        let Ok(redis_url) = std::env::var("REDIS_URL") else {
+            return Self::new(ttl_secs, max_entries);
+        };
+
+        #[cfg(feature = "redis")]
+        {
+            match RedisBackend::new(&redis_url, namespace, retention(Duration::from_secs(ttl_secs))) {
+                Ok(backend) => {
+                    log::info!("Using Redis backend for cache {}", namespace);
+                    Self::with_backend(Box::new(backend), ttl_secs)
+                },
+                Err(e) => {
+                    log::error!("{}; falling back to in-memory cache {}", e, namespace);
+                    Self::new(ttl_secs, max_entries)
+                }
+            }
+        }
+
+        #[cfg(not(feature = "redis"))]
+        {
+            let _ = redis_url;
+            log::warn!("REDIS_URL is set but the redis feature is disabled; using in-memory cache {}", namespace);
+            Self::new(ttl_secs, max_entries)
+        }
     }
-    
+
     /// Get a value from the cache if it exists and is not expired
-    pub fn get(&self, key: &K) -> Option<V> {
-        let cache_lock = self.cache.lock().unwrap();
-        
-        if let Some(entry) = cache_lock.get(key) {
+    pub async fn get(&self, key: &K) -> Option<V> {
+        if let Some(entry) = self.backend.get(&key.to_string()).await {
             if let Ok(elapsed) = entry.timestamp.elapsed() {
                 if elapsed < self.ttl {
                     log::info!("Cache hit for key {}", key.to_string());
-                    return Some(entry.value.clone());
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(entry.value);
                 } else {
                     log::info!("Cache expired for key {}", key.to_string());
                 }
@@ -59,49 +431,328 @@ where
         } else {
             log::info!("Cache miss for key {}", key.to_string());
         }
-        
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
         None
     }
-    
+
+    /// Like `get`, but an entry that expired less than `STALE_WHILE_REVALIDATE_SECS`
+    /// ago is returned as `Stale`, so the caller can serve it while refreshing it
+    pub async fn get_with_state(&self, key: &K) -> CacheState<V> {
+        if let Some(entry) = self.backend.get(&key.to_string()).await
+            && let Ok(elapsed) = entry.timestamp.elapsed() {
+            if elapsed < self.ttl {
+                log::info!("Cache hit for key {}", key.to_string());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return CacheState::Fresh(entry.value);
+            }
+            if elapsed < self.ttl + *STALE_WHILE_REVALIDATE {
+                log::info!("Serving stale cache entry for key {} while revalidating ({:?} old)", key.to_string(), elapsed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return CacheState::Stale(entry.value);
+            }
+        }
+
+        log::info!("Cache miss for key {}", key.to_string());
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        CacheState::Missing
+    }
+
+    /// Get a value stored less than `max_age` ago, even if it is past the TTL, for
+    /// clients that say how old a response they accept
+    pub async fn get_within(&self, key: &K, max_age: Duration) -> Option<V> {
+        let entry = self.backend.get(&key.to_string()).await
+            .filter(|entry| entry.timestamp.elapsed().is_ok_and(|elapsed| elapsed < max_age));
+        match entry {
+            Some(entry) => {
+                log::info!("Cache hit for key {} within max age {:?}", key.to_string(), max_age);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value)
+            },
+            None => {
+                log::info!("Cache miss for key {} within max age {:?}", key.to_string(), max_age);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Get a value from the cache, or produce it with `fetch` and cache it. A failed fetch
+    /// caches nothing. No lock is held while `fetch` runs, so concurrent misses may
+    /// each fetch, and the last one stored wins.
+    pub async fn get_or_insert_with<F, Fut, E>(&self, key: K, fetch: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key).await {
+            return Ok(value);
+        }
+
+        let value = fetch().await?;
+        self.insert(key, value.clone()).await;
+        Ok(value)
+    }
+
+    /// Get an expired value that is still within the grace period, for use when
+    /// refetching it failed. Returns `None` if `SERVE_EXPIRED_ON_ERROR` is disabled.
+    pub async fn get_expired(&self, key: &K) -> Option<V> {
+        let entry = self.backend.get(&key.to_string()).await?;
+        let elapsed = entry.timestamp.elapsed().ok()?;
+        if elapsed >= self.ttl && elapsed < retention(self.ttl) && *SERVE_EXPIRED_ON_ERROR {
+            log::warn!("Serving expired cache entry for key {} ({:?} old)", key.to_string(), elapsed);
+            return Some(entry.value);
+        }
+        None
+    }
+
+    /// Whether the entry for this key exists but is past its TTL
+    pub async fn is_expired(&self, key: &K) -> bool {
+        self.age(key).await.is_some_and(|age| age >= self.ttl)
+    }
+
+    /// How long ago the entry for this key was stored, whether or not it has expired
+    pub async fn age(&self, key: &K) -> Option<Duration> {
+        self.backend.get(&key.to_string()).await
+            .and_then(|entry| entry.timestamp.elapsed().ok())
+    }
+
     /// Insert a value into the cache
-    pub fn insert(&self, key: K, value: V) {
-        let mut cache_lock = self.cache.lock().unwrap();
-        
-        cache_lock.insert(key.clone(), CacheEntry {
+    pub async fn insert(&self, key: K, value: V) {
+        self.backend.insert(&key.to_string(), CacheEntry {
             value,
             timestamp: SystemTime::now(),
-        });
-        
+        }).await;
+
         log::info!("Cache updated for key {}", key.to_string());
     }
-    
+
     /// Remove a key from the cache
-    pub fn remove(&self, key: &K) {
-        let mut cache_lock = self.cache.lock().unwrap();
-        cache_lock.remove(key);
+    pub async fn remove(&self, key: &K) {
+        self.backend.remove(&key.to_string()).await;
         log::info!("Cache entry removed for key {}", key.to_string());
     }
-    
+
+    /// Hit, miss and eviction counts since startup, with the current entry count.
+    /// An expired entry counts as a miss.
+    pub async fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.backend.evictions().await,
+            entries: self.backend.len().await,
+        }
+    }
+
+    /// Remove entries that can no longer be served, returning how many were removed.
+    /// Expired entries are kept while they may still be served on error or while
+    /// revalidating.
+    pub async fn sweep(&self) -> usize {
+        self.backend.sweep(retention(self.ttl)).await
+    }
+
     /// Clear the entire cache
-    pub fn clear(&self) {
-        let mut cache_lock = self.cache.lock().unwrap();
-        cache_lock.clear();
+    pub async fn clear(&self) {
+        self.backend.clear().await;
         log::info!("Cache cleared");
     }
 }
 
-/// Create a lazily-initialized global cache instance
+/// Periodically purge entries that can no longer be served from every global cache,
+/// every `CACHE_SWEEP_SECS`. Returns immediately if sweeping is disabled.
+pub async fn run_sweeper() {
+    if CACHE_SWEEP_INTERVAL.is_zero() {
+        log::info!("Cache sweeping disabled");
+        return;
+    }
+
+    loop {
+        async_std::task::sleep(*CACHE_SWEEP_INTERVAL).await;
+        let swept = crate::letterboxd::sweep_caches().await + crate::spotify::sweep_caches().await;
+        log::debug!("Swept {} expired cache entries", swept);
+    }
+}
+
+/// Create a lazily-initialized global cache instance, with its backend chosen at startup
 #[macro_export]
 macro_rules! define_global_cache {
+    ($name:ident, $key_type:ty, $value_type:ty, $ttl_secs:expr, $max_entries:expr) => {
+        pub static $name: std::sync::LazyLock<$crate::cache::Cache<$key_type, $value_type>> = std::sync::LazyLock::new(|| {
+            $crate::cache::Cache::from_env(stringify!($name), $ttl_secs, Some($max_entries))
+        });
+    };
+
     ($name:ident, $key_type:ty, $value_type:ty, $ttl_secs:expr) => {
-        pub static $name: LazyLock<Cache<$key_type, $value_type>> = LazyLock::new(|| {
-            Cache::new($ttl_secs)
+        pub static $name: std::sync::LazyLock<$crate::cache::Cache<$key_type, $value_type>> = std::sync::LazyLock::new(|| {
+            $crate::cache::Cache::from_env(stringify!($name), $ttl_secs, None)
         });
     };
-    
+
     ($name:ident, $key_type:ty, $value_type:ty) => {
-        pub static $name: LazyLock<Cache<$key_type, $value_type>> = LazyLock::new(|| {
-            Cache::default()
+        pub static $name: std::sync::LazyLock<$crate::cache::Cache<$key_type, $value_type>> = std::sync::LazyLock::new(|| {
+            $crate::cache::Cache::from_env(stringify!($name), $crate::cache::DEFAULT_CACHE_DURATION_SECS, None)
         });
     };
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[async_std::test]
+    async fn concurrent_gets_share_the_cache() {
+        let cache: Arc<Cache<String, u32>> = Arc::new(Cache::new(60, None));
+        cache.insert("key".to_string(), 7).await;
+
+        let lookups = (0..100).map(|_| {
+            let cache = Arc::clone(&cache);
+            async_std::task::spawn(async move { cache.get(&"key".to_string()).await })
+        });
+        let values = futures::future::join_all(lookups).await;
+
+        assert!(values.iter().all(|value| *value == Some(7)));
+        assert_eq!(cache.stats().await.hits, 100);
+    }
+
+    #[async_std::test]
+    async fn concurrent_inserts_and_gets_do_not_lose_entries() {
+        let cache: Arc<Cache<String, u32>> = Arc::new(Cache::new(60, None));
+
+        let writers = (0..50u32).map(|i| {
+            let cache = Arc::clone(&cache);
+            async_std::task::spawn(async move {
+                cache.insert(format!("key{}", i), i).await;
+                cache.get(&format!("key{}", i)).await
+            })
+        });
+        let values = futures::future::join_all(writers).await;
+
+        assert!(values.iter().enumerate().all(|(i, value)| *value == Some(i as u32)));
+        assert_eq!(cache.stats().await.entries, Some(50));
+    }
+
+    #[async_std::test]
+    async fn memory_backend_stores_removes_and_clears() {
+        let backend: MemoryBackend<String> = MemoryBackend::new(None, None);
+        let now = SystemTime::now();
+        backend.insert("a", CacheEntry { value: "one".to_string(), timestamp: now }).await;
+        backend.insert("b", CacheEntry { value: "two".to_string(), timestamp: now }).await;
+
+        assert_eq!(backend.get("a").await.map(|entry| entry.value), Some("one".to_string()));
+        assert_eq!(backend.len().await, Some(2));
+
+        backend.remove("a").await;
+        assert!(backend.get("a").await.is_none());
+        assert_eq!(backend.get("b").await.map(|entry| entry.value), Some("two".to_string()));
+
+        backend.clear().await;
+        assert_eq!(backend.len().await, Some(0));
+    }
+
+    #[async_std::test]
+    async fn cache_on_memory_backend_expires_after_ttl() {
+        let backend: MemoryBackend<u32> = MemoryBackend::new(None, None);
+        let old = SystemTime::now() - Duration::from_secs(120);
+        backend.insert("old", CacheEntry { value: 1, timestamp: old }).await;
+        let cache: Cache<String, u32> = Cache::with_backend(Box::new(backend), 60);
+        cache.insert("new".to_string(), 2).await;
+
+        assert_eq!(cache.get(&"old".to_string()).await, None);
+        assert_eq!(cache.get(&"new".to_string()).await, Some(2));
+        let stats = cache.stats().await;
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[async_std::test]
+    async fn byte_budget_evicts_the_oldest_entries() {
+        // Each value serializes to 102 bytes: 100 characters and the quotes
+        let backend: MemoryBackend<String> = MemoryBackend::new(Some(350), None);
+        let start = SystemTime::now() - Duration::from_secs(60);
+        for i in 0..3u64 {
+            let entry = CacheEntry { value: "x".repeat(100), timestamp: start + Duration::from_secs(i) };
+            backend.insert(&format!("key{}", i), entry).await;
+        }
+        assert_eq!(backend.len().await, Some(3));
+        assert_eq!(backend.evictions().await, 0);
+
+        backend.insert("key3", CacheEntry { value: "x".repeat(100), timestamp: start + Duration::from_secs(3) }).await;
+
+        assert_eq!(backend.len().await, Some(3));
+        assert_eq!(backend.evictions().await, 1);
+        assert!(backend.get("key0").await.is_none());
+        assert!(backend.get("key3").await.is_some());
+    }
+
+    #[async_std::test]
+    async fn entry_count_bound_keeps_the_newest_entries() {
+        let backend: MemoryBackend<u32> = MemoryBackend::new(None, Some(3));
+        let start = SystemTime::now() - Duration::from_secs(60);
+        for i in 0..5u32 {
+            let entry = CacheEntry { value: i, timestamp: start + Duration::from_secs(u64::from(i)) };
+            backend.insert(&format!("key{}", i), entry).await;
+        }
+
+        assert_eq!(backend.len().await, Some(3));
+        assert_eq!(backend.evictions().await, 2);
+        assert!(backend.get("key0").await.is_none());
+        assert!(backend.get("key1").await.is_none());
+        for i in 2..5 {
+            assert_eq!(backend.get(&format!("key{}", i)).await.map(|entry| entry.value), Some(i));
+        }
+
+        // Replacing an existing key doesn't evict anything
+        backend.insert("key2", CacheEntry { value: 20, timestamp: SystemTime::now() }).await;
+        assert_eq!(backend.len().await, Some(3));
+        assert_eq!(backend.evictions().await, 2);
+    }
+
+    #[async_std::test]
+    async fn expired_entry_is_served_when_the_refetch_fails() {
+        let backend: MemoryBackend<u32> = MemoryBackend::new(None, None);
+        let stored = SystemTime::now() - Duration::from_secs(120);
+        backend.insert("key", CacheEntry { value: 7, timestamp: stored }).await;
+        let cache: Cache<String, u32> = Cache::with_backend(Box::new(backend), 60);
+        let key = "key".to_string();
+
+        let refetched: Result<u32, String> = cache.get_or_insert_with(key.clone(), || async { Err("upstream down".to_string()) }).await;
+        assert_eq!(refetched, Err("upstream down".to_string()));
+
+        // The failed refetch stored nothing, so the expired value is still there to fall back on
+        assert!(cache.is_expired(&key).await);
+        assert_eq!(cache.get_expired(&key).await, Some(7));
+    }
+
+    #[async_std::test]
+    async fn get_within_honours_max_age_regardless_of_ttl() {
+        let backend: MemoryBackend<u32> = MemoryBackend::new(None, None);
+        let stored = SystemTime::now() - Duration::from_secs(120);
+        backend.insert("key", CacheEntry { value: 7, timestamp: stored }).await;
+        let cache: Cache<String, u32> = Cache::with_backend(Box::new(backend), 60);
+        let key = "key".to_string();
+
+        // Younger than max_age though past the TTL, so still served
+        assert_eq!(cache.get_within(&key, Duration::from_secs(300)).await, Some(7));
+        // Older than max_age, so not served but kept for other readers
+        assert_eq!(cache.get_within(&key, Duration::from_secs(30)).await, None);
+        assert!(cache.age(&key).await.is_some());
+    }
+
+    // Needs a Redis server at REDIS_URL; skipped when it isn't set
+    #[cfg(feature = "redis")]
+    #[async_std::test]
+    async fn redis_backend_round_trip() {
+        let Ok(redis_url) = std::env::var("REDIS_URL") else {
+            return;
+        };
+        let namespace = format!("test-{}", std::process::id());
+        let backend = RedisBackend::new(&redis_url, &namespace, Duration::from_secs(60)).unwrap();
+        let cache: Cache<String, Vec<String>> = Cache::with_backend(Box::new(backend), 60);
+
+        cache.insert("key".to_string(), vec!["value".to_string()]).await;
+        assert_eq!(cache.get(&"key".to_string()).await, Some(vec!["value".to_string()]));
+
+        cache.clear().await;
+        assert_eq!(cache.get(&"key".to_string()).await, None);
+    }
+}
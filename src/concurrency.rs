@@ -0,0 +1,108 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tide::{log, Middleware, Next, Request, Response, StatusCode};
+use tide::prelude::*;
+
+/// Seconds clients are asked to wait before retrying a shed request
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Maximum number of requests handled at once, from `MAX_CONCURRENT_REQUESTS`. Unlimited if unset.
+pub static MAX_CONCURRENT_REQUESTS: LazyLock<Option<usize>> = LazyLock::new(|| {
+    std::env::var("MAX_CONCURRENT_REQUESTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|max| *max > 0)
+});
+
+// Requests currently being handled, not counting exempt ones
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Releases a request's slot when it completes, however it completes
+struct Slot;
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware that answers 503 Service Unavailable with `Retry-After` once
+/// `MAX_CONCURRENT_REQUESTS` are in flight, instead of queuing more work.
+/// Health checks are exempt so that an overloaded instance can still be probed.
+pub struct ConcurrencyLimit {
+    max: Option<usize>,
+    // Path of the health routes under the app's prefix
+    health: String,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(prefix: &str) -> Self {
+        ConcurrencyLimit { max: *MAX_CONCURRENT_REQUESTS, health: crate::prefixed(prefix, "/health") }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ConcurrencyLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let Some(max) = self.max else {
+            return Ok(next.run(req).await);
+        };
+
+        let path = req.url().path();
+        if path == self.health || path.starts_with(&format!("{}/", self.health)) {
+            return Ok(next.run(req).await);
+        }
+
+        if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= max {
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("Shedding request to {}: {} requests already in flight", path, max);
+            let mut res = Response::new(StatusCode::ServiceUnavailable);
+            res.insert_header("Retry-After", RETRY_AFTER_SECS.to_string());
+            res.set_content_type("application/json");
+            res.set_body(json!({ "error": "Server is busy, try again shortly" }));
+            return Ok(res);
+        }
+
+        let _slot = Slot;
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn requests_beyond_the_limit_get_a_503() {
+        let mut app = tide::new();
+        app.with(ConcurrencyLimit { max: Some(1), health: crate::prefixed("/api", "/health") });
+        app.at("/slow").get(|_| async {
+            async_std::task::sleep(Duration::from_millis(300)).await;
+            Ok("done")
+        });
+        app.at("/fast").get(|_| async { Ok("done") });
+        app.at("/api/health").get(|_| async { Ok("ok") });
+
+        let slow_app = app.clone();
+        let slow = async_std::task::spawn(async move {
+            let res: tide::http::Response = slow_app.respond(tide::http::Request::get("http://localhost/slow")).await.unwrap();
+            res.status()
+        });
+        async_std::task::sleep(Duration::from_millis(100)).await;
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/fast")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(res.header("Retry-After").unwrap().as_str(), RETRY_AFTER_SECS.to_string());
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body, json!({ "error": "Server is busy, try again shortly" }));
+
+        // Health checks under the app's prefix are let through while saturated
+        let res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/api/health")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        assert_eq!(slow.await, StatusCode::Ok);
+        let res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/fast")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+}
@@ -0,0 +1,122 @@
+use serde_json::Value;
+use tide::Response;
+
+/// Renders a handler's normalized response body in an output format selected with `format`
+pub trait Formatter: Send + Sync {
+    /// Content type of the rendered body
+    fn content_type(&self) -> &'static str;
+
+    /// Render the body. `items` names the field holding the endpoint's main list, e.g. `tracks`.
+    fn render(&self, body: &Value, items: &str) -> String;
+}
+
+/// The body as a JSON document, the default
+struct Json;
+
+impl Formatter for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn render(&self, body: &Value, _items: &str) -> String {
+        body.to_string()
+    }
+}
+
+/// The main list as newline-delimited JSON, one item per line
+struct JsonLines;
+
+impl Formatter for JsonLines {
+    fn content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+
+    fn render(&self, body: &Value, items: &str) -> String {
+        body.get(items)
+            .and_then(Value::as_array)
+            .map(|items| items.iter().map(|item| format!("{}\n", item)).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Registered formatters by `format` name. A new format is a `Formatter` impl plus an entry here.
+static FORMATTERS: &[(&str, &dyn Formatter)] = &[
+    ("json", &Json),
+    ("jsonl", &JsonLines),
+];
+
+/// The formatter for a `format` query parameter, JSON if none is given
+pub fn lookup(name: Option<&str>) -> Result<&'static dyn Formatter, String> {
+    lookup_in(FORMATTERS, name)
+}
+
+// The formatter for a `format` name among the given registered formatters
+fn lookup_in(formatters: &[(&str, &'static dyn Formatter)], name: Option<&str>) -> Result<&'static dyn Formatter, String> {
+    let name = name.unwrap_or("json");
+    formatters.iter()
+        .find(|(registered, _)| *registered == name)
+        .map(|(_, formatter)| *formatter)
+        .ok_or_else(|| format!("Unknown format '{}'", name))
+}
+
+/// Render a body into a response with the formatter's content type
+pub fn set_body(res: &mut Response, formatter: &dyn Formatter, body: &Value, items: &str) {
+    res.set_body(formatter.render(body, items));
+    res.set_content_type(formatter.content_type());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tide::{Request, StatusCode};
+
+    /// Track names as a comma-separated line
+    struct Names;
+
+    impl Formatter for Names {
+        fn content_type(&self) -> &'static str {
+            "text/plain"
+        }
+
+        fn render(&self, body: &Value, items: &str) -> String {
+            body[items].as_array().into_iter().flatten()
+                .filter_map(|item| item["track_name"].as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    }
+
+    static REGISTRY: &[(&str, &dyn Formatter)] = &[
+        ("json", &Json),
+        ("custom", &Names),
+    ];
+
+    #[async_std::test]
+    async fn a_registered_formatter_is_selected_by_name() {
+        let mut app = tide::new();
+        app.at("/tracks").get(|req: Request<()>| async move {
+            let params = crate::params::QueryParams::from_url(req.url(), &["format"], true).unwrap();
+            let formatter = match lookup_in(REGISTRY, params.get("format")) {
+                Ok(formatter) => formatter,
+                Err(e) => return Ok(crate::params::bad_request(&e)),
+            };
+            let mut res = Response::new(StatusCode::Ok);
+            set_body(&mut res, formatter, &json!({ "tracks": [{ "track_name": "One" }, { "track_name": "Two" }] }), "tracks");
+            Ok(res)
+        });
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/tracks?format=custom")).await.unwrap();
+        assert_eq!(res.content_type().unwrap().essence(), "text/plain");
+        assert_eq!(res.body_string().await.unwrap(), "One, Two");
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/tracks")).await.unwrap();
+        assert_eq!(res.content_type().unwrap().essence(), "application/json");
+        let body: Value = res.body_json().await.unwrap();
+        assert_eq!(body["tracks"][1]["track_name"], "Two");
+
+        // Formats registered elsewhere aren't known to this registry
+        let res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/tracks?format=jsonl")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tide::{log, Request, Response, StatusCode};
+use tide::prelude::*;
+use crate::letterboxd;
+use crate::params::{self, QueryParams};
+use crate::stats;
+use crate::upstream;
+
+/// Number of recent upstream fetches per source the score is computed over
+const WINDOW_SIZE: usize = 20;
+
+/// Sources reported by `/health/score`
+pub const SOURCES: &[&str] = &["letterboxd", "spotify"];
+
+/// Query parameters accepted by `/health`
+const HEALTH_PARAMS: &[&str] = &["deep"];
+
+/// Longest wait for a dependency to answer a deep health check
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Endpoints probed by a deep health check, one per dependency
+const SPOTIFY_PROBE_URL: &str = "https://accounts.spotify.com/api/token";
+
+// Outcome (success or failure) of the most recent upstream fetches per source
+static OUTCOMES: LazyLock<Mutex<HashMap<&'static str, VecDeque<bool>>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/// Record the outcome of an upstream fetch for a source
+pub fn record(source: &'static str, success: bool) {
+    let mut outcomes = OUTCOMES.lock().unwrap();
+    let window = outcomes.entry(source).or_insert_with(|| VecDeque::with_capacity(WINDOW_SIZE));
+    if window.len() >= WINDOW_SIZE {
+        window.pop_front();
+    }
+    window.push_back(success);
+}
+
+/// Percentage of recent fetches for a source that succeeded, or `None` if there were none
+pub fn score(source: &str) -> Option<u8> {
+    let outcomes = OUTCOMES.lock().unwrap();
+    let window = outcomes.get(source).filter(|window| !window.is_empty())?;
+    let successes = window.iter().filter(|success| **success).count();
+    Some((successes * 100 / window.len()) as u8)
+}
+
+// Whether a dependency answers a HEAD request at all within the probe timeout.
+// Any HTTP response counts, since an error status still shows the host is reachable.
+async fn probe(url: &str) -> bool {
+    match async_std::future::timeout(PROBE_TIMEOUT, upstream::send(upstream::head(url))).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            log::warn!("Health probe of {} failed: {}", url, e);
+            false
+        },
+        Err(_) => {
+            log::warn!("Health probe of {} timed out after {:?}", url, PROBE_TIMEOUT);
+            false
+        }
+    }
+}
+
+/// Liveness endpoint for load balancers and monitoring, reporting status and uptime
+/// without touching the network. With `deep=true` it also probes Spotify and the
+/// default Letterboxd feed. This endpoint does not require authentication.
+pub async fn get_health(req: Request<()>) -> tide::Result<Response> {
+    let params = match QueryParams::from_request(&req, HEALTH_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+
+    let mut body = json!({ "status": "ok", "uptime_secs": stats::uptime().as_secs() });
+    if params.flag("deep") {
+        let (spotify, letterboxd) = futures::join!(probe(SPOTIFY_PROBE_URL), probe(letterboxd::DEFAULT_FEED_URL));
+        body["dependencies"] = json!({
+            "spotify": { "reachable": spotify },
+            "letterboxd": { "reachable": letterboxd },
+        });
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(body);
+    Ok(res)
+}
+
+/// Endpoint returning a 0-100 health score per source, based on the success rate of
+/// its last upstream fetches. Sources without recent fetches report `null`.
+/// This endpoint does not require authentication.
+pub async fn get_health_score(_req: Request<()>) -> tide::Result<Response> {
+    let scores: serde_json::Map<String, serde_json::Value> = SOURCES.iter()
+        .map(|source| (source.to_string(), json!(score(source))))
+        .collect();
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!(scores));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failure_lowers_the_score() {
+        // A source of its own, so fetches recorded by other tests don't count
+        let source = "health-score-test";
+        assert_eq!(score(source), None);
+
+        for _ in 0..3 {
+            record(source, true);
+        }
+        assert_eq!(score(source), Some(100));
+
+        record(source, false);
+        assert_eq!(score(source), Some(75));
+
+        // Only the last WINDOW_SIZE fetches count
+        for _ in 0..WINDOW_SIZE {
+            record(source, false);
+        }
+        assert_eq!(score(source), Some(0));
+    }
+}
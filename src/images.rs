@@ -0,0 +1,79 @@
+use std::sync::LazyLock;
+
+/// A 1x1 transparent GIF, small enough to inline wherever an image URL is missing
+pub const PLACEHOLDER_DATA_URI: &str = "data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7";
+
+/// Image shown for `image_fallback=url`, from `IMAGE_FALLBACK_URL`
+static FALLBACK_URL: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("IMAGE_FALLBACK_URL").ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+});
+
+/// What to put in an image field when an item has no image, chosen with `image_fallback`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFallback {
+    /// Leave the field `null` (the default)
+    None,
+    /// Use the configured `IMAGE_FALLBACK_URL`
+    Url,
+    /// Use an inline placeholder data URI
+    Data,
+}
+
+impl ImageFallback {
+    /// Parse the `image_fallback` query parameter
+    pub fn from_param(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("none") => Ok(ImageFallback::None),
+            Some("url") if FALLBACK_URL.is_some() => Ok(ImageFallback::Url),
+            Some("url") => Err("image_fallback=url requires IMAGE_FALLBACK_URL to be set".to_string()),
+            Some("data") => Ok(ImageFallback::Data),
+            Some(other) => Err(format!("Unknown image fallback '{}'", other)),
+        }
+    }
+
+    /// Fill in an image URL that is missing or empty
+    pub fn apply(self, image_url: &mut Option<String>) {
+        if image_url.as_deref().is_some_and(|url| !url.is_empty()) {
+            return;
+        }
+        *image_url = match self {
+            ImageFallback::None => None,
+            ImageFallback::Url => FALLBACK_URL.clone(),
+            ImageFallback::Data => Some(PLACEHOLDER_DATA_URI.to_string()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    #[test]
+    fn an_imageless_item_gets_the_data_uri() {
+        let fallback = ImageFallback::from_param(Some("data")).unwrap();
+
+        let mut missing = None;
+        fallback.apply(&mut missing);
+        assert_eq!(missing.as_deref(), Some(PLACEHOLDER_DATA_URI));
+
+        let mut empty = Some(String::new());
+        fallback.apply(&mut empty);
+        assert_eq!(empty.as_deref(), Some(PLACEHOLDER_DATA_URI));
+
+        // A real image is left alone, and nothing is filled in by default
+        let mut present = Some("https://i.scdn.co/image/cover".to_string());
+        fallback.apply(&mut present);
+        assert_eq!(present.as_deref(), Some("https://i.scdn.co/image/cover"));
+        let mut untouched = None;
+        ImageFallback::from_param(None).unwrap().apply(&mut untouched);
+        assert_eq!(untouched, None);
+
+        // The placeholder is a real GIF
+        let encoded = PLACEHOLDER_DATA_URI.strip_prefix("data:image/gif;base64,").unwrap();
+        let gif = base64::engine::general_purpose::STANDARD.decode(encoded).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+    }
+}
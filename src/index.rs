@@ -0,0 +1,98 @@
+use tide::{Request, Response, StatusCode};
+use tide::prelude::*;
+use crate::stats;
+
+const SERVICE_NAME: &str = "API Endpoint Aggregator";
+
+/// Routes listed on the index, as (method, path) without `ROUTE_PREFIX`
+const ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/url-webhook"),
+    ("POST", "/url-webhook"),
+    ("POST", "/url-webhook/pin"),
+    ("DELETE", "/url-webhook/pin"),
+    ("GET", "/letterboxd"),
+    ("GET", "/spotify"),
+    ("GET", "/spotify/top"),
+    ("GET", "/spotify/now-playing"),
+    ("GET", "/spotify/export.jsonl"),
+    ("POST", "/spotify/token/refresh"),
+    ("GET", "/aggregated"),
+    ("GET", "/aggregated/{profile}"),
+    ("GET", "/recent"),
+    ("GET", "/on-this-day"),
+    ("GET", "/counts"),
+    ("GET", "/stats/served"),
+    ("GET", "/cache/stats"),
+    ("GET", "/health"),
+    ("GET", "/health/score"),
+    ("POST", "/admin/rebuild"),
+    ("POST", "/admin/spotify/excluded-genres"),
+    ("POST", "/admin/source/{name}/pause"),
+    ("POST", "/admin/source/{name}/resume"),
+];
+
+/// Landing endpoint describing the service: name, version, uptime and available
+/// endpoints as JSON, or as a small HTML page for clients that accept `text/html`.
+/// This endpoint does not require authentication.
+pub async fn get_index(req: Request<()>) -> tide::Result<Response> {
+    let endpoints: Vec<(&str, String)> = ENDPOINTS.iter()
+        .map(|(method, path)| (*method, crate::route(path)))
+        .collect();
+    let version = env!("CARGO_PKG_VERSION");
+
+    let wants_html = req.header("Accept")
+        .is_some_and(|accept| accept.as_str().contains("text/html"));
+
+    let mut res = Response::new(StatusCode::Ok);
+    if wants_html {
+        let items: String = endpoints.iter()
+            .map(|(method, path)| format!("<li><code>{} {}</code></li>", method, path))
+            .collect();
+        res.set_body(format!(
+            "<!DOCTYPE html><html><head><title>{name}</title></head><body><h1>{name}</h1><p>Version {version}</p><ul>{items}</ul></body></html>",
+            name = SERVICE_NAME,
+            version = version,
+            items = items,
+        ));
+        res.set_content_type("text/html");
+    } else {
+        let endpoints: Vec<_> = endpoints.iter()
+            .map(|(method, path)| json!({ "method": method, "path": path }))
+            .collect();
+        res.set_content_type("application/json");
+        res.set_body(json!({
+            "service": SERVICE_NAME,
+            "version": version,
+            "uptime_secs": stats::uptime().as_secs(),
+            "endpoints": endpoints,
+        }));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn the_index_lists_the_version_and_endpoints() {
+        let mut app = tide::new();
+        app.at("/").get(get_index);
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["service"], SERVICE_NAME);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        let endpoints = body["endpoints"].as_array().unwrap();
+        assert_eq!(endpoints.len(), ENDPOINTS.len());
+        assert!(endpoints.contains(&json!({ "method": "GET", "path": crate::route("/spotify") })));
+
+        let mut req = tide::http::Request::get("http://localhost/");
+        req.insert_header("Accept", "text/html,application/xhtml+xml");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        let html = res.body_string().await.unwrap();
+        assert!(html.contains(&format!("Version {}", env!("CARGO_PKG_VERSION"))));
+    }
+}
@@ -2,63 +2,386 @@ use rss::{Channel, Item};
 use serde::{Deserialize, Serialize};
 use tide::{log, Request, Response, StatusCode};
 use tide::prelude::*;
-use std::collections::HashMap;
-use std::time::{Instant, Duration, SystemTime};
-use std::sync::{LazyLock, Mutex};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use futures::future::join_all;
 use crate::auth;
-use surf;
+use crate::upstream;
+use crate::pause;
+use crate::health;
+use crate::snapshot;
+use crate::stats;
+use crate::format;
+use crate::define_global_cache;
+use crate::cache::DEFAULT_CACHE_DURATION_SECS;
+use crate::params::{self, QueryParams};
+use crate::images::ImageFallback;
 use url::Url;
+use async_std::io::ReadExt;
 use chrono::DateTime;
 
 const LETTERBOXD_NAMESPACE: &str = "letterboxd";
-const NUMBER_OF_MOVIES_TO_SHOW: usize = 5;
-const CACHE_DURATION_SECS: u64 = 3600; // 1 hour cache duration
+pub const DEFAULT_FEED_URL: &str = "https://letterboxd.com/atropos_Dad/rss";
+/// Movies returned when a request doesn't give a limit, from `LETTERBOXD_LIMIT` (default 5)
+pub static NUMBER_OF_MOVIES_TO_SHOW: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("LETTERBOXD_LIMIT")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(5)
+});
 
-// Cache structure to store results and timestamp
-#[derive(Debug, Clone)]
-struct CacheEntry {
-    movies: Vec<LetterboxdMovie>,
-    timestamp: SystemTime,
-}
+/// Whether feed items without a film title are dropped (the default) or kept under their RSS title
+static REQUIRE_FILM_TITLE: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("LETTERBOXD_REQUIRE_FILM_TITLE").map_or(true, |v| !v.trim().eq_ignore_ascii_case("false"))
+});
+
+/// Whether a redirect with several distinct `Location` values is an error rather than
+/// followed to the first one
+static STRICT_REDIRECTS: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("LETTERBOXD_STRICT_REDIRECTS").is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+});
 
-// Global cache for each feed URL
-static FEED_CACHE: LazyLock<Mutex<HashMap<String, CacheEntry>>> = LazyLock::new(|| {
-    Mutex::new(HashMap::new())
+/// Largest feed body read before the fetch is aborted, from `FEED_MAX_BYTES` (default 5 MB)
+static FEED_MAX_BYTES: LazyLock<u64> = LazyLock::new(|| {
+    std::env::var("FEED_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(5 * 1024 * 1024)
 });
 
+/// Most feeds one request may merge, so a request can't fan out to arbitrary many upstreams
+const MAX_FEEDS: usize = 5;
+
+/// Query parameters accepted by `/letterboxd`
+const QUERY_PARAMS: &[&str] = &["limit", "feed_url", "dedup_scope", "no_cache", "max_age", "clean_title", "format", "liked", "tag", "rating_format", "show_spoilers", "image_fallback"];
+
+/// Most feeds cached at once. Feed URLs come from the query string, so the caches are
+/// bounded to keep arbitrary URLs from growing them without limit.
+const MAX_CACHED_FEEDS: usize = 100;
+
+// Global cache for each feed URL (1 hour cache duration)
+define_global_cache!(FEED_CACHE, String, Vec<LetterboxdMovie>, DEFAULT_CACHE_DURATION_SECS, MAX_CACHED_FEEDS);
+
+// Channel-level metadata for each feed URL, refreshed together with its movies
+define_global_cache!(CHANNEL_CACHE, String, FeedInfo, DEFAULT_CACHE_DURATION_SECS, MAX_CACHED_FEEDS);
+
+/// Channel-level metadata of a feed, for labelling a widget
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedInfo {
+    pub title: String,
+    pub description: String,
+    pub image_url: Option<String>,
+}
+
+impl FeedInfo {
+    fn from_channel(channel: &Channel) -> Self {
+        FeedInfo {
+            title: channel.title().to_string(),
+            description: channel.description().to_string(),
+            image_url: channel.image().map(|image| normalize_link(image.url())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LetterboxdMovie {
     pub title: String,
+    /// The title exactly as in the feed, kept when `title` is cleaned
+    #[serde(default)]
+    pub raw_title: String,
     pub link: String,
     pub description: String,
     pub pub_date: Option<String>,
     pub film_title: Option<String>,
     pub rating: Option<String>,
+    /// `rating` as a number of stars, e.g. 3.5
+    #[serde(default)]
+    pub rating_value: Option<f32>,
     pub rewatch: Option<String>,
+    #[serde(default)]
+    pub is_rewatch: bool,
+    /// The feed this entry came from
+    #[serde(default)]
+    pub feed_url: Option<String>,
+    /// Whether the member liked the film, if the feed says
+    #[serde(default)]
+    pub liked: Option<bool>,
+    /// The entry's tags, if the feed lists any
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Whether the review is marked as containing spoilers
+    #[serde(default)]
+    pub contains_spoilers: bool,
+    /// The film poster, from the first image in the description
+    #[serde(default)]
+    pub poster_url: Option<String>,
+    /// `description` as plain text, for places that don't render HTML
+    #[serde(default)]
+    pub description_text: Option<String>,
 }
 
-pub async fn fetch_letterboxd_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie>, String> {
-    let start_time = Instant::now();
+impl LetterboxdMovie {
+    /// Replace the decorated feed title (year, rating stars) with just the film name
+    pub fn clean_title(&mut self) {
+        if let Some(film_title) = &self.film_title {
+            self.title = film_title.clone();
+        }
+    }
+
+    /// Blank the review text of a spoiler review, keeping its title and rating
+    pub fn hide_spoilers(&mut self) {
+        if self.contains_spoilers {
+            self.description.clear();
+            self.description_text = None;
+        }
+    }
+}
+
+/// Marker Letterboxd puts in the description of reviews flagged as containing spoilers
+const SPOILER_MARKER: &str = "This review may contain spoilers";
+
+/// How entries from multiple feeds are de-duplicated when merged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DedupScope {
+    /// Keep each feed's entries, so a film watched by two users appears twice
+    Feed,
+    /// Collapse the same film across feeds, keeping the most recent watch (the default)
+    Film,
+}
+
+impl DedupScope {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "feed" => Some(DedupScope::Feed),
+            "film" => Some(DedupScope::Film),
+            _ => None,
+        }
+    }
+}
+
+/// How `rating_value` is rendered, chosen with `rating_format`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RatingFormat {
+    /// Stars as a number, e.g. 3.5 (the default)
+    Float,
+    /// Stars as a string with one decimal, e.g. "3.5"
+    String,
+    /// Out of 10 as an integer, e.g. 7
+    TenScale,
+}
+
+impl RatingFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "float" => Some(RatingFormat::Float),
+            "string" => Some(RatingFormat::String),
+            "ten_scale" => Some(RatingFormat::TenScale),
+            _ => None,
+        }
+    }
+
+    fn render(self, stars: f32) -> serde_json::Value {
+        match self {
+            RatingFormat::Float => json!(stars),
+            RatingFormat::String => json!(format!("{:.1}", stars)),
+            RatingFormat::TenScale => json!((stars * 2.0).round() as u8),
+        }
+    }
+}
+
+/// Interpret a raw Letterboxd yes/no value such as rewatch (usually "Yes"/"No") as a boolean
+fn parse_yes_no(value: Option<&str>) -> bool {
+    value.is_some_and(|v| matches!(v.trim().to_lowercase().as_str(), "yes" | "true" | "1"))
+}
+
+/// Movies for a feed if they are already cached, without fetching
+pub async fn cached_movies(feed_url: &str) -> Option<Vec<LetterboxdMovie>> {
+    FEED_CACHE.get(&feed_url.to_string()).await
+}
+
+/// Drop the cached movies for a feed so the next request refetches it
+pub async fn invalidate_feed(feed_url: &str) {
+    FEED_CACHE.remove(&feed_url.to_string()).await;
+    CHANNEL_CACHE.remove(&feed_url.to_string()).await;
+}
+
+/// Purge expired entries from the Letterboxd caches, returning how many were removed
+pub async fn sweep_caches() -> usize {
+    FEED_CACHE.sweep().await + CHANNEL_CACHE.sweep().await
+}
+
+/// Channel metadata for a feed fetched earlier, even if it has since expired
+pub async fn feed_info(feed_url: &str) -> Option<FeedInfo> {
+    match CHANNEL_CACHE.get(&feed_url.to_string()).await {
+        Some(info) => Some(info),
+        None => CHANNEL_CACHE.get_expired(&feed_url.to_string()).await,
+    }
+}
+
+/// Whether any of the given feeds is being served from an expired cache entry
+pub async fn served_stale(feed_urls: &[String]) -> bool {
+    for feed_url in feed_urls {
+        if FEED_CACHE.is_expired(feed_url).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// Age of the oldest cached entry among the given feeds, i.e. how stale the merged list may be
+pub async fn cached_age(feed_urls: &[String]) -> Option<Duration> {
+    let mut oldest = None;
+    for feed_url in feed_urls {
+        oldest = oldest.max(FEED_CACHE.age(feed_url).await);
+    }
+    oldest
+}
+
+/// Fetch one or more feeds concurrently and merge them into a single list, most
+/// recent first, limited to `limit` movies. Feeds that fail are
+/// logged and skipped; an error is only returned if every feed fails.
+/// With `max_age`, cached feeds are used as long as they are younger, as for `fetch_feed_within`.
+pub async fn fetch_movies(feed_urls: &[String], scope: DedupScope, limit: usize, max_age: Option<Duration>) -> Result<Vec<LetterboxdMovie>, String> {
+    let results = join_all(feed_urls.iter().map(|feed_url| fetch_feed_within(feed_url, max_age))).await;
     
-    // Check cache first
-    {
-        let cache_lock = FEED_CACHE.lock().unwrap();
-        if let Some(cache_entry) = cache_lock.get(feed_url) {
-            if let Ok(elapsed) = cache_entry.timestamp.elapsed() {
-                if elapsed < Duration::from_secs(CACHE_DURATION_SECS) {
-                    log::info!("Cache hit for feed {}", feed_url);
-                    return Ok(cache_entry.movies.clone());
-                } else {
-                    log::info!("Cache expired for feed {}", feed_url);
-                }
+    let mut movies: Vec<LetterboxdMovie> = Vec::new();
+    let mut any_fetched = false;
+    let mut last_error = None;
+    for (feed_url, result) in feed_urls.iter().zip(results) {
+        match result {
+            Ok(feed_movies) => {
+                any_fetched = true;
+                movies.extend(feed_movies);
+            },
+            Err(e) => {
+                log::error!("Error fetching Letterboxd feed {}: {}", feed_url, e);
+                last_error = Some(e);
             }
-        } else {
-            log::info!("Cache miss for feed {}", feed_url);
         }
     }
     
+    // A feed that was fetched but has no entries is still a success
+    if !any_fetched
+        && let Some(e) = last_error {
+        return Err(e);
+    }
+    
+    if scope == DedupScope::Film && feed_urls.len() > 1 {
+        movies = dedup_across_feeds(movies);
+    }
+    
+    sort_by_recency(&mut movies);
+    movies.truncate(limit);
+    Ok(movies)
+}
+
+// Collapse entries for the same film from different feeds, keeping the most recent one
+fn dedup_across_feeds(movies: Vec<LetterboxdMovie>) -> Vec<LetterboxdMovie> {
+    let mut by_film: HashMap<String, LetterboxdMovie> = HashMap::new();
+    for movie in movies {
+        let key = movie.film_title.clone().unwrap_or_else(|| movie.title.clone());
+        match by_film.get(&key) {
+            Some(existing) if compare_recency(&movie, existing) != Ordering::Less => {},
+            _ => {
+                by_film.insert(key, movie);
+            }
+        }
+    }
+    by_film.into_values().collect()
+}
+
+pub async fn fetch_letterboxd_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie>, String> {
+    fetch_feed_within(feed_url, None).await
+}
+
+/// Fetch a feed, using the cache when possible. With `max_age`, a cached copy fetched
+/// less than that long ago is used even if it is past the TTL, and an older one is refetched.
+async fn fetch_feed_within(feed_url: &str, max_age: Option<Duration>) -> Result<Vec<LetterboxdMovie>, String> {
+    let cache_key = feed_url.to_string();
+    let result = match max_age {
+        None => FEED_CACHE.get_or_insert_with(cache_key.clone(), || refetch_feed(feed_url)).await,
+        Some(max_age) => match FEED_CACHE.get_within(&cache_key, max_age).await {
+            Some(movies) => Ok(movies),
+            None => match refetch_feed(feed_url).await {
+                Ok(movies) => {
+                    FEED_CACHE.insert(cache_key.clone(), movies.clone()).await;
+                    Ok(movies)
+                },
+                Err(e) => Err(e),
+            },
+        },
+    };
+    
+    // Slightly stale data is better than none, including data older than max_age
+    match result {
+        Ok(movies) => Ok(movies),
+        Err(e) => match FEED_CACHE.get(&cache_key).await {
+            Some(movies) => Ok(movies),
+            None => FEED_CACHE.get_expired(&cache_key).await.ok_or(e),
+        },
+    }
+}
+
+// Download a feed and cache its channel metadata, leaving the movies for the caller to cache
+async fn refetch_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie>, String> {
+    let result = snapshot::fetch(&format!("letterboxd_{}", feed_url), download_feed(feed_url)).await;
+    health::record("letterboxd", result.is_ok());
+    let (info, movies) = result?;
+    CHANNEL_CACHE.insert(feed_url.to_string(), info).await;
+    Ok(movies)
+}
+
+// Read a feed body of at most `max_bytes`. At most one byte past the limit is read,
+// so an oversized feed is never buffered whole.
+async fn read_feed_body(response: &mut surf::Response, max_bytes: u64, url: &str) -> Result<Vec<u8>, String> {
+    if response.len().is_some_and(|len| len as u64 > max_bytes) {
+        return Err(format!("Feed is larger than the {} byte limit", max_bytes));
+    }
+    // A body that trickles in slowly is cut off by the same timeout as the response
+    let mut content = Vec::new();
+    match async_std::future::timeout(*upstream::HTTP_TIMEOUT, response.take(max_bytes + 1).read_to_end(&mut content)).await {
+        Ok(Ok(_)) => {},
+        Ok(Err(e)) => return Err(format!("Failed to read response body: {}", e)),
+        Err(_) => return Err(format!("Feed body from {} not received within {:?}", url, *upstream::HTTP_TIMEOUT)),
+    }
+    if content.len() as u64 > max_bytes {
+        return Err(format!("Feed is larger than the {} byte limit", max_bytes));
+    }
+    Ok(content)
+}
+
+/// Pick the redirect target from the `Location` header values of a response from `from`.
+/// The first value is followed; several distinct values are logged, or rejected if `strict`
+/// (`LETTERBOXD_STRICT_REDIRECTS=true`). A header with no non-empty value is an error.
+fn redirect_location<'a>(values: &[&'a str], from: &str, strict: bool) -> Result<&'a str, String> {
+    let mut locations: Vec<&str> = values.iter()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .collect();
+    let Some(&first) = locations.first() else {
+        return Err(format!("Redirect from {} has an empty Location header", from));
+    };
+
+    locations.sort_unstable();
+    locations.dedup();
+    if locations.len() > 1 {
+        if strict {
+            return Err(format!("Redirect from {} has {} conflicting Location values", from, locations.len()));
+        }
+        log::warn!("Redirect from {} has {} conflicting Location values, following {}", from, locations.len(), first);
+    }
+    Ok(first)
+}
+
+// Fetch and parse a feed from Letterboxd, bypassing the cache
+async fn download_feed(feed_url: &str) -> Result<(FeedInfo, Vec<LetterboxdMovie>), String> {
+    let start_time = Instant::now();
+    
     let mut current_url = feed_url.to_string();
-    let mut response = match surf::get(&current_url).await {
+    let mut response = match upstream::send_with_timeout(upstream::get(&current_url), &current_url).await {
         Ok(resp) => resp,
         Err(e) => return Err(format!("Failed to fetch RSS feed: {}", e)),
     };
@@ -67,30 +390,22 @@ pub async fn fetch_letterboxd_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie
     let mut redirect_count = 0;
     while response.status().is_redirection() && redirect_count < 10 {
         if let Some(loc) = response.header("Location") {
-            if let Some(value) = loc.iter().next() {
-                let loc_str = value.as_str();
-                let fixed_loc_str = if loc_str.starts_with("//") {
-                    format!("https:{}", loc_str)
-                } else {
-                    loc_str.to_string()
-                };
-                let new_url = match Url::parse(&fixed_loc_str) {
-                    Ok(url) => url.into_string(),
-                    Err(_) => {
-                        let base_url = Url::parse(&current_url).map_err(|e| format!("Invalid base URL {}: {}", current_url, e))?;
-                        base_url.join(&fixed_loc_str).map_err(|e| format!("Failed to join base URL with relative redirect: {}", e))?.into_string()
-                    }
-                };
-                
-                current_url = new_url.clone();
-                response = match surf::get(&new_url).await {
-                    Ok(resp) => resp,
-                    Err(e) => return Err(format!("Failed to follow redirect to {}: {}", new_url, e)),
-                };
-                redirect_count += 1;
-            } else {
-                break;
-            }
+            let values: Vec<&str> = loc.iter().map(|value| value.as_str()).collect();
+            let fixed_loc_str = normalize_link(redirect_location(&values, &current_url, *STRICT_REDIRECTS)?);
+            let new_url: String = match Url::parse(&fixed_loc_str) {
+                Ok(url) => url.into(),
+                Err(_) => {
+                    let base_url = Url::parse(&current_url).map_err(|e| format!("Invalid base URL {}: {}", current_url, e))?;
+                    base_url.join(&fixed_loc_str).map_err(|e| format!("Failed to join base URL with relative redirect: {}", e))?.into()
+                }
+            };
+            
+            current_url = new_url.clone();
+            response = match upstream::send_with_timeout(upstream::get(&new_url), &new_url).await {
+                Ok(resp) => resp,
+                Err(e) => return Err(format!("Failed to follow redirect to {}: {}", new_url, e)),
+            };
+            redirect_count += 1;
         } else {
             break;
         }
@@ -101,10 +416,7 @@ pub async fn fetch_letterboxd_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie
     
     let parse_start = Instant::now();
     
-    let content = match response.body_bytes().await {
-        Ok(bytes) => bytes,
-        Err(e) => return Err(format!("Failed to read response body: {}", e)),
-    };
+    let content = read_feed_body(&mut response, *FEED_MAX_BYTES, &current_url).await?;
     
     // Parse the RSS feed
     let channel = match Channel::read_from(&content[..]) {
@@ -118,77 +430,181 @@ pub async fn fetch_letterboxd_feed(feed_url: &str) -> Result<Vec<LetterboxdMovie
     let process_start = Instant::now();
     
     // Process the feed items
-    let movies = process_letterboxd_items(channel.items());
+    let movies = process_letterboxd_items(channel.items(), feed_url, *REQUIRE_FILM_TITLE);
     
     let process_time = process_start.elapsed();
     log::info!("Movie processing took: {:?}", process_time);
     
     let total_time = start_time.elapsed();
-    log::info!("Total fetch_letterboxd_feed took: {:?}", total_time);
-    
-    // Update cache with the new results
-    {
-        let mut cache_lock = FEED_CACHE.lock().unwrap();
-        cache_lock.insert(feed_url.to_string(), CacheEntry {
-            movies: movies.clone(),
-            timestamp: SystemTime::now(),
+    log::info!("Total download_feed took: {:?}", total_time);
+    
+    Ok((FeedInfo::from_channel(&channel), movies))
+}
+
+/// The `src` of the first `<img>` tag in an item description, which Letterboxd uses for the poster
+fn extract_poster_url(description: &str) -> Option<String> {
+    let tag_start = description.find("<img")?;
+    let tag = &description[tag_start..];
+    let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+    let src_start = tag.find("src=")? + "src=".len();
+    let quote = tag[src_start..].chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &tag[src_start + 1..];
+    let src = &value[..value.find(quote)?];
+    (!src.is_empty()).then(|| normalize_link(src))
+}
+
+/// Strip the tags from description HTML, decode its entities and collapse whitespace,
+/// or `None` if no text is left
+fn html_to_text(html: &str) -> Option<String> {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(tag_start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..tag_start]));
+        // Tags separate words, e.g. between paragraphs
+        text.push(' ');
+        rest = rest[tag_start..].find('>').map_or("", |tag_end| &rest[tag_start + tag_end + 1..]);
+    }
+    text.push_str(&decode_entities(rest));
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+// Decode named and numeric HTML character references, leaving unknown ones as they are
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').map(|end| &rest[1..end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "hellip" => Some('…'),
+            "ndash" => Some('–'),
+            "mdash" => Some('—'),
+            "lsquo" => Some('‘'),
+            "rsquo" => Some('’'),
+            "ldquo" => Some('“'),
+            "rdquo" => Some('”'),
+            _ => {
+                let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+            }
         });
-        log::info!("Cache updated for feed {}", feed_url);
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            },
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Make a protocol-relative (`//host/...`) or `http://` URL an absolute `https://` one,
+/// so links can be embedded in https pages without mixed-content problems
+fn normalize_link(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("//") {
+        format!("https://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("https://{}", rest)
+    } else {
+        url.to_string()
     }
+}
+
+// Build a movie from a feed item, with the film title if it has one
+fn movie_from_item(item: &Item, film_title: Option<String>, feed_url: &str) -> LetterboxdMovie {
+    let rating = extract_extension_value(item, LETTERBOXD_NAMESPACE, "memberRating");
+    let rewatch = extract_extension_value(item, LETTERBOXD_NAMESPACE, "rewatch");
+    let liked = extract_extension_value(item, LETTERBOXD_NAMESPACE, "liked");
+    let spoilers = extract_extension_value(item, LETTERBOXD_NAMESPACE, "spoilers");
+    let description = item.description().unwrap_or_default().to_string();
     
-    Ok(movies)
+    let title = item.title().unwrap_or_default().to_string();
+    LetterboxdMovie {
+        raw_title: title.clone(),
+        title,
+        link: normalize_link(item.link().unwrap_or_default()),
+        contains_spoilers: parse_yes_no(spoilers.as_deref()) || description.contains(SPOILER_MARKER),
+        poster_url: extract_poster_url(&description),
+        description_text: html_to_text(&description),
+        description,
+        pub_date: item.pub_date().map(|s| s.to_string()),
+        film_title,
+        rating_value: rating.as_deref().and_then(|rating| rating.trim().parse().ok()),
+        rating,
+        is_rewatch: parse_yes_no(rewatch.as_deref()),
+        rewatch,
+        feed_url: Some(feed_url.to_string()),
+        liked: liked.as_deref().map(|liked| parse_yes_no(Some(liked))),
+        tags: extract_extension_values(item, LETTERBOXD_NAMESPACE, "tag"),
+    }
 }
 
-fn process_letterboxd_items(items: &[Item]) -> Vec<LetterboxdMovie> {
+// Merge the feed items into movies, newest first. Items without a film title are
+// dropped if `require_film_title`, and otherwise kept under their RSS title.
+fn process_letterboxd_items(items: &[Item], feed_url: &str, require_film_title: bool) -> Vec<LetterboxdMovie> {
     let start_time = Instant::now();
     
     // Group movies by film title to handle duplicates
     let mut movie_map: HashMap<String, LetterboxdMovie> = HashMap::new();
+    // Items without a film title, kept as they are when film titles aren't required
+    let mut untitled: Vec<LetterboxdMovie> = Vec::new();
     
     for item in items {
         log::debug!("Processing item: {}", item.title().unwrap_or_default());
         // Extract Letterboxd-specific fields from extensions
         let film_title = extract_extension_value(item, LETTERBOXD_NAMESPACE, "filmTitle");
         
-        // Skip if no film title
+        // Items without a film title are skipped, unless the policy keeps them
         if let Some(film_title) = &film_title {
             log::debug!("Film title: {}", film_title);
-            let rating = extract_extension_value(item, LETTERBOXD_NAMESPACE, "memberRating");
-            let rewatch = extract_extension_value(item, LETTERBOXD_NAMESPACE, "rewatch");
-            
-            let movie = LetterboxdMovie {
-                title: item.title().unwrap_or_default().to_string(),
-                link: item.link().unwrap_or_default().to_string(),
-                description: item.description().unwrap_or_default().to_string(),
-                pub_date: item.pub_date().map(|s| s.to_string()),
-                film_title: Some(film_title.clone()),
-                rating,
-                rewatch,
-            };
+            let movie = movie_from_item(item, Some(film_title.clone()), feed_url);
 
             // If we already have an entry for this movie, update with any new info
             if let Some(existing_movie) = movie_map.get_mut(film_title) {
                 // Keep the rating if it exists
                 if existing_movie.rating.is_none() && movie.rating.is_some() {
                     existing_movie.rating = movie.rating;
+                    existing_movie.rating_value = movie.rating_value;
                 }
                 
                 // Update title to include rating if original didn't have it
                 if !existing_movie.title.contains('★') && movie.title.contains('★') {
                     existing_movie.title = movie.title;
+                    existing_movie.raw_title = movie.raw_title;
                 }
                 
                 // Keep the most recent review
-                if let (Some(existing_date), Some(new_date)) = (&existing_movie.pub_date, &movie.pub_date) {
-                    if new_date > existing_date {
-                        existing_movie.description = movie.description;
-                        existing_movie.pub_date = Some(new_date.clone());
-                    }
+                if let (Some(existing_date), Some(new_date)) = (&existing_movie.pub_date, &movie.pub_date)
+                    && new_date > existing_date {
+                    existing_movie.description = movie.description;
+                    existing_movie.contains_spoilers = movie.contains_spoilers;
+                    existing_movie.poster_url = movie.poster_url;
+                    existing_movie.description_text = movie.description_text;
+                    existing_movie.pub_date = Some(new_date.clone());
                 }
             } else {
                 // Add new movie to the map
                 movie_map.insert(film_title.clone(), movie);
             }
+        } else if !require_film_title {
+            log::debug!("No film title found, keeping item under its RSS title");
+            untitled.push(movie_from_item(item, None, feed_url));
         } else {
             log::debug!("No film title found");
         }
@@ -201,38 +617,45 @@ fn process_letterboxd_items(items: &[Item]) -> Vec<LetterboxdMovie> {
     
     // Convert hashmap to vector
     let mut movies: Vec<LetterboxdMovie> = movie_map.values().cloned().collect();
+    movies.extend(untitled);
     
-    // Sort by publication date (most recent first)
-    movies.sort_by(|a, b| {
-        match (&a.pub_date, &b.pub_date) {
-            (Some(a_date), Some(b_date)) => {
-                // Parse the RFC2822 dates
-                let a_parsed = DateTime::parse_from_rfc2822(a_date);
-                let b_parsed = DateTime::parse_from_rfc2822(b_date);
-                
-                match (a_parsed, b_parsed) {
-                    (Ok(a_dt), Ok(b_dt)) => b_dt.cmp(&a_dt), // Most recent first
-                    _ => b_date.cmp(a_date),  // Fallback to string comparison if parse fails
-                }
-            },
-            (None, Some(_)) => std::cmp::Ordering::Less,
-            (Some(_), None) => std::cmp::Ordering::Greater,
-            (None, None) => std::cmp::Ordering::Equal,
-        }
-    });
+    // Sort by publication date (most recent first). The full list is kept so
+    // that feeds can be merged before limiting to the number of movies to show.
+    sort_by_recency(&mut movies);
     
     let sorting_time = sorting_start.elapsed();
     log::debug!("Sorting movies took: {:?}", sorting_time);
     
-    // Limit to the number of movies to show
-    movies.truncate(NUMBER_OF_MOVIES_TO_SHOW);
-    
     let total_time = start_time.elapsed();
     log::debug!("Total process_letterboxd_items took: {:?}", total_time);
     
     movies
 }
 
+/// Sort movies by publication date, most recent first
+pub fn sort_by_recency(movies: &mut [LetterboxdMovie]) {
+    movies.sort_by(compare_recency);
+}
+
+// Ordering that puts the more recently published movie first
+fn compare_recency(a: &LetterboxdMovie, b: &LetterboxdMovie) -> Ordering {
+    match (&a.pub_date, &b.pub_date) {
+        (Some(a_date), Some(b_date)) => {
+            // Parse the RFC2822 dates
+            let a_parsed = DateTime::parse_from_rfc2822(a_date);
+            let b_parsed = DateTime::parse_from_rfc2822(b_date);
+            
+            match (a_parsed, b_parsed) {
+                (Ok(a_dt), Ok(b_dt)) => b_dt.cmp(&a_dt), // Most recent first
+                _ => b_date.cmp(a_date),  // Fallback to string comparison if parse fails
+            }
+        },
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
 fn extract_extension_value(item: &Item, namespace: &str, key: &str) -> Option<String> {
     item.extensions().get(namespace)
         .and_then(|ext| ext.get(key))
@@ -240,45 +663,162 @@ fn extract_extension_value(item: &Item, namespace: &str, key: &str) -> Option<St
         .and_then(|value| value.value().map(|s| s.to_string()))
 }
 
+// Every value of an extension element that may be repeated, such as tags
+fn extract_extension_values(item: &Item, namespace: &str, key: &str) -> Vec<String> {
+    item.extensions().get(namespace)
+        .and_then(|ext| ext.get(key))
+        .map(|values| values.iter()
+            .filter_map(|value| value.value())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect())
+        .unwrap_or_default()
+}
+
 pub async fn get_letterboxd_movies(req: Request<()>) -> tide::Result<Response> {
     let start_time = Instant::now();
     
     // Check for API key in the request headers
     if !auth::validate_api_key(&req) {
-        return Ok(Response::new(StatusCode::Unauthorized));
+        return Ok(auth::unauthorized_response());
+    }
+    
+    if pause::is_paused("letterboxd") {
+        return Ok(pause::paused_response("letterboxd"));
     }
     
-    // Get the feed URL from query parameters, or use default
-    let feed_url = req.url().query_pairs()
-        .find(|(k, _)| k == "feed_url")
-        .map(|(_, v)| v.to_string())
-        .unwrap_or_else(|| "https://letterboxd.com/atropos_Dad/rss".to_string());
+    // Validate and sanitize query parameters
+    let params = match QueryParams::from_request(&req, QUERY_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    // Get the feed URLs from query parameters (repeated or comma-separated), or use default
+    let mut feed_urls: Vec<String> = params.get_all("feed_url").iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    if feed_urls.is_empty() {
+        feed_urls.push(DEFAULT_FEED_URL.to_string());
+    }
+    let mut seen = HashSet::new();
+    feed_urls.retain(|url| seen.insert(url.clone()));
+    if feed_urls.len() > MAX_FEEDS {
+        return Ok(params::bad_request(&format!("At most {} feeds may be requested at once", MAX_FEEDS)));
+    }
+    
+    // How to de-duplicate entries across feeds
+    let dedup_scope = match params.get("dedup_scope") {
+        None => DedupScope::Film,
+        Some(value) => match DedupScope::parse(value) {
+            Some(scope) => scope,
+            None => return Ok(params::bad_request(&format!("Unknown dedup_scope '{}'", value))),
+        },
+    };
+    
+    // How ratings are rendered, as a number of stars by default
+    let rating_format = match params.get("rating_format") {
+        None => RatingFormat::Float,
+        Some(value) => match RatingFormat::parse(value) {
+            Some(format) => format,
+            None => return Ok(params::bad_request(&format!("Unknown rating_format '{}'", value))),
+        },
+    };
+    
+    // Get the limit from query parameters, or use default. The cache holds the whole feed, so any limit is served from it.
+    let limit = params.parse::<usize>("limit").unwrap_or(*NUMBER_OF_MOVIES_TO_SHOW);
+    
+    // Get optional filters on the liked status and a tag (case-insensitive)
+    let liked = params.parse::<bool>("liked");
+    let tag = params.get("tag").map(str::to_lowercase);
     
     // Get optional no_cache parameter
-    let no_cache = req.url().query_pairs()
-        .find(|(k, _)| k == "no_cache")
-        .map(|(_, v)| v == "true")
-        .unwrap_or(false);
+    let no_cache = params.flag("no_cache");
+    
+    // Get optional max_age parameter (seconds the client will accept from cache)
+    let max_age = params.parse::<u64>("max_age").map(Duration::from_secs);
+    
+    // Get optional clean_title parameter to return just the film name as the title
+    let clean_title = params.flag("clean_title");
+    
+    // Review text of spoiler reviews is hidden unless asked for
+    let show_spoilers = params.flag("show_spoilers");
+    
+    // Get optional fallback for movies without a poster
+    let image_fallback = match ImageFallback::from_param(params.get("image_fallback")) {
+        Ok(image_fallback) => image_fallback,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    // Get optional output format, JSON by default
+    let formatter = match format::lookup(params.get("format")) {
+        Ok(formatter) => formatter,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
         
     let setup_time = start_time.elapsed();
     log::debug!("API endpoint setup took: {:?}", setup_time);
     
     // Clear cache if requested
     if no_cache {
-        let mut cache_lock = FEED_CACHE.lock().unwrap();
-        cache_lock.remove(&feed_url);
-        log::info!("Cache cleared for feed {} due to no_cache parameter", feed_url);
+        for feed_url in &feed_urls {
+            invalidate_feed(feed_url).await;
+            log::info!("Cache cleared for feed {} due to no_cache parameter", feed_url);
+        }
     }
     
-    // Fetch and process the feed
-    match fetch_letterboxd_feed(&feed_url).await {
-        Ok(movies) => {
+    // Fetch, process and merge the feeds
+    match fetch_movies(&feed_urls, dedup_scope, usize::MAX, max_age).await {
+        Ok(mut movies) => {
             let fetch_time = start_time.elapsed();
             log::info!("Feed fetch completed in: {:?}", fetch_time);
             
+            // Filter the whole feed before limiting, so older matching entries are still found
+            movies.retain(|movie| liked.is_none_or(|liked| movie.liked == Some(liked))
+                && tag.as_ref().is_none_or(|tag| movie.tags.iter().any(|t| t.to_lowercase() == *tag)));
+            movies.truncate(limit);
+            
+            if clean_title {
+                movies.iter_mut().for_each(LetterboxdMovie::clean_title);
+            }
+            if !show_spoilers {
+                movies.iter_mut().for_each(LetterboxdMovie::hide_spoilers);
+            }
+            for movie in &mut movies {
+                image_fallback.apply(&mut movie.poster_url);
+            }
+            
+            stats::movies_served(movies.len());
+            let mut body = json!({ "movies": movies });
+            if rating_format != RatingFormat::Float {
+                for (movie, value) in movies.iter().zip(body["movies"].as_array_mut().into_iter().flatten()) {
+                    if let Some(stars) = movie.rating_value {
+                        value["rating_value"] = rating_format.render(stars);
+                    }
+                }
+            }
+            // Channel metadata only describes a single feed, so merged feeds have none
+            if let [feed_url] = feed_urls.as_slice()
+                && let Some(info) = feed_info(feed_url).await {
+                body["feed"] = json!(info);
+            }
+            
+            // Data past the TTL isn't stale if it is within the max_age the client accepts
+            let within_max_age = match max_age {
+                Some(max_age) => cached_age(&feed_urls).await.is_some_and(|age| age < max_age),
+                None => false,
+            };
             let mut res = Response::new(StatusCode::Ok);
-            res.set_content_type("application/json");
-            res.set_body(json!({ "movies": movies }));
+            if served_stale(&feed_urls).await && !within_max_age {
+                res.insert_header("Warning", upstream::STALE_WARNING);
+                body["stale"] = json!(true);
+            }
+            format::set_body(&mut res, formatter, &body, "movies");
+            if let Some(age) = cached_age(&feed_urls).await {
+                res.insert_header("Age", age.as_secs().to_string());
+            }
             
             let total_time = start_time.elapsed();
             log::info!("Total API request handled in: {:?}", total_time);
@@ -296,4 +836,419 @@ pub async fn get_letterboxd_movies(req: Request<()>) -> tide::Result<Response> {
             Ok(res)
         }
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockUpstream};
+
+    #[test]
+    fn member_rating_is_parsed_into_a_number() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Rated</title><letterboxd:memberRating>3.5</letterboxd:memberRating></item>
+<item><title>Padded</title><letterboxd:memberRating> 4.0 </letterboxd:memberRating></item>
+<item><title>Garbled</title><letterboxd:memberRating>four</letterboxd:memberRating></item>
+<item><title>Unrated</title></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let ratings: Vec<(Option<String>, Option<f32>)> = channel.items().iter()
+            .map(|item| movie_from_item(item, None, "feed"))
+            .map(|movie| (movie.rating, movie.rating_value))
+            .collect();
+
+        assert_eq!(ratings, vec![
+            (Some("3.5".to_string()), Some(3.5)),
+            (Some("4.0".to_string()), Some(4.0)),
+            (Some("four".to_string()), None),
+            (None, None),
+        ]);
+    }
+
+    #[test]
+    fn clean_title_drops_the_year_and_stars() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Heat, 1995 - ★★★★½</title><letterboxd:filmTitle>Heat</letterboxd:filmTitle></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let mut movie = movie_from_item(&channel.items()[0], Some("Heat".to_string()), "feed");
+        movie.clean_title();
+
+        assert_eq!(movie.title, "Heat");
+        assert!(!movie.title.contains('★'));
+        assert_eq!(movie.raw_title, "Heat, 1995 - ★★★★½");
+    }
+
+    #[test]
+    fn rewatch_flags_are_read_and_entries_sorted_newest_first() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Again</title><pubDate>Mon, 06 Jan 2025 20:00:00 +0000</pubDate><letterboxd:rewatch>Yes</letterboxd:rewatch></item>
+<item><title>First</title><pubDate>Wed, 08 Jan 2025 20:00:00 +0000</pubDate><letterboxd:rewatch>No</letterboxd:rewatch></item>
+<item><title>Unknown</title><pubDate>Tue, 07 Jan 2025 20:00:00 +0000</pubDate></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let mut movies: Vec<LetterboxdMovie> = channel.items().iter()
+            .map(|item| movie_from_item(item, None, "feed"))
+            .collect();
+        sort_by_recency(&mut movies);
+
+        let flags: Vec<(&str, bool, Option<&str>)> = movies.iter()
+            .map(|movie| (movie.title.as_str(), movie.is_rewatch, movie.rewatch.as_deref()))
+            .collect();
+        assert_eq!(flags, vec![
+            ("First", false, Some("No")),
+            ("Unknown", false, None),
+            ("Again", true, Some("Yes")),
+        ]);
+    }
+
+    #[async_std::test]
+    async fn a_film_shared_by_two_feeds_is_collapsed_only_in_film_scope() {
+        let first_feed = "https://example.com/dedup-first/rss".to_string();
+        let second_feed = "https://example.com/dedup-second/rss".to_string();
+        let first: Vec<LetterboxdMovie> = serde_json::from_value(json!([
+            { "title": "Heat", "link": "https://letterboxd.com/a/heat/", "description": "", "pub_date": "Sun, 05 Jan 2025 20:00:00 +0000",
+              "film_title": "Heat", "rating": null, "rewatch": null, "feed_url": first_feed },
+            { "title": "Alien", "link": "https://letterboxd.com/a/alien/", "description": "", "pub_date": "Sat, 04 Jan 2025 20:00:00 +0000",
+              "film_title": "Alien", "rating": null, "rewatch": null, "feed_url": first_feed },
+        ])).unwrap();
+        let second: Vec<LetterboxdMovie> = serde_json::from_value(json!([
+            { "title": "Heat", "link": "https://letterboxd.com/b/heat/", "description": "", "pub_date": "Thu, 09 Jan 2025 20:00:00 +0000",
+              "film_title": "Heat", "rating": null, "rewatch": null, "feed_url": second_feed },
+        ])).unwrap();
+        FEED_CACHE.insert(first_feed.clone(), first).await;
+        FEED_CACHE.insert(second_feed.clone(), second).await;
+        let feeds = [first_feed, second_feed];
+
+        let by_feed = fetch_movies(&feeds, DedupScope::Feed, usize::MAX, None).await.unwrap();
+        let links: Vec<&str> = by_feed.iter().map(|movie| movie.link.as_str()).collect();
+        assert_eq!(links, vec!["https://letterboxd.com/b/heat/", "https://letterboxd.com/a/heat/", "https://letterboxd.com/a/alien/"]);
+
+        let by_film = fetch_movies(&feeds, DedupScope::Film, usize::MAX, None).await.unwrap();
+        let links: Vec<&str> = by_film.iter().map(|movie| movie.link.as_str()).collect();
+        assert_eq!(links, vec!["https://letterboxd.com/b/heat/", "https://letterboxd.com/a/alien/"]);
+
+        // Film scope is the default when several feeds are requested
+        let mut app = tide::new();
+        app.at("/letterboxd").get(get_letterboxd_movies);
+        let mut req = tide::http::Request::get(format!("http://localhost/letterboxd?feed_url={},{}", feeds[0], feeds[1]).as_str());
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let links: Vec<&str> = body["movies"].as_array().unwrap().iter().map(|movie| movie["link"].as_str().unwrap()).collect();
+        assert_eq!(links, vec!["https://letterboxd.com/b/heat/", "https://letterboxd.com/a/alien/"]);
+    }
+
+    #[async_std::test]
+    async fn an_empty_feed_is_a_success_even_when_another_fails() {
+        let empty_feed = "https://example.com/merge-empty/rss".to_string();
+        FEED_CACHE.insert(empty_feed.clone(), vec![]).await;
+        let failing_feed = test_support::refused_url("/rss").await;
+
+        let movies = fetch_movies(&[empty_feed, failing_feed.clone()], DedupScope::Film, usize::MAX, None).await.unwrap();
+        assert!(movies.is_empty());
+
+        assert!(fetch_movies(&[failing_feed], DedupScope::Film, usize::MAX, None).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn upstream_calls_are_counted_on_a_cold_fetch_only() {
+        let body = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel><title>Films</title>
+<item><title>Film, 2020</title><letterboxd:filmTitle>Film</letterboxd:filmTitle></item>
+</channel></rss>"#;
+        let feed = MockUpstream::always(test_support::ok("application/rss+xml", body)).await;
+        let feed_url = feed.url("/rss");
+
+        let mut app = tide::new();
+        app.at("/letterboxd").with(upstream::UpstreamCallCounter).get(get_letterboxd_movies);
+        let url = format!("http://localhost/letterboxd?feed_url={}", feed_url);
+        let mut calls = Vec::new();
+        for _ in 0..2 {
+            let mut req = tide::http::Request::get(url.as_str());
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            calls.push(res.header("X-Upstream-Calls").unwrap().as_str().parse::<u32>().unwrap());
+        }
+
+        assert!(calls[0] > 0);
+        assert_eq!(calls[1], 0);
+    }
+
+    #[async_std::test]
+    async fn age_is_zero_when_fetched_and_grows_on_cache_hits() {
+        let body = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel><title>Films</title>
+<item><title>Film, 2020</title><letterboxd:filmTitle>Film</letterboxd:filmTitle></item>
+</channel></rss>"#;
+        let feed = MockUpstream::always(test_support::ok("application/rss+xml", body)).await;
+        let feed_url = feed.url("/rss");
+
+        let mut app = tide::new();
+        app.at("/letterboxd").get(get_letterboxd_movies);
+        let url = format!("http://localhost/letterboxd?feed_url={}", feed_url);
+        let mut ages = Vec::new();
+        for _ in 0..2 {
+            let mut req = tide::http::Request::get(url.as_str());
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            ages.push(res.header("Age").unwrap().as_str().parse::<u64>().unwrap());
+            async_std::task::sleep(Duration::from_millis(1100)).await;
+        }
+
+        assert_eq!(ages[0], 0);
+        assert!(ages[1] >= 1);
+    }
+
+    #[test]
+    fn protocol_relative_and_http_links_become_https() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Relative</title><link>//letterboxd.com/someone/film/heat/</link>
+<description><![CDATA[<p><img src="//a.ltrbxd.com/heat.jpg"/></p>]]></description></item>
+<item><title>Plain</title><link>http://letterboxd.com/someone/film/ran/</link>
+<description><![CDATA[<p><img src='http://a.ltrbxd.com/ran.jpg'/></p>]]></description></item>
+<item><title>Secure</title><link>https://letterboxd.com/someone/film/alien/</link></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let links: Vec<(String, Option<String>)> = channel.items().iter()
+            .map(|item| movie_from_item(item, None, "feed"))
+            .map(|movie| (movie.link, movie.poster_url))
+            .collect();
+
+        assert_eq!(links, vec![
+            ("https://letterboxd.com/someone/film/heat/".to_string(), Some("https://a.ltrbxd.com/heat.jpg".to_string())),
+            ("https://letterboxd.com/someone/film/ran/".to_string(), Some("https://a.ltrbxd.com/ran.jpg".to_string())),
+            ("https://letterboxd.com/someone/film/alien/".to_string(), None),
+        ]);
+    }
+
+    #[test]
+    fn untitled_items_are_dropped_or_kept_by_policy() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>A list</title>
+<item><title>Heat, 1995</title><pubDate>Wed, 08 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Heat</letterboxd:filmTitle></item>
+<item><title>A note about the list</title><pubDate>Tue, 07 Jan 2025 20:00:00 +0000</pubDate></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+
+        let required = process_letterboxd_items(channel.items(), "feed", true);
+        let titles: Vec<(&str, Option<&str>)> = required.iter()
+            .map(|movie| (movie.title.as_str(), movie.film_title.as_deref()))
+            .collect();
+        assert_eq!(titles, vec![("Heat, 1995", Some("Heat"))]);
+
+        let kept = process_letterboxd_items(channel.items(), "feed", false);
+        let titles: Vec<(&str, Option<&str>)> = kept.iter()
+            .map(|movie| (movie.title.as_str(), movie.film_title.as_deref()))
+            .collect();
+        assert_eq!(titles, vec![("Heat, 1995", Some("Heat")), ("A note about the list", None)]);
+    }
+
+    #[test]
+    fn location_headers_with_one_several_or_no_values() {
+        let from = "https://letterboxd.com/someone/rss/";
+
+        assert_eq!(redirect_location(&["https://letterboxd.com/other/rss/"], from, true), Ok("https://letterboxd.com/other/rss/"));
+        // Repeats of the same location aren't a conflict
+        assert_eq!(redirect_location(&["/a/rss/", " /a/rss/ "], from, true), Ok("/a/rss/"));
+
+        let conflicting = ["/first/rss/", "/second/rss/"];
+        assert_eq!(redirect_location(&conflicting, from, false), Ok("/first/rss/"));
+        assert_eq!(
+            redirect_location(&conflicting, from, true),
+            Err("Redirect from https://letterboxd.com/someone/rss/ has 2 conflicting Location values".to_string())
+        );
+
+        assert_eq!(
+            redirect_location(&["", "  "], from, false),
+            Err("Redirect from https://letterboxd.com/someone/rss/ has an empty Location header".to_string())
+        );
+    }
+
+    #[test]
+    fn channel_metadata_is_read_from_the_feed() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Letterboxd - someone</title>
+<link>https://letterboxd.com/someone/</link>
+<description>Letterboxd - someone</description>
+<image><url>//a.ltrbxd.com/avatar.jpg</url><title>someone</title><link>https://letterboxd.com/someone/</link></image>
+<item><title>Heat</title></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let feed = FeedInfo::from_channel(&channel);
+
+        assert_eq!(json!(feed), json!({
+            "title": "Letterboxd - someone",
+            "description": "Letterboxd - someone",
+            "image_url": "https://a.ltrbxd.com/avatar.jpg",
+        }));
+
+        let without_image = Channel::read_from(r#"<rss version="2.0"><channel><title>Films</title><description></description></channel></rss>"#.as_bytes()).unwrap();
+        assert_eq!(FeedInfo::from_channel(&without_image).image_url, None);
+    }
+
+    #[async_std::test]
+    async fn an_oversized_feed_fails_with_a_clear_error() {
+        let body = "x".repeat(4096);
+        // The first response declares its size, the later ones only end when the connection closes
+        let feed = MockUpstream::start(move |number, _| if number == 0 {
+            test_support::ok("application/rss+xml", &body)
+        } else {
+            format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n", body.len(), body)
+        }).await;
+        let url = feed.url("/rss");
+
+        for _ in 0..2 {
+            let mut response = upstream::send(upstream::get(&url)).await.unwrap();
+            let error = read_feed_body(&mut response, 1024, &url).await.unwrap_err();
+            assert_eq!(error, "Feed is larger than the 1024 byte limit");
+        }
+
+        let mut response = upstream::send(upstream::get(&url)).await.unwrap();
+        assert_eq!(read_feed_body(&mut response, 8192, &url).await.unwrap().len(), 4096);
+    }
+
+    #[async_std::test]
+    async fn liked_and_tag_filters_match_only_items_with_the_fields() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Heat</title><pubDate>Wed, 08 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Heat</letterboxd:filmTitle>
+<letterboxd:liked>Yes</letterboxd:liked><letterboxd:tag>Heist</letterboxd:tag><letterboxd:tag>la</letterboxd:tag></item>
+<item><title>Ran</title><pubDate>Tue, 07 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Ran</letterboxd:filmTitle>
+<letterboxd:liked>No</letterboxd:liked></item>
+<item><title>Alien</title><pubDate>Mon, 06 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Alien</letterboxd:filmTitle></item>
+</channel>
+</rss>"#;
+        let feed_url = "https://example.com/liked-and-tags/rss";
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let movies = process_letterboxd_items(channel.items(), feed_url, true);
+        let fields: Vec<(&str, Option<bool>, Vec<String>)> = movies.iter()
+            .map(|movie| (movie.title.as_str(), movie.liked, movie.tags.clone()))
+            .collect();
+        assert_eq!(fields, vec![
+            ("Heat", Some(true), vec!["Heist".to_string(), "la".to_string()]),
+            ("Ran", Some(false), vec![]),
+            ("Alien", None, vec![]),
+        ]);
+        FEED_CACHE.insert(feed_url.to_string(), movies).await;
+
+        let mut app = tide::new();
+        app.at("/letterboxd").get(get_letterboxd_movies);
+        let titles = |query: &str| {
+            let mut req = tide::http::Request::get(format!("http://localhost/letterboxd?feed_url={}&{}", feed_url, query).as_str());
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let body: serde_json::Value = res.body_json().await.unwrap();
+                body["movies"].as_array().unwrap().iter()
+                    .map(|movie| movie["title"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(titles("liked=true").await, vec!["Heat"]);
+        assert_eq!(titles("liked=false").await, vec!["Ran"]);
+        assert_eq!(titles("tag=heist").await, vec!["Heat"]);
+        assert!(titles("tag=horror").await.is_empty());
+        assert_eq!(titles("").await, vec!["Heat", "Ran", "Alien"]);
+    }
+
+    #[test]
+    fn a_three_and_a_half_star_rating_in_each_format() {
+        let format = |name: &str| RatingFormat::parse(name).unwrap();
+
+        assert_eq!(format("float").render(3.5), json!(3.5));
+        assert_eq!(format("string").render(3.5), json!("3.5"));
+        assert_eq!(format("ten_scale").render(3.5), json!(7));
+        assert_eq!(RatingFormat::parse("percent"), None);
+
+        // A rating merged in from a later duplicate keeps its number alongside the string
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Heat</title><pubDate>Wed, 08 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Heat</letterboxd:filmTitle></item>
+<item><title>Heat - ★★★½</title><pubDate>Tue, 07 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Heat</letterboxd:filmTitle>
+<letterboxd:memberRating>3.5</letterboxd:memberRating></item>
+</channel>
+</rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let movies = process_letterboxd_items(channel.items(), "feed", true);
+        assert_eq!(movies.len(), 1);
+        assert_eq!((movies[0].rating.as_deref(), movies[0].rating_value), (Some("3.5"), Some(3.5)));
+    }
+
+    #[async_std::test]
+    async fn spoiler_reviews_are_hidden_unless_asked_for() {
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:letterboxd="https://letterboxd.com">
+<channel>
+<title>Films</title>
+<item><title>Se7en, 1995 - ★★★★★</title><pubDate>Wed, 08 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Se7en</letterboxd:filmTitle>
+<letterboxd:memberRating>5.0</letterboxd:memberRating><letterboxd:spoilers>Yes</letterboxd:spoilers><description>What's in the box</description></item>
+<item><title>Psycho</title><pubDate>Tue, 07 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Psycho</letterboxd:filmTitle>
+<description><![CDATA[<p><em>This review may contain spoilers.</em></p><p>Mother</p>]]></description></item>
+<item><title>Heat</title><pubDate>Mon, 06 Jan 2025 20:00:00 +0000</pubDate><letterboxd:filmTitle>Heat</letterboxd:filmTitle>
+<description>Great diner scene</description></item>
+</channel>
+</rss>"#;
+        let feed_url = "https://example.com/spoilers/rss";
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+        let movies = process_letterboxd_items(channel.items(), feed_url, true);
+        let flags: Vec<bool> = movies.iter().map(|movie| movie.contains_spoilers).collect();
+        assert_eq!(flags, vec![true, true, false]);
+        FEED_CACHE.insert(feed_url.to_string(), movies).await;
+
+        let mut app = tide::new();
+        app.at("/letterboxd").get(get_letterboxd_movies);
+        let movies = |query: &str| {
+            let mut req = tide::http::Request::get(format!("http://localhost/letterboxd?feed_url={}&{}", feed_url, query).as_str());
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let body: serde_json::Value = res.body_json().await.unwrap();
+                body["movies"].as_array().unwrap().clone()
+            }
+        };
+
+        let hidden = movies("").await;
+        assert_eq!(hidden[0]["description"], "");
+        assert_eq!(hidden[0]["title"], "Se7en, 1995 - ★★★★★");
+        assert_eq!(hidden[0]["rating_value"], 5.0);
+        assert_eq!(hidden[1]["description"], "");
+        assert_eq!(hidden[2]["description"], "Great diner scene");
+
+        let shown = movies("show_spoilers=true").await;
+        assert_eq!(shown[0]["description"], "What's in the box");
+        assert!(shown[1]["description"].as_str().unwrap().contains("Mother"));
+    }
+}
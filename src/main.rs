@@ -1,9 +1,12 @@
-use tide::{log, prelude::*};
+use tide::log;
 use dotenv::dotenv;
 use femme::LevelFilter;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 use tide::security::{CorsMiddleware, Origin};
 use http_types::headers::HeaderValue;
+use upstream::UpstreamCallCounter;
 
 mod url_handlers;
 mod auth;
@@ -11,6 +14,47 @@ mod letterboxd;
 mod spotify;
 mod cache;
 mod aggregator;
+mod params;
+mod upstream;
+mod startup;
+mod admin;
+mod select;
+mod health;
+mod profiles;
+mod timeout;
+mod bench;
+mod images;
+mod pause;
+mod concurrency;
+mod snapshot;
+mod stats;
+mod format;
+mod index;
+mod rate_limit;
+#[cfg(test)]
+mod test_support;
+
+/// Path prefix all routes are registered under, e.g. `/api` when mounted behind a
+/// reverse proxy that doesn't strip it. Empty by default.
+static ROUTE_PREFIX: LazyLock<String> = LazyLock::new(|| {
+    let prefix = env::var("ROUTE_PREFIX").unwrap_or_default();
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() { String::new() } else { format!("/{}", prefix) }
+});
+
+/// The full path of a route under `ROUTE_PREFIX`
+fn route(path: &str) -> String {
+    prefixed(&ROUTE_PREFIX, path)
+}
+
+// The full path of a route under a normalized prefix such as `/api`, or `""` for none
+fn prefixed(prefix: &str, path: &str) -> String {
+    match (prefix, path) {
+        ("", path) => path.to_string(),
+        (prefix, "/") => prefix.to_string(),
+        (prefix, path) => format!("{}{}", prefix, path),
+    }
+}
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
@@ -27,11 +71,11 @@ async fn main() -> tide::Result<()> {
     }
     
     // Check for critical environment variables
-    let api_key = env::var("API_KEY").unwrap_or_else(|_| {
-        log::warn!("API_KEY not set in environment");
-        "missing".to_string()
-    });
-    log::info!("API_KEY is {}", if api_key != "missing" { "set" } else { "missing" });
+    let api_key_set = ["API_KEY", "API_KEYS"].iter().any(|name| env::var(name).is_ok_and(|v| !v.trim().is_empty()));
+    if !api_key_set {
+        log::warn!("Neither API_KEY nor API_KEYS is set in environment");
+    }
+    log::info!("API key is {}", if api_key_set { "set" } else { "missing" });
     
         
     let allowed_origin = env::var("ALLOWED_ORIGIN").unwrap_or_else(|_| "https://jeaic.com".to_string());
@@ -47,27 +91,97 @@ async fn main() -> tide::Result<()> {
     };
     tide::log::with_level(log_level);
     
-    let mut app = tide::new();
-    let cors = CorsMiddleware::new()
-        // .allow_origin(Origin::Any)
-        .allow_origin(Origin::Exact(allowed_origin))
-        .allow_methods("GET, POST, OPTIONS".parse::<HeaderValue>().unwrap())
-        .allow_credentials(false);
-    app.with(cors);
-    
     // Get host and port from environment variables or use defaults
     let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = env::var("PORT").unwrap_or_else(|_| "4653".to_string());
     log::info!("Using HOST={} and PORT={}", host, port);
+    startup::log_summary(&host, &port, &allowed_origin);
+    upstream::init_client();
+    stats::init();
+    url_handlers::load_urls(Path::new(url_handlers::URL_FILE));
     
-    app.at("/").get(|_| async { Ok("API Endpoint Aggregator") });
-    app.at("/url-webhook").post(url_handlers::log_url);
-    app.at("/url-webhook").get(url_handlers::get_urls);
-    app.at("/letterboxd").get(letterboxd::get_letterboxd_movies);
-    app.at("/spotify").get(spotify::get_spotify_tracks);
-    app.at("/aggregated").get(aggregator::get_aggregated_data);
+    let app = build_app(allowed_origin, &ROUTE_PREFIX);
     
-    log::info!("Server running on http://{}:{}", host, port);
+    // Time one request to each endpoint and exit, instead of serving
+    if bench::enabled() {
+        if let Err(e) = bench::run(&app, &ROUTE_PREFIX).await {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    
+    // Persist webhook URLs off the request path, and flush them once more on shutdown
+    async_std::task::spawn(url_handlers::run_url_writer(PathBuf::from(url_handlers::URL_FILE)));
+    async_std::task::spawn(cache::run_sweeper());
+    if let Err(e) = ctrlc::set_handler(|| {
+        log::info!("Shutting down, flushing URLs to file");
+        url_handlers::flush_urls(Path::new(url_handlers::URL_FILE));
+        std::process::exit(0);
+    }) {
+        log::warn!("Failed to install shutdown handler: {}", e);
+    }
+    
+    log::info!("Server running on http://{}:{}{}", host, port, route("/"));
     app.listen(format!("{}:{}", host, port)).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Build the server with its middleware and routes, registered under `prefix`
+fn build_app(allowed_origin: String, prefix: &str) -> tide::Server<()> {
+    let route = |path: &str| prefixed(prefix, path);
+    let mut app = tide::new();
+    let cors = CorsMiddleware::new()
+        // .allow_origin(Origin::Any)
+        .allow_origin(Origin::Exact(allowed_origin))
+        .allow_methods("GET, POST, DELETE, OPTIONS".parse::<HeaderValue>().unwrap())
+        .allow_credentials(false);
+    app.with(cors);
+    app.with(rate_limit::RateLimit::new());
+    app.with(concurrency::ConcurrencyLimit::new(prefix));
+    app.with(timeout::RequestTimeout::new());
+    
+    app.at(&route("/")).get(index::get_index);
+    app.at(&route("/url-webhook")).post(url_handlers::log_url);
+    app.at(&route("/url-webhook")).get(url_handlers::get_urls);
+    app.at(&route("/url-webhook/pin")).post(url_handlers::pin_url);
+    app.at(&route("/url-webhook/pin")).delete(url_handlers::unpin_url);
+    app.at(&route("/letterboxd")).with(UpstreamCallCounter).get(letterboxd::get_letterboxd_movies);
+    app.at(&route("/spotify")).with(UpstreamCallCounter).get(spotify::get_spotify_tracks);
+    app.at(&route("/spotify/top")).with(UpstreamCallCounter).get(spotify::get_top_items);
+    app.at(&route("/spotify/now-playing")).with(UpstreamCallCounter).get(spotify::get_now_playing);
+    app.at(&route("/spotify/export.jsonl")).get(spotify::export_tracks);
+    app.at(&route("/spotify/token/refresh")).with(UpstreamCallCounter).post(spotify::refresh_token);
+    app.at(&route("/aggregated")).with(UpstreamCallCounter).get(aggregator::get_aggregated_data);
+    app.at(&route("/aggregated/:profile")).with(UpstreamCallCounter).get(aggregator::get_profile_data);
+    app.at(&route("/recent")).with(UpstreamCallCounter).get(aggregator::get_recent);
+    app.at(&route("/on-this-day")).with(UpstreamCallCounter).get(aggregator::get_on_this_day);
+    app.at(&route("/counts")).with(UpstreamCallCounter).get(aggregator::get_counts);
+    app.at(&route("/stats/served")).get(stats::get_served);
+    app.at(&route("/cache/stats")).get(stats::get_cache_stats);
+    app.at(&route("/health")).get(health::get_health);
+    app.at(&route("/health/score")).get(health::get_health_score);
+    app.at(&route("/admin/rebuild")).with(UpstreamCallCounter).post(admin::rebuild);
+    app.at(&route("/admin/spotify/excluded-genres")).post(admin::set_excluded_genres);
+    app.at(&route("/admin/source/:name/pause")).post(admin::pause_source);
+    app.at(&route("/admin/source/:name/resume")).post(admin::resume_source);
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn routes_respond_under_the_prefix() {
+        let app = build_app("*".to_string(), "/api");
+
+        for path in ["/api", "/api/health", "/api/health/score"] {
+            let res: tide::http::Response = app.respond(tide::http::Request::get(format!("http://localhost{}", path).as_str())).await.unwrap();
+            assert_eq!(res.status(), tide::StatusCode::Ok, "{}", path);
+        }
+
+        let res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/health")).await.unwrap();
+        assert_eq!(res.status(), tide::StatusCode::NotFound);
+    }
+}
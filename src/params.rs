@@ -0,0 +1,145 @@
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tide::{log, Request, Response, StatusCode};
+use tide::prelude::*;
+use url::Url;
+
+/// Whether to reject requests with query parameters the endpoint doesn't know
+static STRICT_QUERY_PARAMS: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("STRICT_QUERY_PARAMS").is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+});
+
+/// Length limit for query parameters without a specific limit below
+const DEFAULT_MAX_PARAM_LENGTH: usize = 256;
+
+/// Per-parameter length limits, in characters
+const MAX_PARAM_LENGTHS: &[(&str, usize)] = &[
+    ("feed_url", 2048),
+    ("artist", 200),
+];
+
+fn max_length(key: &str) -> usize {
+    MAX_PARAM_LENGTHS.iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, max)| *max)
+        .unwrap_or(DEFAULT_MAX_PARAM_LENGTH)
+}
+
+/// Query parameters of a request, validated against length limits and with
+/// control characters stripped so values are safe to use and log
+pub struct QueryParams {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    /// Parse the request's query string, returning an error message if any
+    /// parameter exceeds its length limit, or in strict mode if any parameter
+    /// is not one of the endpoint's `known` parameters
+    pub fn from_request<State>(req: &Request<State>, known: &[&str]) -> Result<Self, String> {
+        Self::from_url(req.url(), known, *STRICT_QUERY_PARAMS)
+    }
+
+    /// Parse the query string of a URL, rejecting unknown parameters if `strict`
+    pub(crate) fn from_url(url: &Url, known: &[&str], strict: bool) -> Result<Self, String> {
+        let mut pairs = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            let key: String = key.chars().filter(|c| !c.is_control()).collect();
+            let limit = max_length(&key);
+            if key.chars().count() > DEFAULT_MAX_PARAM_LENGTH || value.chars().count() > limit {
+                log::warn!("Rejecting request with over-length query parameter {:.64}", key);
+                return Err(format!(
+                    "Query parameter '{:.64}' exceeds the maximum length of {}", key, limit
+                ));
+            }
+
+            let value = value.chars().filter(|c| !c.is_control()).collect();
+            pairs.push((key, value));
+        }
+
+        if strict {
+            let mut unknown: Vec<&str> = pairs.iter()
+                .map(|(key, _)| key.as_str())
+                .filter(|key| !known.contains(key))
+                .collect();
+            unknown.sort();
+            unknown.dedup();
+            if !unknown.is_empty() {
+                log::warn!("Rejecting request with unknown query parameters {:?}", unknown);
+                return Err(format!("Unknown query parameters: {}", unknown.join(", ")));
+            }
+        }
+
+        Ok(QueryParams { pairs })
+    }
+
+    /// First value of a parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of a parameter that may be repeated
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs.iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// First value of a parameter parsed into `T`, or `None` if absent or unparseable
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|v| v.parse::<T>().ok())
+    }
+
+    /// Whether a boolean flag parameter is set to "true"
+    pub fn flag(&self, key: &str) -> bool {
+        self.get(key) == Some("true")
+    }
+}
+
+/// JSON 400 response with an error message
+pub fn bad_request(message: &str) -> Response {
+    let mut res = Response::new(StatusCode::BadRequest);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "error": message }));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn over_length_values_are_rejected() {
+        let url = Url::parse(&format!("http://localhost/spotify?album={}", "a".repeat(257))).unwrap();
+        let error = QueryParams::from_url(&url, &["album"], false).err().unwrap();
+        assert_eq!(error, "Query parameter 'album' exceeds the maximum length of 256");
+
+        // feed_url has a longer limit of its own
+        let url = Url::parse(&format!("http://localhost/letterboxd?feed_url={}", "a".repeat(1000))).unwrap();
+        assert!(QueryParams::from_url(&url, &["feed_url"], false).is_ok());
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        let url = Url::parse("http://localhost/spotify?album=Abbey%0D%0ARoad%00&li%07mit=3").unwrap();
+        let params = QueryParams::from_url(&url, &["album", "limit"], false).unwrap();
+
+        assert_eq!(params.get("album"), Some("AbbeyRoad"));
+        assert_eq!(params.parse::<usize>("limit"), Some(3));
+    }
+
+    #[test]
+    fn a_misspelled_parameter_is_rejected_only_in_strict_mode() {
+        let url = Url::parse("http://localhost/spotify?limt=5&limit=3").unwrap();
+
+        let error = QueryParams::from_url(&url, &["limit"], true).err().unwrap();
+        assert_eq!(error, "Unknown query parameters: limt");
+
+        // Lenient mode ignores the typo and keeps the known parameters
+        let params = QueryParams::from_url(&url, &["limit"], false).unwrap();
+        assert_eq!(params.parse::<usize>("limit"), Some(3));
+    }
+}
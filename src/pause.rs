@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tide::{Response, StatusCode};
+use tide::prelude::*;
+
+// Whether each upstream source is paused. Kept in memory only, so a restart resumes everything.
+static LETTERBOXD_PAUSED: AtomicBool = AtomicBool::new(false);
+static SPOTIFY_PAUSED: AtomicBool = AtomicBool::new(false);
+
+fn flag(source: &str) -> Option<&'static AtomicBool> {
+    match source {
+        "letterboxd" => Some(&LETTERBOXD_PAUSED),
+        "spotify" => Some(&SPOTIFY_PAUSED),
+        _ => None,
+    }
+}
+
+/// Pause or resume a source. Returns `false` for an unknown source.
+pub fn set_paused(source: &str, paused: bool) -> bool {
+    match flag(source) {
+        Some(flag) => {
+            flag.store(paused, Ordering::Relaxed);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Whether a source is paused, in which case it must not be fetched from upstream
+pub fn is_paused(source: &str) -> bool {
+    flag(source).is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Response for a source endpoint while the source is paused
+pub fn paused_response(source: &str) -> Response {
+    let mut res = Response::new(StatusCode::ServiceUnavailable);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "error": format!("The {} source is paused.", source), "paused": true }));
+    res
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use serde::Deserialize;
+use tide::log;
+
+/// A named set of aggregation settings, served at `/aggregated/{profile}`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Sources to include (`urls`, `letterboxd`, `spotify`); all of them if absent
+    pub sources: Option<Vec<String>>,
+    #[serde(default)]
+    pub limits: ProfileLimits,
+    /// Letterboxd feed to use instead of the default
+    pub feed: Option<String>,
+}
+
+/// Per-source item limits of a profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileLimits {
+    pub urls: Option<usize>,
+    pub letterboxd: Option<usize>,
+    pub spotify: Option<usize>,
+}
+
+// Profiles configured as a JSON object in PROFILES, mapping each name to its settings
+static PROFILES: LazyLock<HashMap<String, Profile>> = LazyLock::new(|| {
+    let Ok(config) = std::env::var("PROFILES") else {
+        return HashMap::new();
+    };
+    match serde_json::from_str::<HashMap<String, Profile>>(&config) {
+        Ok(profiles) => {
+            log::info!("Loaded {} aggregation profiles", profiles.len());
+            profiles
+        },
+        Err(e) => {
+            log::error!("Ignoring invalid PROFILES configuration: {}", e);
+            HashMap::new()
+        }
+    }
+});
+
+/// Look up a configured profile by name
+pub fn get(name: &str) -> Option<&'static Profile> {
+    PROFILES.get(name)
+}
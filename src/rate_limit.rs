@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tide::{log, Middleware, Next, Request, Response, StatusCode};
+use tide::prelude::*;
+use crate::auth;
+
+/// Number of tracked clients above which expired windows are swept out
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Requests each client may make per window, from `RATE_LIMIT_MAX`. No limit if unset.
+pub static RATE_LIMIT_MAX: LazyLock<Option<u32>> = LazyLock::new(|| {
+    std::env::var("RATE_LIMIT_MAX")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|max| *max > 0)
+});
+
+/// Length of a rate limit window, from `RATE_LIMIT_WINDOW_SECS` (default 60)
+pub static RATE_LIMIT_WINDOW: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("RATE_LIMIT_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(60);
+    Duration::from_secs(secs)
+});
+
+/// Start of a client's current window and the requests made in it
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+static WINDOWS: LazyLock<Mutex<HashMap<String, Window>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+// The valid API key sent with the request if any, otherwise the peer's IP address.
+// Keys are identified by position so an invalid credential can't buy a fresh window.
+fn client_key<State>(req: &Request<State>) -> String {
+    if let Some(index) = auth::matching_key(req) {
+        return format!("key:{}", index);
+    }
+    let peer = req.peer_addr().unwrap_or("unknown");
+    // Drop the port so all connections from one host share a window
+    let ip = peer.rsplit_once(':').map_or(peer, |(ip, _)| ip);
+    format!("ip:{}", ip)
+}
+
+/// Time until the client may make another request, or `None` if this one is allowed
+fn check(key: String, max: u32, window: Duration) -> Option<Duration> {
+    let now = Instant::now();
+    let mut windows = WINDOWS.lock().unwrap();
+    if windows.len() > SWEEP_THRESHOLD {
+        windows.retain(|_, w| now.duration_since(w.started) < window);
+    }
+
+    let entry = windows.entry(key).or_insert(Window { started: now, count: 0 });
+    if now.duration_since(entry.started) >= window {
+        *entry = Window { started: now, count: 0 };
+    }
+    if entry.count >= max {
+        return Some(window - now.duration_since(entry.started));
+    }
+    entry.count += 1;
+    None
+}
+
+/// Middleware allowing each client `RATE_LIMIT_MAX` requests per `RATE_LIMIT_WINDOW_SECS`,
+/// with a fixed window per valid API key, or per IP address for requests without one.
+/// Requests over the limit get 429 Too Many Requests with `Retry-After`.
+pub struct RateLimit {
+    max: Option<u32>,
+    window: Duration,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        RateLimit { max: *RATE_LIMIT_MAX, window: *RATE_LIMIT_WINDOW }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let Some(max) = self.max else {
+            return Ok(next.run(req).await);
+        };
+
+        if let Some(retry_after) = check(client_key(&req), max, self.window) {
+            // Round up so clients never retry before the window has reset
+            let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            log::warn!("Rate limit exceeded for request to {}, retry after {}s", req.url().path(), retry_after_secs);
+            let mut res = Response::new(StatusCode::TooManyRequests);
+            res.insert_header("Retry-After", retry_after_secs.to_string());
+            res.set_content_type("application/json");
+            res.set_body(json!({ "error": "Rate limit exceeded, try again later" }));
+            return Ok(res);
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[async_std::test]
+    async fn invalid_credentials_share_the_ip_window() {
+        let mut app = tide::new();
+        app.with(RateLimit { max: Some(2), window: Duration::from_secs(60) });
+        app.at("/").get(|_| async { Ok("ok") });
+        test_support::use_test_key();
+
+        let request = |credential: Option<String>| {
+            let mut req = tide::http::Request::get("http://localhost/");
+            req.set_peer_addr(Some("203.0.113.55:40000"));
+            if let Some(credential) = credential {
+                req.insert_header(auth::API_KEY_HEADER.as_str(), credential);
+            }
+            req
+        };
+
+        // A different made-up bearer value each time still counts against the IP
+        for attempt in 0..2 {
+            let res: tide::http::Response = app.respond(request(Some(format!("Bearer made-up-{}", attempt)))).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let mut res: tide::http::Response = app.respond(request(Some("Bearer made-up-2".to_string()))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        let retry_after: u64 = res.header("Retry-After").unwrap().as_str().parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body, json!({ "error": "Rate limit exceeded, try again later" }));
+        let res: tide::http::Response = app.respond(request(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+
+        // A valid key has a window of its own
+        let res: tide::http::Response = app.respond(request(Some(test_support::credential()))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(WINDOWS.lock().unwrap().keys().all(|key| !key.contains("made-up")));
+    }
+}
@@ -0,0 +1,93 @@
+use serde_json::Value;
+
+/// One step of a selection path: a field name, optionally marked with `[]` as an array
+struct Segment<'a> {
+    field: &'a str,
+    array: bool,
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment<'_>>, String> {
+    path.split('.')
+        .map(|part| {
+            let (field, array) = match part.strip_suffix("[]") {
+                Some(field) => (field, true),
+                None => (part, false),
+            };
+            if field.is_empty() || !field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Invalid select path '{}'", path));
+            }
+            Ok(Segment { field, array })
+        })
+        .collect()
+}
+
+fn apply(value: &Value, segments: &[Segment]) -> Result<Value, String> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Ok(value.clone());
+    };
+
+    match value {
+        // Selecting a field from an array selects it from every element
+        Value::Array(items) => items.iter()
+            .map(|item| apply(item, segments))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        Value::Object(fields) => {
+            let child = fields.get(segment.field)
+                .ok_or_else(|| format!("Unknown field '{}' in select path", segment.field))?;
+            if segment.array && !child.is_array() {
+                return Err(format!("Field '{}' in select path is not an array", segment.field));
+            }
+            apply(child, rest)
+        },
+        _ => Err(format!("Cannot select field '{}' from a scalar value", segment.field)),
+    }
+}
+
+/// Project a serialized response down to a dotted path such as `tracks.album_image_url`.
+/// Fields are looked up on every element of an array; `tracks[].track_name` additionally
+/// requires `tracks` to be an array. Returns an error for malformed or unknown paths.
+pub fn select(value: &Value, path: &str) -> Result<Value, String> {
+    let segments = parse_path(path)?;
+    apply(value, &segments)
+}
+
+/// Keep only the given fields on an object, or on every object in an array
+pub fn retain_fields(value: &mut Value, keep: &[String]) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| retain_fields(item, keep)),
+        Value::Object(fields) => fields.retain(|field, _| keep.contains(field)),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn a_scalar_field_is_selected() {
+        let value = json!({ "status": { "tracks": "ok" }, "tracks": [] });
+        assert_eq!(select(&value, "status.tracks"), Ok(json!("ok")));
+    }
+
+    #[test]
+    fn a_field_is_selected_from_every_array_element() {
+        let value = json!({ "tracks": [
+            { "track_name": "One", "artist": "A" },
+            { "track_name": "Two", "artist": "B" },
+        ] });
+        assert_eq!(select(&value, "tracks[].track_name"), Ok(json!(["One", "Two"])));
+        assert_eq!(select(&value, "tracks.artist"), Ok(json!(["A", "B"])));
+    }
+
+    #[test]
+    fn invalid_paths_are_rejected() {
+        let value = json!({ "tracks": [{ "track_name": "One" }], "urls": "none" });
+        assert_eq!(select(&value, "tracks..name"), Err("Invalid select path 'tracks..name'".to_string()));
+        assert_eq!(select(&value, "movies"), Err("Unknown field 'movies' in select path".to_string()));
+        assert_eq!(select(&value, "urls[]"), Err("Field 'urls' in select path is not an array".to_string()));
+        assert_eq!(select(&value, "urls.first"), Err("Cannot select field 'first' from a scalar value".to_string()));
+    }
+}
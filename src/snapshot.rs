@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tide::log;
+
+/// Whether upstream results are recorded to, or replayed from, snapshot files
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Off,
+    Record,
+    Replay,
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+// Replay wins if both are set, so a demo never reaches the network by accident
+static MODE: LazyLock<Mode> = LazyLock::new(|| {
+    if env_flag("REPLAY") {
+        Mode::Replay
+    } else if env_flag("RECORD") {
+        Mode::Record
+    } else {
+        Mode::Off
+    }
+});
+
+/// Directory snapshot files are written to and read from
+static SNAPSHOT_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    PathBuf::from(std::env::var("SNAPSHOT_DIR").unwrap_or_else(|_| "snapshots".to_string()))
+});
+
+/// Whether upstream results come from snapshot files instead of the network
+pub fn is_replaying() -> bool {
+    *MODE == Mode::Replay
+}
+
+// File for a snapshot key, with anything but letters and digits replaced so URLs make safe names
+fn path(dir: &Path, key: &str) -> PathBuf {
+    let name: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.json", name))
+}
+
+fn save<T: Serialize>(dir: &Path, key: &str, value: &T) {
+    let path = path(dir, key);
+    let result = std::fs::create_dir_all(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| serde_json::to_string_pretty(value).map_err(|e| e.to_string()))
+        .and_then(|json| std::fs::write(&path, json).map_err(|e| e.to_string()));
+    match result {
+        Ok(()) => log::info!("Recorded snapshot {}", path.display()),
+        Err(e) => log::error!("Failed to record snapshot {}: {}", path.display(), e),
+    }
+}
+
+fn load<T: DeserializeOwned>(dir: &Path, key: &str) -> Result<T, String> {
+    let path = path(dir, key);
+    let json = std::fs::read_to_string(&path)
+        .map_err(|e| format!("No snapshot to replay at {}: {}", path.display(), e))?;
+    serde_json::from_str(&json)
+        .map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))
+}
+
+/// Run an upstream download, recording its normalized result under `key` with
+/// `RECORD=true`, or skip the download and load the recorded result with `REPLAY=true`
+pub async fn fetch<T, F>(key: &str, download: F) -> Result<T, String>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = Result<T, String>>,
+{
+    fetch_with(*MODE, &SNAPSHOT_DIR, key, download).await
+}
+
+// `fetch` in the given mode, with snapshot files in `dir`
+async fn fetch_with<T, F>(mode: Mode, dir: &Path, key: &str, download: F) -> Result<T, String>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = Result<T, String>>,
+{
+    match mode {
+        Mode::Off => download.await,
+        Mode::Replay => load(dir, key),
+        Mode::Record => {
+            let result = download.await;
+            if let Ok(value) = &result {
+                save(dir, key, value);
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify::RecentlyPlayed;
+    use serde_json::json;
+
+    #[async_std::test]
+    async fn a_recorded_response_is_replayed_without_the_network() {
+        let dir = std::env::temp_dir().join(format!("api-agg-snapshots-{}", std::process::id()));
+        let key = "spotify_recently_played";
+        let recorded: RecentlyPlayed = serde_json::from_value(json!({
+            "tracks": [{ "track_name": "One", "artist": "A", "album_name": "X", "played_at": "2025-01-02T10:00:00Z",
+                         "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": ["pop"] }],
+            "excluded": [],
+        })).unwrap();
+
+        let result = fetch_with(Mode::Record, &dir, key, async { Ok(recorded.clone()) }).await.unwrap();
+        assert_eq!(result.tracks[0].track_name, "One");
+        assert!(path(&dir, key).exists());
+
+        // Replaying never runs the download, which would fail here as if offline
+        let replayed: RecentlyPlayed = fetch_with(Mode::Replay, &dir, key, async {
+            Err("network unavailable".to_string())
+        }).await.unwrap();
+        assert_eq!(json!(replayed), json!(recorded));
+
+        let missing = fetch_with::<RecentlyPlayed, _>(Mode::Replay, &dir, "spotify_never_recorded", async {
+            Err("network unavailable".to_string())
+        }).await;
+        assert!(missing.unwrap_err().starts_with("No snapshot to replay"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,61 +1,263 @@
 use serde::{Deserialize, Serialize};
 use tide::{log, Request, Response, StatusCode};
 use tide::prelude::*;
-use std::collections::HashMap;
-use std::time::{Instant, Duration, SystemTime};
-use std::sync::{LazyLock, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std::sync::{LazyLock, Mutex, RwLock};
 use crate::auth;
-use surf;
-use base64;
+use crate::upstream;
+use crate::health;
+use crate::snapshot;
+use crate::stats;
+use crate::format;
+use crate::cache::CacheState;
+use crate::define_global_cache;
+use crate::params::{self, QueryParams};
+use crate::images::ImageFallback;
+use crate::select;
+use crate::pause;
+use futures::TryStreamExt;
+use futures::future::join_all;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use base64::engine::general_purpose::STANDARD as BASE64;
 
-static CLIENT_ID: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("SPOTIFY_CLIENT_ID").expect("SPOTIFY_CLIENT_ID must be set.")
-});
+/// Spotify API credentials, all of which must be set for Spotify to be enabled
+struct Credentials {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+}
 
-static CLIENT_SECRET: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("SPOTIFY_CLIENT_SECRET").expect("SPOTIFY_CLIENT_SECRET must be set.")
+static CREDENTIALS: LazyLock<Option<Credentials>> = LazyLock::new(|| {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    match (var("SPOTIFY_CLIENT_ID"), var("SPOTIFY_CLIENT_SECRET"), var("SPOTIFY_REFRESH_TOKEN")) {
+        (Some(client_id), Some(client_secret), Some(refresh_token)) => {
+            Some(Credentials { client_id, client_secret, refresh_token })
+        },
+        _ => {
+            log::warn!("Spotify credentials are not fully set; the Spotify source is disabled");
+            None
+        }
+    }
 });
 
-static REFRESH_TOKEN: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("SPOTIFY_REFRESH_TOKEN").expect("SPOTIFY_REFRESH_TOKEN must be set.")
-});
+const NOT_CONFIGURED: &str = "Spotify is not configured";
 
-static EXCLUDED_GENRES: LazyLock<Vec<String>> = LazyLock::new(|| {
-    std::env::var("SPOTIFY_EXCLUDED_GENRES")
+// Genres filtered out of the tracks. Starts from SPOTIFY_EXCLUDED_GENRES and can be
+// changed at runtime through /admin/spotify/excluded-genres.
+static EXCLUDED_GENRES: LazyLock<RwLock<Vec<String>>> = LazyLock::new(|| {
+    let genres = std::env::var("SPOTIFY_EXCLUDED_GENRES")
         .unwrap_or_else(|_| "comedy".to_string())
         .split(',')
         .filter(|s| !s.is_empty())
         .map(|s| s.trim().to_lowercase())
+        .collect();
+    RwLock::new(genres)
+});
+
+// Genres a track must have at least one of to be kept, from SPOTIFY_INCLUDED_GENRES. Empty keeps every track.
+static INCLUDED_GENRES: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("SPOTIFY_INCLUDED_GENRES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim().to_lowercase())
         .collect()
 });
 
-const CACHE_DURATION_SECS: u64 = 900; // 15 minutes
-const NUMBER_OF_TRACKS_TO_SHOW: usize = 6;
+static GENRE_MATCH_MODE: LazyLock<GenreMatchMode> = LazyLock::new(|| {
+    match std::env::var("SPOTIFY_GENRE_MATCH_MODE").unwrap_or_default().trim().to_lowercase().as_str() {
+        "" | "exact" => GenreMatchMode::Exact,
+        "prefix" => GenreMatchMode::Prefix,
+        "contains" => GenreMatchMode::Contains,
+        other => {
+            log::warn!("Unknown SPOTIFY_GENRE_MATCH_MODE '{}', matching genres exactly", other);
+            GenreMatchMode::Exact
+        }
+    }
+});
 
-// Cache structure to store access token and timestamp
-#[derive(Debug, Clone)]
-struct TokenCacheEntry {
-    access_token: String,
-    timestamp: SystemTime,
+/// Named track field-sets from `SPOTIFY_FIELDSET_<name>=field,field`, selected with `fieldset=<name>`
+static FIELDSETS: LazyLock<HashMap<String, Vec<String>>> = LazyLock::new(|| fieldsets_from(std::env::vars()));
+
+// Field-sets defined by `SPOTIFY_FIELDSET_<name>` among the given environment variables
+fn fieldsets_from(vars: impl Iterator<Item = (String, String)>) -> HashMap<String, Vec<String>> {
+    vars
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix("SPOTIFY_FIELDSET_")?;
+            let fields = value.split(',')
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+                .collect();
+            Some((name.to_string(), fields))
+        })
+        .collect()
 }
 
-// Cache structure to store recently played tracks and timestamp
-#[derive(Debug, Clone)]
-struct TracksCacheEntry {
-    tracks: Vec<SpotifyTrack>,
-    timestamp: SystemTime,
+// Whether timestamps are cut to whole seconds (`TIMESTAMP_PRECISION=s`) rather than passed through (`ms`)
+static SECOND_PRECISION: LazyLock<bool> = LazyLock::new(|| {
+    match std::env::var("TIMESTAMP_PRECISION").as_deref().map(str::trim) {
+        Err(_) | Ok("ms") => false,
+        Ok("s") => true,
+        Ok(other) => {
+            log::warn!("Unknown TIMESTAMP_PRECISION '{}', keeping millisecond timestamps", other);
+            false
+        }
+    }
+});
+
+/// Format an ISO 8601 timestamp from Spotify, as UTC with a `Z` suffix when cut to
+/// `second_precision`. Timestamps that don't parse are passed through unchanged.
+fn format_timestamp(value: &str, second_precision: bool) -> String {
+    if !second_precision {
+        return value.to_string();
+    }
+    match DateTime::parse_from_rfc3339(value) {
+        Ok(timestamp) => timestamp.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true),
+        Err(_) => value.to_string(),
+    }
 }
 
-// Global cache for access token
-static TOKEN_CACHE: LazyLock<Mutex<Option<TokenCacheEntry>>> = LazyLock::new(|| {
-    Mutex::new(None)
+// Genre given to tracks whose artists have none, e.g. "unknown"; tracks keep an empty list if unset
+static DEFAULT_GENRE: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("SPOTIFY_DEFAULT_GENRE")
+        .ok()
+        .map(|genre| genre.trim().to_string())
+        .filter(|genre| !genre.is_empty())
+});
+
+static ARTIST_DISPLAY: LazyLock<ArtistDisplay> = LazyLock::new(|| {
+    match std::env::var("ARTIST_DISPLAY").unwrap_or_default().trim().to_lowercase().as_str() {
+        "album" => ArtistDisplay::Album,
+        _ => ArtistDisplay::Track,
+    }
+});
+
+// Market used when the client's Accept-Language doesn't name one, e.g. "IE"
+static DEFAULT_MARKET: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("SPOTIFY_MARKET")
+        .ok()
+        .map(|market| market.trim().to_uppercase())
+        .filter(|market| !market.is_empty())
 });
 
-// Global cache for recently played tracks
-static TRACKS_CACHE: LazyLock<Mutex<Option<TracksCacheEntry>>> = LazyLock::new(|| {
-    Mutex::new(None)
+pub const CACHE_DURATION_SECS: u64 = 900; // 15 minutes
+const NUMBER_OF_TRACKS_TO_SHOW: usize = 6;
+
+/// Largest track `limit` honoured, from `SPOTIFY_MAX_LIMIT`; larger values are clamped to it
+pub static MAX_LIMIT: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("SPOTIFY_MAX_LIMIT")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(50)
 });
 
+/// Query parameters accepted by `/spotify`
+const QUERY_PARAMS: &[&str] = &["limit", "no_cache", "max_age", "debug", "album", "sort", "genres_per_track", "image_fallback", "fieldset", "format", "before", "after"];
+
+/// Most tracks streamed by `/spotify/export.jsonl`
+const EXPORT_MAX_TRACKS: usize = 500;
+/// Items requested per page when exporting (the Spotify maximum)
+const EXPORT_PAGE_SIZE: usize = 50;
+
+const TOKEN_CACHE_KEY: &str = "access_token";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const TRACKS_CACHE_KEY: &str = "recently_played";
+
+// Global cache for access token
+// Entries are kept for up to the default lifetime, and used only while their own lifetime allows.
+// The entry holds that lifetime next to the token, since the cache's TTL is the same for every entry.
+define_global_cache!(TOKEN_CACHE, &'static str, TokenCacheEntry, DEFAULT_TOKEN_LIFETIME_SECS);
+
+/// Token lifetime assumed when Spotify doesn't report one
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+/// How long before its expiry a cached token is replaced, so it never expires mid-request
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// A cached access token and the lifetime Spotify gave it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenCacheEntry {
+    access_token: String,
+    expires_in: Duration,
+}
+
+impl TokenCacheEntry {
+    // Whether a token cached `age` ago can still be used, leaving the expiry margin to spare
+    fn is_fresh(&self, age: Duration) -> bool {
+        age + TOKEN_EXPIRY_MARGIN < self.expires_in
+    }
+}
+
+/// Most history pages cached at once, since `before`/`after` cursors come from the query string
+const MAX_CACHED_PAGES: usize = 100;
+
+// Global cache for recently played tracks, per position in the history. Keyed by the `before`/`after`
+// cursor rather than by limit: each entry holds the whole filtered page, which every limit is cut from,
+// along with the excluded tracks and the cursors the response passes on.
+define_global_cache!(TRACKS_CACHE, String, RecentlyPlayed, CACHE_DURATION_SECS, MAX_CACHED_PAGES);
+
+// Markets with cached playability, bounded since markets come from request headers
+const MAX_CACHED_MARKETS: usize = 50;
+
+// Whether each track can be played in a market, keyed by market and then by track ID
+define_global_cache!(PLAYABLE_CACHE, String, HashMap<String, bool>, CACHE_DURATION_SECS, MAX_CACHED_MARKETS);
+
+// Tracks cache keys being refreshed in the background, so a stale entry is refetched once
+static REFRESHING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// How long the currently playing track is cached, short since it changes with every song
+const NOW_PLAYING_CACHE_DURATION_SECS: u64 = 30;
+const NOW_PLAYING_CACHE_KEY: &str = "now_playing";
+
+// Global cache for the currently playing track, `None` while nothing plays
+define_global_cache!(NOW_PLAYING_CACHE, &'static str, Option<NowPlaying>, NOW_PLAYING_CACHE_DURATION_SECS);
+
+/// How long top tracks and artists are cached, long since they change slowly
+const TOP_CACHE_DURATION_SECS: u64 = 3600;
+
+// Global cache for top items, keyed by type and time range
+define_global_cache!(TOP_CACHE, String, TopItems, TOP_CACHE_DURATION_SECS);
+
+/// Query parameters accepted by `/spotify/top`
+const TOP_PARAMS: &[&str] = &["type", "time_range", "limit", "no_cache"];
+
+/// Time ranges Spotify computes top items over: about 4 weeks, 6 months and all time
+const TIME_RANGES: &[&str] = &["short_term", "medium_term", "long_term"];
+
+/// Top items fetched per type and time range (the Spotify maximum); `limit` is applied to these
+const TOP_FETCH_LIMIT: usize = 50;
+
+/// How a track genre is compared with a configured genre, e.g. whether "pop" matches "k-pop"
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GenreMatchMode {
+    /// The genres are equal
+    Exact,
+    /// The track genre starts with the configured one, so "pop" matches "pop rock" but not "k-pop"
+    Prefix,
+    /// Either genre contains the other, so "pop" matches "k-pop" and "synthpop"
+    Contains,
+}
+
+impl GenreMatchMode {
+    /// Whether a lowercase track genre matches a lowercase configured genre
+    fn matches(self, genre: &str, configured: &str) -> bool {
+        match self {
+            GenreMatchMode::Exact => genre == configured,
+            GenreMatchMode::Prefix => genre.starts_with(configured),
+            GenreMatchMode::Contains => genre.contains(configured) || configured.contains(genre),
+        }
+    }
+}
+
+/// Which artist to show for a track: the track's first listed artist or the album artist
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArtistDisplay {
+    Track,
+    Album,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpotifyTrack {
     pub track_name: String,
@@ -65,6 +267,137 @@ pub struct SpotifyTrack {
     pub spotify_url: String,
     pub album_image_url: Option<String>,
     pub genres: Vec<String>,
+    /// 30-second audio preview, when Spotify provides one
+    #[serde(default)]
+    pub preview_url: Option<String>,
+    /// Spotify's popularity score from 0 to 100
+    #[serde(default)]
+    pub popularity: Option<u32>,
+    /// Length of the track in milliseconds
+    #[serde(default)]
+    pub duration_ms: u32,
+}
+
+/// A track dropped by the genre filter, reported on `/spotify?debug=true`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExcludedTrack {
+    pub track_name: String,
+    pub artist: String,
+    pub matched_genre: String,
+}
+
+/// Recently played tracks after genre filtering, plus the tracks that were filtered out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentlyPlayed {
+    pub tracks: Vec<SpotifyTrack>,
+    pub excluded: Vec<ExcludedTrack>,
+    /// Spotify's cursors for the neighbouring pages of the history
+    #[serde(default)]
+    pub cursors: Option<Cursors>,
+}
+
+impl RecentlyPlayed {
+    /// Tracks playable in the given market, as reported by Spotify's Tracks API for that
+    /// market. If playability can't be fetched, all tracks are kept.
+    pub async fn playable_in(self, market: Option<&str>) -> Vec<SpotifyTrack> {
+        let Some(market) = market else {
+            return self.tracks;
+        };
+        let playable = match fetch_playability(&self.tracks, market).await {
+            Ok(playable) => playable,
+            Err(e) => {
+                log::warn!("Keeping all tracks, failed to check playability in market {}: {}", market, e);
+                return self.tracks;
+            }
+        };
+        self.tracks.into_iter()
+            .filter(|track| playable.get(track_id(&track.spotify_url)) != Some(&false))
+            .collect()
+    }
+}
+
+// The `limit` query parameter, or the default, clamped to `MAX_LIMIT`
+fn track_limit(params: &QueryParams) -> usize {
+    params.parse::<usize>("limit").unwrap_or(NUMBER_OF_TRACKS_TO_SHOW).min(*MAX_LIMIT)
+}
+
+// Spotify track ID from an open.spotify.com track URL
+fn track_id(spotify_url: &str) -> &str {
+    spotify_url.rsplit('/').next().unwrap_or_default()
+}
+
+// Whether each of the tracks is playable in a market, by track ID, fetching only those not cached yet
+async fn fetch_playability(tracks: &[SpotifyTrack], market: &str) -> Result<HashMap<String, bool>, String> {
+    let cache_key = market.to_string();
+    let mut playable = PLAYABLE_CACHE.get(&cache_key).await.unwrap_or_default();
+    let missing: Vec<String> = tracks.iter()
+        .map(|track| track_id(&track.spotify_url).to_string())
+        .filter(|id| !id.is_empty() && !playable.contains_key(id))
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if missing.is_empty() {
+        return Ok(playable);
+    }
+
+    let access_token = get_access_token().await?;
+    let chunks: Vec<&[String]> = missing.chunks(50).collect();
+    for result in join_all(chunks.iter().map(|chunk| get_playability_chunk(chunk, market, &access_token))).await {
+        playable.extend(result?);
+    }
+    PLAYABLE_CACHE.insert(cache_key, playable.clone()).await;
+    Ok(playable)
+}
+
+// Playability of up to 50 tracks in a market, from the Tracks API with `market` set.
+// Relinked tracks are reported under the ID that was asked for.
+async fn get_playability_chunk(chunk: &[String], market: &str, access_token: &str) -> Result<HashMap<String, bool>, String> {
+    let url = format!("https://api.spotify.com/v1/tracks?ids={}&market={}", chunk.join(","), market);
+    let mut response = upstream::send_with_retry(|| {
+        upstream::get(&url).header("Authorization", format!("Bearer {}", access_token))
+    })
+        .await
+        .map_err(|e| format!("Failed to make request to Spotify Tracks API: {}", e))?;
+
+    if !response.status().is_success() {
+        let error_text = response.body_string()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("Failed to get track playability: {} - {}", response.status(), error_text));
+    }
+
+    let tracks_response: TracksResponse = response.body_json()
+        .await
+        .map_err(|e| format!("Failed to parse tracks response: {}", e))?;
+    Ok(tracks_response.tracks.into_iter()
+        .flatten()
+        .map(|track| {
+            let id = track.linked_from.map_or(track.id, |linked| linked.id);
+            (id, track.is_playable.unwrap_or(true))
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct TracksResponse {
+    // Spotify returns null for tracks it can't find
+    tracks: Vec<Option<MarketTrack>>,
+}
+
+// A track from the Tracks API requested for a market
+#[derive(Debug, Deserialize)]
+struct MarketTrack {
+    id: String,
+    #[serde(default)]
+    is_playable: Option<bool>,
+    /// The originally requested track, when Spotify substituted one playable in the market
+    #[serde(default)]
+    linked_from: Option<LinkedTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinkedTrack {
+    id: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,15 +405,79 @@ struct TokenResponse {
     access_token: String,
     #[allow(dead_code)]
     token_type: String,
-    #[allow(dead_code)]
-    expires_in: u32,
+    #[serde(default)]
+    expires_in: Option<u64>,
     #[allow(dead_code)]
     scope: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct RecentlyPlayedResponse {
-    items: Vec<PlayHistoryObject>,
+    // Parsed one by one so that a single malformed item doesn't fail the whole response
+    items: Vec<serde_json::Value>,
+    #[serde(default)]
+    cursors: Option<Cursors>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cursors {
+    /// Cursor for the page of items played before this one
+    pub before: Option<String>,
+    /// Cursor for the items played after this page
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+/// Position in the recently played history to fetch from, as a Unix timestamp in milliseconds.
+/// Spotify accepts at most one of the two.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageCursor {
+    /// Plays before this time
+    Before(String),
+    /// Plays after this time
+    After(String),
+}
+
+impl PageCursor {
+    /// The cursor given by the `before` or `after` parameter, or an error if both are given
+    fn from_params(params: &QueryParams) -> Result<Option<Self>, String> {
+        match (params.parse::<u64>("before"), params.parse::<u64>("after")) {
+            (Some(_), Some(_)) => Err("Only one of 'before' and 'after' may be given".to_string()),
+            (Some(before), None) => Ok(Some(PageCursor::Before(before.to_string()))),
+            (None, Some(after)) => Ok(Some(PageCursor::After(after.to_string()))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn query(&self) -> String {
+        match self {
+            PageCursor::Before(before) => format!("before={}", before),
+            PageCursor::After(after) => format!("after={}", after),
+        }
+    }
+}
+
+// Cache key for the tracks from a position in the history, or the latest tracks
+fn tracks_cache_key(cursor: Option<&PageCursor>) -> String {
+    match cursor {
+        None => TRACKS_CACHE_KEY.to_string(),
+        Some(cursor) => format!("{}:{}", TRACKS_CACHE_KEY, cursor.query()),
+    }
+}
+
+impl RecentlyPlayedResponse {
+    /// Items that could be parsed, skipping and logging any that can't
+    fn play_history(self) -> Vec<PlayHistoryObject> {
+        self.items.into_iter()
+            .filter_map(|item| match serde_json::from_value::<PlayHistoryObject>(item) {
+                Ok(item) => Some(item),
+                Err(e) => {
+                    log::warn!("Skipping recently played item that could not be parsed: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -89,24 +486,125 @@ struct PlayHistoryObject {
     played_at: String,
 }
 
+/// The track currently playing on the account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NowPlaying {
+    pub track_name: String,
+    pub artist: String,
+    pub album_name: String,
+    pub spotify_url: String,
+    pub album_image_url: Option<String>,
+    /// Position in the track in milliseconds
+    pub progress_ms: Option<u64>,
+    /// Whether playback is running rather than paused
+    pub is_playing: bool,
+}
+
+/// An artist in the account's top artists
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotifyArtist {
+    pub name: String,
+    pub genres: Vec<String>,
+    pub image_url: Option<String>,
+    pub spotify_url: String,
+}
+
+/// The account's top tracks or top artists over a time range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TopItems {
+    Tracks(Vec<SpotifyTrack>),
+    Artists(Vec<SpotifyArtist>),
+}
+
+impl TopItems {
+    fn len(&self) -> usize {
+        match self {
+            TopItems::Tracks(tracks) => tracks.len(),
+            TopItems::Artists(artists) => artists.len(),
+        }
+    }
+
+    fn truncate(&mut self, limit: usize) {
+        match self {
+            TopItems::Tracks(tracks) => tracks.truncate(limit),
+            TopItems::Artists(artists) => artists.truncate(limit),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TopResponse<T> {
+    items: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CurrentlyPlayingResponse {
+    #[serde(default)]
+    is_playing: bool,
+    #[serde(default)]
+    progress_ms: Option<u64>,
+    /// `track`, `episode`, `ad` or `unknown`; only tracks are reported
+    #[serde(default)]
+    currently_playing_type: Option<String>,
+    #[serde(default)]
+    item: Option<TrackObject>,
+}
+
+// Only the track name (and the item's play time) are essential; other fields default when missing
 #[derive(Debug, Deserialize)]
 struct TrackObject {
     name: String,
+    #[serde(default)]
     album: AlbumObject,
+    #[serde(default)]
     artists: Vec<ArtistObject>,
-    external_urls: ExternalUrls,
+    #[serde(default)]
+    external_urls: Option<ExternalUrls>,
+    #[serde(default)]
     id: String,
+    #[serde(default)]
+    preview_url: Option<String>,
+    #[serde(default)]
+    popularity: Option<u32>,
+    #[serde(default)]
+    duration_ms: u32,
 }
 
-#[derive(Debug, Deserialize)]
+impl TrackObject {
+    /// Link to the track on Spotify, built from its ID if the response has no external URL
+    fn spotify_url(&self) -> String {
+        match &self.external_urls {
+            Some(urls) => urls.spotify.clone(),
+            None if !self.id.is_empty() => format!("https://open.spotify.com/track/{}", self.id),
+            None => String::new(),
+        }
+    }
+
+    /// Name of the artist to display, falling back to the track artist when the album has none
+    fn display_artist(&self, preference: ArtistDisplay) -> String {
+        let artists = match preference {
+            ArtistDisplay::Album if !self.album.artists.is_empty() => &self.album.artists,
+            _ => &self.artists,
+        };
+        artists.first().map(|artist| artist.name.clone()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct AlbumObject {
+    #[serde(default)]
     name: String,
+    #[serde(default)]
     images: Vec<ImageObject>,
+    #[serde(default)]
+    artists: Vec<ArtistObject>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ArtistObject {
+    #[serde(default)]
     name: String,
+    #[serde(default)]
     id: String,
 }
 
@@ -114,9 +612,9 @@ struct ArtistObject {
 struct ImageObject {
     url: String,
     #[allow(dead_code)]
-    height: u32,
+    height: Option<u32>,
     #[allow(dead_code)]
-    width: u32,
+    width: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -128,46 +626,70 @@ struct ExternalUrls {
 struct FullArtistObject {
     id: String,
     name: String,
+    #[serde(default)]
     genres: Vec<String>,
+    #[serde(default)]
+    images: Vec<ImageObject>,
+    #[serde(default)]
+    external_urls: Option<ExternalUrls>,
+}
+
+impl From<FullArtistObject> for SpotifyArtist {
+    fn from(artist: FullArtistObject) -> Self {
+        SpotifyArtist {
+            spotify_url: match artist.external_urls {
+                Some(urls) => urls.spotify,
+                None => format!("https://open.spotify.com/artist/{}", artist.id),
+            },
+            name: artist.name,
+            genres: artist.genres,
+            image_url: artist.images.into_iter().next().map(|image| image.url),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ArtistsResponse {
-    artists: Vec<FullArtistObject>,
+    // Spotify returns null for artists it can't find
+    artists: Vec<Option<FullArtistObject>>,
 }
 
 async fn get_artists_with_genres(artist_ids: Vec<String>, access_token: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    fetch_artist_genres("https://api.spotify.com/v1/artists", artist_ids, access_token).await
+}
+
+// Look up the artists' genres at the given Artists endpoint
+async fn fetch_artist_genres(artists_url: &str, artist_ids: Vec<String>, access_token: &str) -> Result<HashMap<String, Vec<String>>, String> {
     if artist_ids.is_empty() {
         return Ok(HashMap::new());
     }
     
     let start_time = Instant::now();
     
-    // Spotify API allows up to 50 artists per request
-    let mut all_genres = HashMap::new();
+    // Spotify API allows up to 50 artists per request, and the requests are sent concurrently
+    let chunks: Vec<&[String]> = artist_ids.chunks(50).collect();
+    let results = join_all(chunks.iter().map(|chunk| get_artist_genres_chunk(artists_url, chunk, access_token))).await;
     
-    for chunk in artist_ids.chunks(50) {
-        let ids = chunk.join(",");
-        let mut response = surf::get(format!("https://api.spotify.com/v1/artists?ids={}", ids))
-            .header("Authorization", format!("Bearer {}", access_token))
-            .await
-            .map_err(|e| format!("Failed to make request to Spotify Artists API: {}", e))?;
-        
-        if response.status().is_success() {
-            let artists_response: ArtistsResponse = response.body_json()
-                .await
-                .map_err(|e| format!("Failed to parse artists response: {}", e))?;
-            
-            for artist in artists_response.artists {
-                all_genres.insert(artist.id, artist.genres);
+    // A failed chunk leaves its artists without genres; only fail if every chunk did
+    let mut all_genres = HashMap::new();
+    let mut first_error = None;
+    let mut any_succeeded = false;
+    for result in results {
+        match result {
+            Ok(genres) => {
+                all_genres.extend(genres);
+                any_succeeded = true;
+            },
+            Err(e) => {
+                log::error!("{}", e);
+                first_error.get_or_insert(e);
             }
-        } else {
-            let error_text = response.body_string()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            log::error!("Failed to get artist genres: {} - {}", response.status(), error_text);
         }
     }
+    if !any_succeeded
+        && let Some(e) = first_error {
+        return Err(e);
+    }
     
     let total_time = start_time.elapsed();
     log::info!("Fetched genres for {} artists in {:?}", artist_ids.len(), total_time);
@@ -175,40 +697,71 @@ async fn get_artists_with_genres(artist_ids: Vec<String>, access_token: &str) ->
     Ok(all_genres)
 }
 
-async fn get_access_token() -> Result<String, String> {
-    let start_time = Instant::now();
+// Fetch the genres of up to 50 artists in one request
+async fn get_artist_genres_chunk(artists_url: &str, chunk: &[String], access_token: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let ids = chunk.join(",");
+    let url = format!("{}?ids={}", artists_url, ids);
+    let mut response = upstream::send_with_retry(|| {
+        upstream::get(&url).header("Authorization", format!("Bearer {}", access_token))
+    })
+        .await
+        .map_err(|e| format!("Failed to make request to Spotify Artists API: {}", e))?;
+    
+    if !response.status().is_success() {
+        let error_text = response.body_string()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("Failed to get artist genres: {} - {}", response.status(), error_text));
+    }
     
+    let artists_response: ArtistsResponse = response.body_json()
+        .await
+        .map_err(|e| format!("Failed to parse artists response: {}", e))?;
+    Ok(artists_response.artists.into_iter()
+        .flatten()
+        .map(|artist| (artist.id, artist.genres))
+        .collect())
+}
+
+async fn get_access_token() -> Result<String, String> {
     // Check cache first
-    {
-        let cache_lock = TOKEN_CACHE.lock().unwrap();
-        if let Some(cache_entry) = &*cache_lock {
-            if let Ok(elapsed) = cache_entry.timestamp.elapsed() {
-                if elapsed < Duration::from_secs(CACHE_DURATION_SECS) {
-                    log::info!("Access token cache hit");
-                    return Ok(cache_entry.access_token.clone());
-                } else {
-                    log::info!("Access token cache expired");
-                }
-            }
-        } else {
-            log::info!("Access token cache miss");
-        }
+    if let Some(entry) = TOKEN_CACHE.get(&TOKEN_CACHE_KEY).await
+        && TOKEN_CACHE.age(&TOKEN_CACHE_KEY).await.is_some_and(|age| entry.is_fresh(age)) {
+        return Ok(entry.access_token);
     }
     
+    refresh_access_token().await.map(|token_response| token_response.access_token)
+}
+
+// Exchange the refresh token for a new access token and cache it, regardless of the cached one
+async fn refresh_access_token() -> Result<TokenResponse, String> {
+    let credentials = CREDENTIALS.as_ref().ok_or(NOT_CONFIGURED)?;
+    request_access_token(TOKEN_URL, credentials).await
+}
+
+// Exchange the refresh token at the given token endpoint and cache the new access token
+async fn request_access_token(token_url: &str, credentials: &Credentials) -> Result<TokenResponse, String> {
+    let start_time = Instant::now();
+    
     // Create basic auth header
-    let basic = base64::encode(format!("{}:{}", *CLIENT_ID, *CLIENT_SECRET));
+    let basic = BASE64.encode(format!("{}:{}", credentials.client_id, credentials.client_secret));
     
-    // Prepare request body
-    let mut body = surf::Body::from_form(&[
+    // Prepare request body, encoded once so it can be resent on retry
+    let body = surf::Body::from_form(&[
         ("grant_type", "refresh_token"),
-        ("refresh_token", REFRESH_TOKEN.as_str()),
-    ]).map_err(|e| format!("Failed to create request body: {}", e))?;
+        ("refresh_token", credentials.refresh_token.as_str()),
+    ]).map_err(|e| format!("Failed to create request body: {}", e))?
+        .into_string()
+        .await
+        .map_err(|e| format!("Failed to create request body: {}", e))?;
     
     // Make request to Spotify API
-    let mut response = surf::post("https://accounts.spotify.com/api/token")
-        .header("Authorization", format!("Basic {}", basic))
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
+    let mut response = upstream::send_with_retry(|| {
+        upstream::post(token_url)
+            .header("Authorization", format!("Basic {}", basic))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body.clone())
+    })
         .await
         .map_err(|e| format!("Failed to make request to Spotify API: {}", e))?;
     
@@ -218,22 +771,17 @@ async fn get_access_token() -> Result<String, String> {
             .await
             .map_err(|e| format!("Failed to parse response: {}", e))?;
         
-        let access_token = token_response.access_token;
-        
         // Update cache
-        {
-            let mut cache_lock = TOKEN_CACHE.lock().unwrap();
-            *cache_lock = Some(TokenCacheEntry {
-                access_token: access_token.clone(),
-                timestamp: SystemTime::now(),
-            });
-            log::info!("Access token cache updated");
-        }
+        let expires_in = Duration::from_secs(token_response.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS));
+        TOKEN_CACHE.insert(TOKEN_CACHE_KEY, TokenCacheEntry {
+            access_token: token_response.access_token.clone(),
+            expires_in,
+        }).await;
         
         let total_time = start_time.elapsed();
-        log::info!("Total get_access_token took: {:?}", total_time);
+        log::info!("Total refresh_access_token took: {:?}", total_time);
         
-        Ok(access_token)
+        Ok(token_response)
     } else {
         let error_text = response.body_string()
             .await
@@ -242,28 +790,232 @@ async fn get_access_token() -> Result<String, String> {
     }
 }
 
-pub async fn get_recently_played(limit: usize) -> Result<Vec<SpotifyTrack>, String> {
+/// The genre rules applied to recently played tracks, with lowercase genre lists
+#[derive(Debug, Clone)]
+struct GenreFilter {
+    excluded: Vec<String>,
+    included: Vec<String>,
+    mode: GenreMatchMode,
+    /// Genre given to tracks whose artists have none
+    default_genre: Option<String>,
+}
+
+impl GenreFilter {
+    /// The configured rules, with the excluded genres as currently set
+    fn current() -> Self {
+        GenreFilter {
+            excluded: EXCLUDED_GENRES.read().unwrap().clone(),
+            included: INCLUDED_GENRES.clone(),
+            mode: *GENRE_MATCH_MODE,
+            default_genre: DEFAULT_GENRE.clone(),
+        }
+    }
+
+    /// Return the first of a track's genres that matches the excluded list, if any
+    fn matched_excluded(&self, track_genres: &[String]) -> Option<String> {
+        track_genres.iter().find(|genre| {
+            let genre_lower = genre.to_lowercase();
+            self.excluded.iter().any(|excluded| self.mode.matches(&genre_lower, excluded))
+        }).cloned()
+    }
+
+    /// Whether a track's genres satisfy the included list, which every track does when it's empty
+    fn has_included(&self, track_genres: &[String]) -> bool {
+        self.included.is_empty() || track_genres.iter().any(|genre| {
+            let genre_lower = genre.to_lowercase();
+            self.included.iter().any(|included| self.mode.matches(&genre_lower, included))
+        })
+    }
+}
+
+pub async fn get_recently_played(limit: usize, market: Option<&str>) -> Result<Vec<SpotifyTrack>, String> {
+    let recently_played = fetch_recently_played().await?;
+    Ok(recently_played.playable_in(market).await.into_iter().take(limit.min(*MAX_LIMIT)).collect())
+}
+
+// Whether a track's album name contains any of the given lowercase album names
+fn from_any_album(track: &SpotifyTrack, albums: &[String]) -> bool {
+    let album_name = track.album_name.to_lowercase();
+    albums.iter().any(|album| album_name.contains(album.as_str()))
+}
+
+// Keep at most `cap` of a track's genres. They are sorted and deduplicated, so the kept ones are deterministic.
+fn cap_genres(track: &mut SpotifyTrack, cap: Option<usize>) {
+    if let Some(cap) = cap {
+        track.genres.truncate(cap);
+    }
+}
+
+// Most popular first; the sort is stable so equally popular tracks stay in play order
+fn sort_most_popular_first(tracks: &mut [SpotifyTrack]) {
+    tracks.sort_by_key(|track| std::cmp::Reverse(track.popularity));
+}
+
+// Market from the most preferred language in an Accept-Language header, if it
+// names a region (e.g. "en-IE" -> "IE"). Bare languages like "en" are ambiguous.
+fn market_from_accept_language(header: &str) -> Option<String> {
+    let preferred = header.split(',')
+        .map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (tag, quality)
+        })
+        .filter(|(tag, quality)| !tag.is_empty() && *tag != "*" && *quality > 0.0)
+        // Highest quality wins; ties go to the earlier entry
+        .fold(None, |best: Option<(&str, f32)>, (tag, quality)| match best {
+            Some((_, best_quality)) if best_quality >= quality => best,
+            _ => Some((tag, quality)),
+        })?
+        .0;
+
+    let region = preferred.split(['-', '_']).nth(1)?;
+    if region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(region.to_uppercase())
+    } else {
+        None
+    }
+}
+
+/// Spotify market for a request: from its Accept-Language header when that maps
+/// confidently to a country, otherwise the configured `SPOTIFY_MARKET`
+pub fn resolve_market(req: &Request<()>) -> Option<String> {
+    req.header("Accept-Language")
+        .and_then(|value| market_from_accept_language(value.as_str()))
+        .or_else(|| DEFAULT_MARKET.clone())
+}
+
+/// Whether all Spotify credentials are set, or tracks are replayed from a snapshot.
+/// Otherwise the Spotify source is disabled.
+pub fn is_configured() -> bool {
+    CREDENTIALS.is_some() || snapshot::is_replaying()
+}
+
+// 503 response for the Spotify endpoints when Spotify is not configured
+fn not_configured_response() -> Response {
+    let mut res = Response::new(StatusCode::ServiceUnavailable);
+    res.set_content_type("application/json");
+    res.set_body(json!({ "error": "Spotify is not configured." }));
+    res
+}
+
+/// Recently played tracks if they are already cached, without fetching
+pub async fn cached_recently_played() -> Option<RecentlyPlayed> {
+    TRACKS_CACHE.get(&TRACKS_CACHE_KEY.to_string()).await
+}
+
+/// Drop the cached tracks and access token so the next request refetches both
+pub async fn invalidate_caches() {
+    TRACKS_CACHE.clear().await;
+    TOKEN_CACHE.clear().await;
+}
+
+/// Purge expired entries from the Spotify caches, returning how many were removed
+pub async fn sweep_caches() -> usize {
+    TRACKS_CACHE.sweep().await + TOP_CACHE.sweep().await + NOW_PLAYING_CACHE.sweep().await
+        + TOKEN_CACHE.sweep().await + PLAYABLE_CACHE.sweep().await
+}
+
+/// Replace the excluded genres and drop the cached tracks, so the next fetch applies
+/// the new list. Returns the list as stored, trimmed and lowercased.
+pub async fn set_excluded_genres(genres: &[String]) -> Vec<String> {
+    let genres: Vec<String> = genres.iter()
+        .map(|genre| genre.trim().to_lowercase())
+        .filter(|genre| !genre.is_empty())
+        .collect();
+    *EXCLUDED_GENRES.write().unwrap() = genres.clone();
+    TRACKS_CACHE.clear().await;
+    TOP_CACHE.clear().await;
+    genres
+}
+
+/// Whether the recently played tracks are being served from an expired cache entry
+pub async fn served_stale() -> bool {
+    TRACKS_CACHE.is_expired(&TRACKS_CACHE_KEY.to_string()).await
+}
+
+/// How long ago the cached recently played tracks were fetched
+pub async fn cached_age() -> Option<Duration> {
+    TRACKS_CACHE.age(&TRACKS_CACHE_KEY.to_string()).await
+}
+
+/// Fetch the full filtered list of recently played tracks, using the cache when possible
+pub async fn fetch_recently_played() -> Result<RecentlyPlayed, String> {
+    fetch_recently_played_from(None, None).await
+}
+
+/// Fetch the filtered recently played tracks before or after a cursor, or the latest
+/// without one, using the cache for that position when possible. With `max_age`, cached
+/// tracks fetched less than that long ago are used even if they are past the TTL.
+pub async fn fetch_recently_played_from(cursor: Option<&PageCursor>, max_age: Option<Duration>) -> Result<RecentlyPlayed, String> {
     let start_time = Instant::now();
+    let cache_key = tracks_cache_key(cursor);
     
     // Check cache first
-    {
-        let cache_lock = TRACKS_CACHE.lock().unwrap();
-        if let Some(cache_entry) = &*cache_lock {
-            if let Ok(elapsed) = cache_entry.timestamp.elapsed() {
-                if elapsed < Duration::from_secs(CACHE_DURATION_SECS) {
-                    log::info!("Recently played tracks cache hit");
-                    // Return limited results from cache
-                    let limited_tracks = cache_entry.tracks.iter().take(limit).cloned().collect();
-                    return Ok(limited_tracks);
-                } else {
-                    log::info!("Recently played tracks cache expired");
-                }
-            }
-        } else {
-            log::info!("Recently played tracks cache miss");
+    if let Some(max_age) = max_age {
+        if let Some(recently_played) = TRACKS_CACHE.get_within(&cache_key, max_age).await {
+            return Ok(recently_played);
         }
+    } else {
+        match TRACKS_CACHE.get_with_state(&cache_key).await {
+            CacheState::Fresh(recently_played) => return Ok(recently_played),
+            CacheState::Stale(recently_played) => {
+                spawn_refresh(cursor.cloned(), cache_key);
+                return Ok(recently_played);
+            },
+            CacheState::Missing => {},
+        }
+    }
+    
+    if !is_configured() {
+        return Err(NOT_CONFIGURED.to_string());
     }
     
+    let recently_played = match refresh_recently_played(cursor, &cache_key).await {
+        Ok(recently_played) => recently_played,
+        // Slightly stale data is better than none, including data older than max_age
+        Err(e) => return match TRACKS_CACHE.get(&cache_key).await {
+            Some(recently_played) => Ok(recently_played),
+            None => TRACKS_CACHE.get_expired(&cache_key).await.ok_or(e),
+        },
+    };
+    
+    let total_time = start_time.elapsed();
+    log::info!("Total fetch_recently_played took: {:?}, found {} tracks", total_time, recently_played.tracks.len());
+    
+    Ok(recently_played)
+}
+
+// Fetch the tracks for a position from upstream and store them in the cache
+async fn refresh_recently_played(cursor: Option<&PageCursor>, cache_key: &str) -> Result<RecentlyPlayed, String> {
+    let result = snapshot::fetch(&format!("spotify_{}", cache_key), download_recently_played(cursor)).await;
+    health::record("spotify", result.is_ok());
+    let recently_played = result?;
+    
+    // Update cache with all filtered tracks
+    TRACKS_CACHE.insert(cache_key.to_string(), recently_played.clone()).await;
+    Ok(recently_played)
+}
+
+// Refresh a stale tracks cache entry in the background, unless a refresh is already running
+fn spawn_refresh(cursor: Option<PageCursor>, cache_key: String) {
+    if !REFRESHING.lock().unwrap().insert(cache_key.clone()) {
+        return;
+    }
+
+    async_std::task::spawn(async move {
+        if let Err(e) = refresh_recently_played(cursor.as_ref(), &cache_key).await {
+            log::error!("Background refresh of {} failed: {}", cache_key, e);
+        }
+        REFRESHING.lock().unwrap().remove(&cache_key);
+    });
+}
+
+// Fetch recently played tracks from Spotify and filter them, bypassing the tracks cache
+async fn download_recently_played(cursor: Option<&PageCursor>) -> Result<RecentlyPlayed, String> {
     // Get access token
     let access_token = get_access_token().await?;
     
@@ -271,94 +1023,229 @@ pub async fn get_recently_played(limit: usize) -> Result<Vec<SpotifyTrack>, Stri
     // Spotify API max is 50, so we'll use that to maximize our chances of getting enough tracks after filtering
     let fetch_limit = 25;
     
+    let page = fetch_recently_played_page(&access_token, fetch_limit, cursor).await?;
+    let cursors = page.cursors.clone();
+    let mut recently_played = process_play_history(page.play_history(), &access_token).await?;
+    recently_played.cursors = cursors;
+    Ok(recently_played)
+}
+
+// Fetch one page of recently played items, optionally only those played before or after a cursor
+async fn fetch_recently_played_page(access_token: &str, limit: usize, cursor: Option<&PageCursor>) -> Result<RecentlyPlayedResponse, String> {
     // Make request to Spotify API
-    let mut response = surf::get(format!("https://api.spotify.com/v1/me/player/recently-played?limit={}", fetch_limit))
-        .header("Authorization", format!("Bearer {}", access_token))
+    let mut url = format!("https://api.spotify.com/v1/me/player/recently-played?limit={}", limit);
+    if let Some(cursor) = cursor {
+        url.push_str(&format!("&{}", cursor.query()));
+    }
+    let mut response = upstream::send_with_retry(|| {
+        upstream::get(&url).header("Authorization", format!("Bearer {}", access_token))
+    })
         .await
         .map_err(|e| format!("Failed to make request to Spotify API: {}", e))?;
     
     // Handle response
     if response.status().is_success() {
-        let recently_played: RecentlyPlayedResponse = response.body_json()
+        response.body_json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))
+    } else {
+        let error_text = response.body_string()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        Err(format!("Failed to get recently played tracks: {} - {}", response.status(), error_text))
+    }
+}
+
+/// Fetch the account's top tracks or artists (`kind` is `tracks` or `artists`) over a
+/// time range, using the cache. Needs the `user-top-read` scope.
+pub async fn fetch_top_items(kind: &str, time_range: &str) -> Result<TopItems, String> {
+    let cache_key = format!("{}:{}", kind, time_range);
+    if let Some(items) = TOP_CACHE.get(&cache_key).await {
+        return Ok(items);
+    }
+    
+    if !is_configured() {
+        return Err(NOT_CONFIGURED.to_string());
+    }
+    
+    let result = snapshot::fetch(&format!("spotify_top_{}_{}", kind, time_range), download_top_items(kind, time_range)).await;
+    health::record("spotify", result.is_ok());
+    let items = result?;
+    
+    TOP_CACHE.insert(cache_key, items.clone()).await;
+    Ok(items)
+}
+
+// Fetch top items from Spotify, bypassing the cache. Tracks get genres and genre filtering as recently played ones.
+async fn download_top_items(kind: &str, time_range: &str) -> Result<TopItems, String> {
+    let access_token = get_access_token().await?;
+    
+    let url = format!("https://api.spotify.com/v1/me/top/{}?time_range={}&limit={}", kind, time_range, TOP_FETCH_LIMIT);
+    let mut response = upstream::send_with_retry(|| {
+        upstream::get(&url).header("Authorization", format!("Bearer {}", access_token))
+    })
+        .await
+        .map_err(|e| format!("Failed to make request to Spotify API: {}", e))?;
+    
+    if !response.status().is_success() {
+        let error_text = response.body_string()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("Failed to get top {}: {} - {}", kind, response.status(), error_text));
+    }
+    
+    if kind == "artists" {
+        let top: TopResponse<FullArtistObject> = response.body_json()
             .await
             .map_err(|e| format!("Failed to parse response: {}", e))?;
-        
-        // Get unique artist IDs
-        let artist_ids: Vec<String> = recently_played.items.iter()
-            .flat_map(|item| item.track.artists.iter().map(|artist| artist.id.clone()))
-            .collect::<std::collections::HashSet<_>>()
-            .into_iter()
+        Ok(TopItems::Artists(top.items.into_iter().map(SpotifyArtist::from).collect()))
+    } else {
+        let top: TopResponse<TrackObject> = response.body_json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        // Top tracks have no play time, so they are processed as history items with an empty one
+        let items = top.items.into_iter()
+            .map(|track| PlayHistoryObject { track, played_at: String::new() })
             .collect();
-        
-        // Fetch artist genres
-        let artist_genres = get_artists_with_genres(artist_ids, &access_token).await?;
-        
-        // Transform response to simplified format with genres
-        let mut tracks: Vec<SpotifyTrack> = Vec::new();
-        
-        for item in recently_played.items.iter() {
-            // Get all genres from all artists on the track
-            let mut track_genres: Vec<String> = Vec::new();
-            for artist in &item.track.artists {
-                if let Some(genres) = artist_genres.get(&artist.id) {
-                    track_genres.extend(genres.clone());
-                }
-            }
-            
-            // Remove duplicates
-            track_genres.sort();
-            track_genres.dedup();
-            
-            // Check if any of the track's genres are in the excluded list
-            let should_exclude = if !EXCLUDED_GENRES.is_empty() {
-                track_genres.iter().any(|genre| {
-                    let genre_lower = genre.to_lowercase();
-                    EXCLUDED_GENRES.iter().any(|excluded| {
-                        genre_lower.contains(excluded) || excluded.contains(&genre_lower)
-                    })
-                })
-            } else {
-                false
-            };
-            
-            if !should_exclude {
-                tracks.push(SpotifyTrack {
-                    track_name: item.track.name.clone(),
-                    artist: item.track.artists.first().map(|artist| artist.name.clone()).unwrap_or_default(),
-                    album_name: item.track.album.name.clone(),
-                    played_at: item.played_at.clone(),
-                    spotify_url: item.track.external_urls.spotify.clone(),
-                    album_image_url: item.track.album.images.first().map(|image| image.url.clone()),
-                    genres: track_genres,
-                });
+        let processed = process_play_history(items, &access_token).await?;
+        Ok(TopItems::Tracks(processed.tracks))
+    }
+}
+
+/// Fetch the track currently playing, or `None` if nothing (or something other than a
+/// track) is playing, using a short-lived cache. Needs the `user-read-currently-playing` scope.
+pub async fn fetch_now_playing() -> Result<Option<NowPlaying>, String> {
+    if let Some(now_playing) = NOW_PLAYING_CACHE.get(&NOW_PLAYING_CACHE_KEY).await {
+        return Ok(now_playing);
+    }
+    
+    if !is_configured() {
+        return Err(NOT_CONFIGURED.to_string());
+    }
+    
+    let result = snapshot::fetch("spotify_now_playing", download_now_playing()).await;
+    health::record("spotify", result.is_ok());
+    let now_playing = result?;
+    
+    NOW_PLAYING_CACHE.insert(NOW_PLAYING_CACHE_KEY, now_playing.clone()).await;
+    Ok(now_playing)
+}
+
+// Fetch the currently playing track from Spotify, bypassing the cache
+async fn download_now_playing() -> Result<Option<NowPlaying>, String> {
+    let access_token = get_access_token().await?;
+    request_now_playing("https://api.spotify.com/v1/me/player/currently-playing", &access_token).await
+}
+
+// Fetch the currently playing track from the given endpoint
+async fn request_now_playing(url: &str, access_token: &str) -> Result<Option<NowPlaying>, String> {
+    let mut response = upstream::send_with_retry(|| {
+        upstream::get(url).header("Authorization", format!("Bearer {}", access_token))
+    })
+        .await
+        .map_err(|e| format!("Failed to make request to Spotify API: {}", e))?;
+    
+    // Spotify answers 204 No Content when nothing is playing
+    if response.status() == StatusCode::NoContent {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        let error_text = response.body_string()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("Failed to get currently playing track: {} - {}", response.status(), error_text));
+    }
+    
+    let current: CurrentlyPlayingResponse = response.body_json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    if current.currently_playing_type.as_deref().is_some_and(|kind| kind != "track") {
+        return Ok(None);
+    }
+    Ok(current.item.map(|track| NowPlaying {
+        track_name: track.name.clone(),
+        artist: track.display_artist(*ARTIST_DISPLAY),
+        album_name: track.album.name.clone(),
+        spotify_url: track.spotify_url(),
+        album_image_url: track.album.images.first().map(|image| image.url.clone()),
+        progress_ms: current.progress_ms,
+        is_playing: current.is_playing,
+    }))
+}
+
+// Look up genres for the items' artists and split them into kept and excluded tracks
+async fn process_play_history(items: Vec<PlayHistoryObject>, access_token: &str) -> Result<RecentlyPlayed, String> {
+    // Get unique artist IDs
+    let artist_ids: Vec<String> = items.iter()
+        .flat_map(|item| item.track.artists.iter().map(|artist| artist.id.clone()))
+        .filter(|id| !id.is_empty())
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect();
+    
+    // Fetch artist genres
+    let artist_genres = get_artists_with_genres(artist_ids, access_token).await?;
+    Ok(filter_by_genre(items, &artist_genres, &GenreFilter::current()))
+}
+
+// Give tracks their artists' genres and split them into kept and excluded tracks
+fn filter_by_genre(items: Vec<PlayHistoryObject>, artist_genres: &HashMap<String, Vec<String>>, filter: &GenreFilter) -> RecentlyPlayed {
+    // Transform response to simplified format with genres
+    let mut tracks: Vec<SpotifyTrack> = Vec::new();
+    let mut excluded: Vec<ExcludedTrack> = Vec::new();
+    
+    for item in items.iter() {
+        // Get all genres from all artists on the track
+        let mut track_genres: Vec<String> = Vec::new();
+        for artist in &item.track.artists {
+            if let Some(genres) = artist_genres.get(&artist.id) {
+                track_genres.extend(genres.clone());
             }
         }
         
-        log::info!("Filtered tracks: {} tracks after genre filtering (excluded genres: {:?})", tracks.len(), *EXCLUDED_GENRES);
+        // Remove duplicates
+        track_genres.sort();
+        track_genres.dedup();
         
-        // Update cache with all filtered tracks
-        {
-            let mut cache_lock = TRACKS_CACHE.lock().unwrap();
-            *cache_lock = Some(TracksCacheEntry {
-                tracks: tracks.clone(),
-                timestamp: SystemTime::now(),
-            });
-            log::info!("Recently played tracks cache updated");
+        // Keep only tracks with an included genre, if any are configured
+        if !filter.has_included(&track_genres) {
+            log::debug!("Skipping {} with no included genre", item.track.name);
+            continue;
         }
         
-        // Limit the results to the requested number
-        let limited_tracks: Vec<SpotifyTrack> = tracks.into_iter().take(limit).collect();
+        // Check if any of the track's genres are in the excluded list; exclusion wins over inclusion
+        if let Some(matched_genre) = filter.matched_excluded(&track_genres) {
+            excluded.push(ExcludedTrack {
+                track_name: item.track.name.clone(),
+                artist: item.track.display_artist(*ARTIST_DISPLAY),
+                matched_genre,
+            });
+            continue;
+        }
         
-        let total_time = start_time.elapsed();
-        log::info!("Total get_recently_played took: {:?}, returning {} tracks", total_time, limited_tracks.len());
+        // Fill in the default genre only after filtering, so it can never cause an exclusion
+        if track_genres.is_empty()
+            && let Some(default_genre) = &filter.default_genre {
+            track_genres.push(default_genre.clone());
+        }
         
-        Ok(limited_tracks)
-    } else {
-        let error_text = response.body_string()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        Err(format!("Failed to get recently played tracks: {} - {}", response.status(), error_text))
+        tracks.push(SpotifyTrack {
+            track_name: item.track.name.clone(),
+            artist: item.track.display_artist(*ARTIST_DISPLAY),
+            album_name: item.track.album.name.clone(),
+            played_at: format_timestamp(&item.played_at, *SECOND_PRECISION),
+            spotify_url: item.track.spotify_url(),
+            album_image_url: item.track.album.images.first().map(|image| image.url.clone()),
+            genres: track_genres,
+            preview_url: item.track.preview_url.clone(),
+            popularity: item.track.popularity,
+            duration_ms: item.track.duration_ms,
+        });
     }
+    
+    log::info!("Filtered tracks: {} tracks after genre filtering (excluded genres: {:?})", tracks.len(), filter.excluded);
+    
+    RecentlyPlayed { tracks, excluded, cursors: None }
 }
 
 pub async fn get_spotify_tracks(req: Request<()>) -> tide::Result<Response> {
@@ -366,44 +1253,144 @@ pub async fn get_spotify_tracks(req: Request<()>) -> tide::Result<Response> {
     
     // Check for API key in the request headers
     if !auth::validate_api_key(&req) {
-        return Ok(Response::new(StatusCode::Unauthorized));
+        return Ok(auth::unauthorized_response());
+    }
+    
+    if pause::is_paused("spotify") {
+        return Ok(pause::paused_response("spotify"));
+    }
+    
+    if !is_configured() {
+        return Ok(not_configured_response());
     }
     
-    // Get the limit from query parameters, or use default
-    let limit = req.url().query_pairs()
-        .find(|(k, _)| k == "limit")
-        .and_then(|(_, v)| v.parse::<usize>().ok())
-        .unwrap_or(NUMBER_OF_TRACKS_TO_SHOW);
+    // Validate and sanitize query parameters
+    let params = match QueryParams::from_request(&req, QUERY_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    let limit = track_limit(&params);
     
     // Get optional no_cache parameter
-    let no_cache = req.url().query_pairs()
-        .find(|(k, _)| k == "no_cache")
-        .map(|(_, v)| v == "true")
-        .unwrap_or(false);
+    let no_cache = params.flag("no_cache");
+    
+    // Get optional max_age parameter (seconds the client will accept from cache)
+    let max_age = params.parse::<u64>("max_age").map(Duration::from_secs);
+    
+    // Get optional debug parameter to report tracks removed by the genre filter
+    let debug = params.flag("debug");
+    
+    // Only show tracks available in the client's market
+    let market = resolve_market(&req);
+    
+    // Get optional album filter (comma-separated, case-insensitive substrings of the album name)
+    let albums: Vec<String> = params.get("album")
+        .map(|value| {
+            value.split(',')
+                .map(|album| album.trim().to_lowercase())
+                .filter(|album| !album.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    
+    // Get optional sort order, most recently played first by default
+    let sort_by_popularity = match params.get("sort") {
+        None | Some("recency") => false,
+        Some("popularity") => true,
+        Some(other) => return Ok(params::bad_request(&format!("Unknown sort order '{}'", other))),
+    };
+    
+    // Get optional cap on the genres listed per track, unlimited by default
+    let genres_per_track = params.parse::<usize>("genres_per_track");
+    
+    // What to show for tracks without an album image
+    let image_fallback = match ImageFallback::from_param(params.get("image_fallback")) {
+        Ok(image_fallback) => image_fallback,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    // Get optional output format, JSON by default
+    let formatter = match format::lookup(params.get("format")) {
+        Ok(formatter) => formatter,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    
+    // Get optional named set of track fields to return
+    let fieldset = match params.get("fieldset") {
+        None => None,
+        Some(name) => match FIELDSETS.get(name) {
+            Some(fields) => Some(fields),
+            None => return Ok(params::bad_request(&format!("Unknown fieldset '{}'", name))),
+        },
+    };
+        
+    // Get optional position in the history (Unix milliseconds), latest plays by default
+    let cursor = match PageCursor::from_params(&params) {
+        Ok(cursor) => cursor,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+    let cache_key = tracks_cache_key(cursor.as_ref());
         
     let setup_time = start_time.elapsed();
     log::debug!("API endpoint setup took: {:?}", setup_time);
     
     // Clear cache if requested
     if no_cache {
-        let mut tracks_cache_lock = TRACKS_CACHE.lock().unwrap();
-        *tracks_cache_lock = None;
-        
-        let mut token_cache_lock = TOKEN_CACHE.lock().unwrap();
-        *token_cache_lock = None;
+        TRACKS_CACHE.clear().await;
+        TOKEN_CACHE.clear().await;
         
         log::info!("Cache cleared due to no_cache parameter");
     }
     
     // Fetch and process recently played tracks
-    match get_recently_played(limit).await {
-        Ok(tracks) => {
+    match fetch_recently_played_from(cursor.as_ref(), max_age).await {
+        Ok(recently_played) => {
             let fetch_time = start_time.elapsed();
             log::info!("Tracks fetch completed in: {:?}", fetch_time);
             
+            let excluded = recently_played.excluded.clone();
+            let cursors = recently_played.cursors.clone();
+            let mut tracks: Vec<SpotifyTrack> = recently_played.playable_in(market.as_deref()).await
+                .into_iter()
+                .filter(|track| albums.is_empty() || from_any_album(track, &albums))
+                .collect();
+            if sort_by_popularity {
+                sort_most_popular_first(&mut tracks);
+            }
+            tracks.truncate(limit);
+            for track in &mut tracks {
+                cap_genres(track, genres_per_track);
+                image_fallback.apply(&mut track.album_image_url);
+            }
+            stats::tracks_served(tracks.len());
+            let mut tracks = json!(tracks);
+            if let Some(fields) = fieldset {
+                select::retain_fields(&mut tracks, fields);
+            }
+            let mut body = if debug {
+                json!({ "tracks": tracks, "excluded": excluded })
+            } else {
+                json!({ "tracks": tracks })
+            };
+            if let Some(cursors) = cursors {
+                body["cursors"] = json!(cursors);
+            }
+            
+            // Data past the TTL isn't stale if it is within the max_age the client accepts
+            let within_max_age = match max_age {
+                Some(max_age) => TRACKS_CACHE.age(&cache_key).await.is_some_and(|age| age < max_age),
+                None => false,
+            };
             let mut res = Response::new(StatusCode::Ok);
-            res.set_content_type("application/json");
-            res.set_body(json!({ "tracks": tracks }));
+            if TRACKS_CACHE.is_expired(&cache_key).await && !within_max_age {
+                res.insert_header("Warning", upstream::STALE_WARNING);
+                body["stale"] = json!(true);
+            }
+            format::set_body(&mut res, formatter, &body, "tracks");
+            if let Some(age) = TRACKS_CACHE.age(&cache_key).await {
+                res.insert_header("Age", age.as_secs().to_string());
+            }
             
             let total_time = start_time.elapsed();
             log::info!("Total API request handled in: {:?}", total_time);
@@ -422,3 +1409,765 @@ pub async fn get_spotify_tracks(req: Request<()>) -> tide::Result<Response> {
         }
     }
 }
+
+/// Stream recently played tracks as newline-delimited JSON, paging back through
+/// the history with Spotify's cursors up to `EXPORT_MAX_TRACKS`. Tracks are sent
+/// as each page arrives rather than buffered. Requires the API key.
+pub async fn export_tracks(req: Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    if pause::is_paused("spotify") {
+        return Ok(pause::paused_response("spotify"));
+    }
+    
+    if !is_configured() {
+        return Ok(not_configured_response());
+    }
+
+    // Get the token up front so that failing to authenticate is still a proper error response
+    let access_token = match get_access_token().await {
+        Ok(access_token) => access_token,
+        Err(e) => {
+            log::error!("Error getting Spotify access token for export: {}", e);
+            let mut res = Response::new(StatusCode::InternalServerError);
+            res.set_content_type("application/json");
+            res.set_body(json!({ "error": "Could not load recently played tracks." }));
+            return Ok(res);
+        }
+    };
+
+    let (sender, receiver) = async_std::channel::bounded(EXPORT_PAGE_SIZE);
+    async_std::task::spawn(stream_export(access_token, sender));
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/x-ndjson");
+    res.set_body(tide::Body::from_reader(receiver.into_async_read(), None));
+    Ok(res)
+}
+
+// Page through the recently played history, sending each track as a JSON line
+async fn stream_export(access_token: String, sender: async_std::channel::Sender<std::io::Result<Vec<u8>>>) {
+    let mut before: Option<String> = None;
+    let mut sent = 0;
+
+    while sent < EXPORT_MAX_TRACKS {
+        let page = match fetch_recently_played_page(&access_token, EXPORT_PAGE_SIZE, before.clone().map(PageCursor::Before).as_ref()).await {
+            Ok(page) => page,
+            Err(e) => {
+                log::error!("Export stopped after {} tracks: {}", sent, e);
+                break;
+            }
+        };
+        let next_before = page.cursors.as_ref().and_then(|cursors| cursors.before.clone());
+
+        let items = page.play_history();
+        if items.is_empty() {
+            break;
+        }
+        let recently_played = match process_play_history(items, &access_token).await {
+            Ok(recently_played) => recently_played,
+            Err(e) => {
+                log::error!("Export stopped after {} tracks: {}", sent, e);
+                break;
+            }
+        };
+
+        let tracks = recently_played.tracks.into_iter().take(EXPORT_MAX_TRACKS - sent);
+        match send_json_lines(tracks, &sender).await {
+            Some(count) => sent += count,
+            // The client has gone away
+            None => return,
+        }
+
+        // Stop when Spotify has no older page
+        match next_before {
+            Some(cursor) if before.as_ref() != Some(&cursor) => before = Some(cursor),
+            _ => break,
+        }
+    }
+
+    log::info!("Exported {} tracks", sent);
+}
+
+// Send each track as a JSON line, returning how many were sent, or `None` if the receiver is gone
+async fn send_json_lines(tracks: impl IntoIterator<Item = SpotifyTrack>, sender: &async_std::channel::Sender<std::io::Result<Vec<u8>>>) -> Option<usize> {
+    let mut sent = 0;
+    for track in tracks {
+        let mut line = match serde_json::to_vec(&track) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to serialize track for export: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+        sender.send(Ok(line)).await.ok()?;
+        sent += 1;
+    }
+    Some(sent)
+}
+
+/// Return the account's top tracks or artists over a time range. Tracks have the
+/// same shape as on `/spotify`, with an empty `played_at`. Requires the API key.
+pub async fn get_top_items(req: Request<()>) -> tide::Result<Response> {
+    let start_time = Instant::now();
+
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    if pause::is_paused("spotify") {
+        return Ok(pause::paused_response("spotify"));
+    }
+
+    if !is_configured() {
+        return Ok(not_configured_response());
+    }
+
+    let params = match QueryParams::from_request(&req, TOP_PARAMS) {
+        Ok(params) => params,
+        Err(e) => return Ok(params::bad_request(&e)),
+    };
+
+    let kind = match params.get("type") {
+        Some(kind @ ("tracks" | "artists")) => kind,
+        Some(other) => return Ok(params::bad_request(&format!("Unknown type '{}', expected tracks or artists", other))),
+        None => return Ok(params::bad_request("Missing type, expected tracks or artists")),
+    };
+    let time_range = match params.get("time_range") {
+        None => "medium_term",
+        Some(range) if TIME_RANGES.contains(&range) => range,
+        Some(other) => return Ok(params::bad_request(&format!("Unknown time_range '{}', expected one of {}", other, TIME_RANGES.join(", ")))),
+    };
+    let limit = track_limit(&params);
+
+    let cache_key = format!("{}:{}", kind, time_range);
+    if params.flag("no_cache") {
+        TOP_CACHE.remove(&cache_key).await;
+    }
+
+    let mut res = match fetch_top_items(kind, time_range).await {
+        Ok(mut items) => {
+            items.truncate(limit);
+            log::info!("Retrieved {} top {} for {}", items.len(), kind, time_range);
+            if let TopItems::Tracks(tracks) = &items {
+                stats::tracks_served(tracks.len());
+            }
+            let items = match items {
+                TopItems::Tracks(tracks) => json!(tracks),
+                TopItems::Artists(artists) => json!(artists),
+            };
+            let mut res = Response::new(StatusCode::Ok);
+            if let Some(age) = TOP_CACHE.age(&cache_key).await {
+                res.insert_header("Age", age.as_secs().to_string());
+            }
+            res.set_body(json!({ "type": kind, "time_range": time_range, "items": items }));
+            res
+        },
+        Err(e) => {
+            log::error!("Error fetching Spotify top {}: {}", kind, e);
+            let mut res = Response::new(StatusCode::InternalServerError);
+            res.set_body(json!({ "error": format!("Could not load top {}.", kind) }));
+            res
+        }
+    };
+    res.set_content_type("application/json");
+    log::info!("Top items fetch completed in: {:?}", start_time.elapsed());
+    Ok(res)
+}
+
+/// Return the track currently playing, or `null` when nothing is playing.
+/// Cached for 30 seconds. Requires the API key.
+pub async fn get_now_playing(req: Request<()>) -> tide::Result<Response> {
+    let start_time = Instant::now();
+
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    if pause::is_paused("spotify") {
+        return Ok(pause::paused_response("spotify"));
+    }
+
+    if !is_configured() {
+        return Ok(not_configured_response());
+    }
+
+    let mut res = match fetch_now_playing().await {
+        Ok(now_playing) => {
+            let mut res = Response::new(StatusCode::Ok);
+            if let Some(age) = NOW_PLAYING_CACHE.age(&NOW_PLAYING_CACHE_KEY).await {
+                res.insert_header("Age", age.as_secs().to_string());
+            }
+            res.set_body(json!({ "now_playing": now_playing }));
+            res
+        },
+        Err(e) => {
+            log::error!("Error fetching currently playing track: {}", e);
+            let mut res = Response::new(StatusCode::InternalServerError);
+            res.set_body(json!({ "error": "Could not load the currently playing track." }));
+            res
+        }
+    };
+    res.set_content_type("application/json");
+    log::info!("Now playing fetch completed in: {:?}", start_time.elapsed());
+    Ok(res)
+}
+
+/// Replace the cached access token with a freshly requested one, leaving the cached
+/// tracks alone, and report how long the new token is valid. The token itself is
+/// never returned. Requires the API key.
+pub async fn refresh_token(req: Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    if CREDENTIALS.is_none() {
+        return Ok(not_configured_response());
+    }
+
+    TOKEN_CACHE.remove(&TOKEN_CACHE_KEY).await;
+    Ok(refresh_response(refresh_access_token().await))
+}
+
+// The response to a token refresh, reporting the new token's lifetime but never the token itself
+fn refresh_response(result: Result<TokenResponse, String>) -> Response {
+    let mut res = match result {
+        Ok(token_response) => {
+            let expires_in = token_response.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS);
+            log::info!("Refreshed Spotify access token, valid for {}s", expires_in);
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({ "refreshed": true, "expires_in": expires_in }));
+            res
+        },
+        Err(e) => {
+            log::error!("Error refreshing Spotify access token: {}", e);
+            let mut res = Response::new(StatusCode::InternalServerError);
+            res.set_body(json!({ "refreshed": false, "error": "Could not refresh the access token." }));
+            res
+        }
+    };
+    res.set_content_type("application/json");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockUpstream};
+
+    #[test]
+    fn compilation_tracks_show_the_chosen_artist() {
+        let track: TrackObject = serde_json::from_value(json!({
+            "name": "Song",
+            "artists": [{ "name": "Performer", "id": "p" }],
+            "album": { "name": "Hits", "artists": [{ "name": "Various Artists", "id": "v" }] },
+        })).unwrap();
+
+        assert_eq!(track.display_artist(ArtistDisplay::Track), "Performer");
+        assert_eq!(track.display_artist(ArtistDisplay::Album), "Various Artists");
+    }
+
+    #[test]
+    fn album_preference_falls_back_to_the_track_artist() {
+        let track: TrackObject = serde_json::from_value(json!({
+            "name": "Song",
+            "artists": [{ "name": "Performer", "id": "p" }],
+        })).unwrap();
+
+        assert_eq!(track.display_artist(ArtistDisplay::Album), "Performer");
+    }
+
+    #[test]
+    fn excluded_tracks_are_reported_with_the_matched_genre() {
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Joke", "artists": [{ "name": "Comic", "id": "c" }] } },
+            { "played_at": "2025-01-02T09:00:00Z", "track": { "name": "Tune", "artists": [{ "name": "Band", "id": "b" }] } },
+        ])).unwrap();
+        let artist_genres = HashMap::from([
+            ("c".to_string(), vec!["Stand-Up".to_string(), "Comedy".to_string()]),
+            ("b".to_string(), vec!["indie rock".to_string()]),
+        ]);
+        let filter = GenreFilter {
+            excluded: vec!["comedy".to_string()],
+            included: vec![],
+            mode: GenreMatchMode::Exact,
+            default_genre: None,
+        };
+
+        let recently_played = filter_by_genre(items, &artist_genres, &filter);
+
+        let kept: Vec<&str> = recently_played.tracks.iter().map(|track| track.track_name.as_str()).collect();
+        assert_eq!(kept, vec!["Tune"]);
+        assert_eq!(recently_played.excluded.len(), 1);
+        assert_eq!(recently_played.excluded[0].track_name, "Joke");
+        assert_eq!(recently_played.excluded[0].artist, "Comic");
+        assert_eq!(recently_played.excluded[0].matched_genre, "Comedy");
+    }
+
+    #[test]
+    fn accept_language_regions_map_to_markets() {
+        assert_eq!(market_from_accept_language("en-IE,en;q=0.9"), Some("IE".to_string()));
+        assert_eq!(market_from_accept_language("fr;q=0.5, de-at;q=0.8"), Some("AT".to_string()));
+        assert_eq!(market_from_accept_language("en"), None);
+        assert_eq!(market_from_accept_language("*"), None);
+        assert_eq!(market_from_accept_language("zh-Hant"), None);
+    }
+
+    #[async_std::test]
+    async fn an_unmappable_language_uses_the_default_market() {
+        let mut app = tide::new();
+        app.at("/market").get(|req: Request<()>| async move {
+            Ok(json!({ "market": resolve_market(&req) }))
+        });
+
+        let mut req = tide::http::Request::get("http://localhost/market");
+        req.insert_header("Accept-Language", "en");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["market"], json!(*DEFAULT_MARKET));
+
+        let mut req = tide::http::Request::get("http://localhost/market");
+        req.insert_header("Accept-Language", "en-IE");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["market"], "IE");
+    }
+
+    #[test]
+    fn album_filter_keeps_tracks_from_any_listed_album() {
+        let tracks: Vec<SpotifyTrack> = serde_json::from_value(json!([
+            { "track_name": "Come Together", "artist": "The Beatles", "album_name": "Abbey Road", "played_at": "",
+              "spotify_url": "", "album_image_url": null, "genres": [] },
+            { "track_name": "Paranoid Android", "artist": "Radiohead", "album_name": "OK Computer", "played_at": "",
+              "spotify_url": "", "album_image_url": null, "genres": [] },
+            { "track_name": "Hey Jude", "artist": "The Beatles", "album_name": "Hey Jude", "played_at": "",
+              "spotify_url": "", "album_image_url": null, "genres": [] },
+        ])).unwrap();
+        let names = |albums: &[&str]| -> Vec<String> {
+            let albums: Vec<String> = albums.iter().map(|album| album.to_string()).collect();
+            tracks.iter()
+                .filter(|track| from_any_album(track, &albums))
+                .map(|track| track.track_name.clone())
+                .collect()
+        };
+
+        assert_eq!(names(&["abbey"]), vec!["Come Together"]);
+        assert!(names(&["revolver"]).is_empty());
+        assert_eq!(names(&["abbey road", "ok computer"]), vec!["Come Together", "Paranoid Android"]);
+    }
+
+    #[test]
+    fn preview_url_is_passed_through_when_spotify_has_one() {
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z",
+              "track": { "name": "With", "preview_url": "https://p.scdn.co/mp3-preview/abc" } },
+            { "played_at": "2025-01-02T09:00:00Z",
+              "track": { "name": "Null", "preview_url": null } },
+            { "played_at": "2025-01-02T08:00:00Z",
+              "track": { "name": "Missing" } },
+        ])).unwrap();
+        let filter = GenreFilter { excluded: vec![], included: vec![], mode: GenreMatchMode::Exact, default_genre: None };
+
+        let tracks = filter_by_genre(items, &HashMap::new(), &filter).tracks;
+
+        assert_eq!(tracks[0].preview_url.as_deref(), Some("https://p.scdn.co/mp3-preview/abc"));
+        assert_eq!(tracks[1].preview_url, None);
+        assert_eq!(tracks[2].preview_url, None);
+        // An absent preview is serialized as null rather than dropped
+        assert_eq!(json!(tracks[2])["preview_url"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn an_item_missing_fields_does_not_fail_the_others() {
+        let response: RecentlyPlayedResponse = serde_json::from_value(json!({
+            "items": [
+                { "played_at": "2025-01-02T10:00:00Z",
+                  "track": { "name": "Linked", "id": "1", "external_urls": { "spotify": "https://open.spotify.com/track/1" } } },
+                { "played_at": "2025-01-02T09:00:00Z",
+                  "track": { "name": "Unlinked", "id": "2" } },
+                { "played_at": "2025-01-02T08:00:00Z",
+                  "track": { "id": "3" } },
+            ],
+        })).unwrap();
+
+        let items = response.play_history();
+
+        // The track without a name can't be shown and is skipped; the one without external_urls is kept
+        let names: Vec<&str> = items.iter().map(|item| item.track.name.as_str()).collect();
+        assert_eq!(names, vec!["Linked", "Unlinked"]);
+        assert_eq!(items[1].track.spotify_url(), "https://open.spotify.com/track/2");
+    }
+
+    #[test]
+    fn tracks_sort_by_popularity_with_ties_in_play_order() {
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Niche", "popularity": 12 } },
+            { "played_at": "2025-01-02T09:00:00Z", "track": { "name": "Hit", "popularity": 90 } },
+            { "played_at": "2025-01-02T08:00:00Z", "track": { "name": "Unrated" } },
+            { "played_at": "2025-01-02T07:00:00Z", "track": { "name": "Also niche", "popularity": 12 } },
+        ])).unwrap();
+        let filter = GenreFilter { excluded: vec![], included: vec![], mode: GenreMatchMode::Exact, default_genre: None };
+
+        let mut tracks = filter_by_genre(items, &HashMap::new(), &filter).tracks;
+        let popularity: Vec<Option<u32>> = tracks.iter().map(|track| track.popularity).collect();
+        assert_eq!(popularity, vec![Some(12), Some(90), None, Some(12)]);
+
+        sort_most_popular_first(&mut tracks);
+        let names: Vec<&str> = tracks.iter().map(|track| track.track_name.as_str()).collect();
+        assert_eq!(names, vec!["Hit", "Niche", "Also niche", "Unrated"]);
+    }
+
+    #[test]
+    fn genreless_tracks_get_the_default_genre_without_being_excluded() {
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Obscure", "artists": [{ "name": "Nobody", "id": "n" }] } },
+            { "played_at": "2025-01-02T09:00:00Z", "track": { "name": "Tune", "artists": [{ "name": "Band", "id": "b" }] } },
+        ])).unwrap();
+        let artist_genres = HashMap::from([("b".to_string(), vec!["indie rock".to_string()])]);
+        // Excluding the default genre itself must not drop the tracks it was given to
+        let filter = GenreFilter {
+            excluded: vec!["unknown".to_string()],
+            included: vec![],
+            mode: GenreMatchMode::Exact,
+            default_genre: Some("unknown".to_string()),
+        };
+
+        let recently_played = filter_by_genre(items, &artist_genres, &filter);
+
+        let genres: Vec<(&str, Vec<String>)> = recently_played.tracks.iter()
+            .map(|track| (track.track_name.as_str(), track.genres.clone()))
+            .collect();
+        assert_eq!(genres, vec![
+            ("Obscure", vec!["unknown".to_string()]),
+            ("Tune", vec!["indie rock".to_string()]),
+        ]);
+        assert!(recently_played.excluded.is_empty());
+
+        // Unset, a genre-less track keeps an empty list
+        let unset = GenreFilter { default_genre: None, ..filter };
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Obscure", "artists": [{ "name": "Nobody", "id": "n" }] } },
+        ])).unwrap();
+        assert!(filter_by_genre(items, &artist_genres, &unset).tracks[0].genres.is_empty());
+    }
+
+    #[async_std::test]
+    async fn export_streams_one_json_object_per_line() {
+        let tracks: Vec<SpotifyTrack> = serde_json::from_value(json!([
+            { "track_name": "One", "artist": "A", "album_name": "X", "played_at": "2025-01-02T10:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": ["pop"] },
+            { "track_name": "Two", "artist": "B", "album_name": "Y", "played_at": "2025-01-02T09:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/2", "album_image_url": null, "genres": [] },
+            { "track_name": "Three", "artist": "C", "album_name": "Z", "played_at": "2025-01-02T08:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/3", "album_image_url": null, "genres": [] },
+        ])).unwrap();
+        let (sender, receiver) = async_std::channel::bounded(EXPORT_PAGE_SIZE);
+        async_std::task::spawn(async move {
+            assert_eq!(send_json_lines(tracks, &sender).await, Some(3));
+        });
+
+        let body = tide::Body::from_reader(receiver.into_async_read(), None).into_string().await.unwrap();
+
+        assert!(body.ends_with('\n'));
+        let names: Vec<String> = body.lines()
+            .map(|line| serde_json::from_str::<SpotifyTrack>(line).unwrap().track_name)
+            .collect();
+        assert_eq!(names, vec!["One", "Two", "Three"]);
+    }
+
+    #[test]
+    fn genres_are_capped_per_track_in_sorted_order() {
+        let items: Vec<PlayHistoryObject> = serde_json::from_value(json!([
+            { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Duet", "artists": [
+                { "name": "One", "id": "1" }, { "name": "Two", "id": "2" },
+            ] } },
+        ])).unwrap();
+        let artist_genres = HashMap::from([
+            ("1".to_string(), vec!["shoegaze".to_string(), "dream pop".to_string()]),
+            ("2".to_string(), vec!["dream pop".to_string(), "art rock".to_string(), "noise pop".to_string()]),
+        ]);
+        let filter = GenreFilter { excluded: vec![], included: vec![], mode: GenreMatchMode::Exact, default_genre: None };
+        let track = filter_by_genre(items, &artist_genres, &filter).tracks.remove(0);
+
+        let mut uncapped = track.clone();
+        cap_genres(&mut uncapped, None);
+        assert_eq!(uncapped.genres, vec!["art rock", "dream pop", "noise pop", "shoegaze"]);
+
+        let mut capped = track;
+        cap_genres(&mut capped, Some(2));
+        assert_eq!(capped.genres, vec!["art rock", "dream pop"]);
+    }
+
+    #[test]
+    fn a_fieldset_projects_each_track_to_its_fields() {
+        let fieldsets = fieldsets_from([
+            ("SPOTIFY_FIELDSET_widget".to_string(), "track_name, artist,album_image_url,".to_string()),
+            ("SPOTIFY_MARKET".to_string(), "IE".to_string()),
+        ].into_iter());
+        assert_eq!(fieldsets.len(), 1);
+
+        let mut tracks = json!([
+            { "track_name": "One", "artist": "A", "album_name": "X", "played_at": "2025-01-02T10:00:00Z",
+              "spotify_url": "https://open.spotify.com/track/1", "album_image_url": null, "genres": ["pop"] },
+        ]);
+        select::retain_fields(&mut tracks, &fieldsets["widget"]);
+
+        assert_eq!(tracks, json!([{ "track_name": "One", "artist": "A", "album_image_url": null }]));
+    }
+
+    #[test]
+    fn second_precision_strips_fractional_seconds() {
+        assert_eq!(format_timestamp("2025-01-02T10:00:05.123Z", true), "2025-01-02T10:00:05Z");
+        assert_eq!(format_timestamp("2025-01-02T11:00:05.999+01:00", true), "2025-01-02T10:00:05Z");
+        assert_eq!(format_timestamp("not a timestamp", true), "not a timestamp");
+
+        // Millisecond precision passes the timestamp through as Spotify sent it
+        assert_eq!(format_timestamp("2025-01-02T10:00:05.123Z", false), "2025-01-02T10:00:05.123Z");
+    }
+
+    #[test]
+    fn an_after_cursor_is_forwarded_and_both_cursors_are_rejected() {
+        let cursor = |query: &str| {
+            let url = url::Url::parse(&format!("http://localhost/spotify?{}", query)).unwrap();
+            PageCursor::from_params(&QueryParams::from_url(&url, QUERY_PARAMS, true).unwrap())
+        };
+
+        let after = cursor("after=1735725600000").unwrap();
+        assert_eq!(after, Some(PageCursor::After("1735725600000".to_string())));
+        let after = after.unwrap();
+        assert_eq!(after.query(), "after=1735725600000");
+        // Each position in the history is cached separately from the latest tracks
+        assert_ne!(tracks_cache_key(Some(&after)), tracks_cache_key(None));
+        assert_ne!(tracks_cache_key(Some(&after)), tracks_cache_key(Some(&PageCursor::Before("1735725600000".to_string()))));
+
+        assert_eq!(cursor("before=1&after=2"), Err("Only one of 'before' and 'after' may be given".to_string()));
+        assert_eq!(cursor(""), Ok(None));
+
+        // Spotify's cursors, including `after`, are passed back to the client
+        let response: RecentlyPlayedResponse = serde_json::from_value(json!({
+            "items": [], "cursors": { "before": "1735725000000", "after": "1735725600000" },
+        })).unwrap();
+        assert_eq!(json!(response.cursors)["after"], "1735725600000");
+    }
+
+    #[async_std::test]
+    async fn a_refresh_recaches_the_token_without_returning_it() {
+        let body = r#"{"access_token":"fresh-secret-token","token_type":"Bearer","expires_in":1800}"#;
+        let accounts = MockUpstream::always(test_support::ok("application/json", body)).await;
+        let token_url = accounts.url("/api/token");
+        let credentials = Credentials {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            refresh_token: "refresh".to_string(),
+        };
+
+        let token_response = request_access_token(&token_url, &credentials).await.unwrap();
+        let cached = TOKEN_CACHE.get(&TOKEN_CACHE_KEY).await.unwrap();
+        assert_eq!(cached.access_token, "fresh-secret-token");
+        assert_eq!(cached.expires_in, Duration::from_secs(1800));
+
+        let mut res: tide::http::Response = refresh_response(Ok(token_response)).into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = res.body_string().await.unwrap();
+        assert!(!body.contains("fresh-secret-token"));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), json!({ "refreshed": true, "expires_in": 1800 }));
+
+        let res: tide::http::Response = refresh_response(Err("Failed to get access token: 400".to_string())).into();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn a_token_is_used_until_its_own_lifetime_less_the_margin() {
+        let token = |expires_in: Option<u64>| {
+            let response: TokenResponse = serde_json::from_value(json!({
+                "access_token": "token", "token_type": "Bearer", "expires_in": expires_in,
+            })).unwrap();
+            TokenCacheEntry {
+                access_token: response.access_token,
+                expires_in: Duration::from_secs(response.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS)),
+            }
+        };
+
+        let short = token(Some(300));
+        assert!(short.is_fresh(Duration::from_secs(200)));
+        assert!(!short.is_fresh(Duration::from_secs(240)));
+        assert!(!short.is_fresh(Duration::from_secs(900)));
+
+        // Shorter than the margin, so never used from the cache
+        assert!(!token(Some(30)).is_fresh(Duration::ZERO));
+
+        let unreported = token(None);
+        assert!(unreported.is_fresh(Duration::from_secs(3500)));
+        assert!(!unreported.is_fresh(Duration::from_secs(3540)));
+    }
+
+    #[async_std::test]
+    async fn now_playing_is_a_track_or_nothing() {
+        let player = MockUpstream::start(|number, _| match number {
+            0 => test_support::empty("204 No Content", &[]),
+            1 => test_support::ok("application/json", r#"{"is_playing":true,"progress_ms":42000,"currently_playing_type":"track","item":{"name":"Song","artists":[{"name":"Performer","id":"p"}],"album":{"name":"Album","images":[{"url":"https://i.scdn.co/image/cover"}]},"external_urls":{"spotify":"https://open.spotify.com/track/1"}}}"#),
+            _ => test_support::ok("application/json", r#"{"is_playing":true,"progress_ms":1000,"currently_playing_type":"episode","item":null}"#),
+        }).await;
+        let url = player.url("/v1/me/player/currently-playing");
+
+        assert!(request_now_playing(&url, "token").await.unwrap().is_none());
+
+        let now_playing = request_now_playing(&url, "token").await.unwrap().unwrap();
+        assert_eq!(json!(now_playing), json!({
+            "track_name": "Song", "artist": "Performer", "album_name": "Album",
+            "spotify_url": "https://open.spotify.com/track/1", "album_image_url": "https://i.scdn.co/image/cover",
+            "progress_ms": 42000, "is_playing": true,
+        }));
+
+        // Podcast episodes aren't reported
+        assert!(request_now_playing(&url, "token").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn a_huge_limit_is_clamped() {
+        let limit = |query: &str| {
+            let url = url::Url::parse(&format!("http://localhost/spotify?{}", query)).unwrap();
+            track_limit(&QueryParams::from_url(&url, QUERY_PARAMS, true).unwrap())
+        };
+
+        assert_eq!(limit("limit=1000000"), *MAX_LIMIT);
+        assert_eq!(limit("limit=3"), 3);
+        assert_eq!(limit(""), NUMBER_OF_TRACKS_TO_SHOW);
+    }
+
+    #[async_std::test]
+    async fn genre_chunks_are_fetched_concurrently_and_fail_alone() {
+        // The chunk starting with artist 0 succeeds, any other is not found
+        let artists = MockUpstream::start_with_delay(Duration::from_millis(200), |_, request| if request.contains("ids=artist0,") {
+            test_support::ok("application/json", r#"{"artists":[{"id":"artist0","name":"Artist","genres":["pop"]},null]}"#)
+        } else {
+            test_support::empty("404 Not Found", &[])
+        }).await;
+        let artists_url = artists.url("/v1/artists");
+
+        let ids: Vec<String> = (0..120).map(|i| format!("artist{}", i)).collect();
+        let genres = fetch_artist_genres(&artists_url, ids.clone(), "token").await.unwrap();
+        assert_eq!(genres, HashMap::from([("artist0".to_string(), vec!["pop".to_string()])]));
+        assert_eq!(artists.most_in_flight(), 3);
+
+        // Only when every chunk fails does the lookup fail
+        let error = fetch_artist_genres(&artists_url, ids[50..].to_vec(), "token").await.unwrap_err();
+        assert!(error.starts_with("Failed to get artist genres: 404"));
+    }
+
+    #[async_std::test]
+    async fn excluded_genres_set_at_runtime_apply_to_the_next_fetch() {
+        let items = || -> Vec<PlayHistoryObject> {
+            serde_json::from_value(json!([
+                { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Joke", "artists": [{ "name": "Comic", "id": "c" }] } },
+                { "played_at": "2025-01-02T09:00:00Z", "track": { "name": "Tune", "artists": [{ "name": "Band", "id": "b" }] } },
+            ])).unwrap()
+        };
+        let artist_genres = HashMap::from([
+            ("c".to_string(), vec!["Comedy".to_string()]),
+            ("b".to_string(), vec!["indie rock".to_string()]),
+        ]);
+        let cache_key = "runtime-excluded-genres".to_string();
+        let kept = |recently_played: &RecentlyPlayed| -> Vec<String> {
+            recently_played.tracks.iter().map(|track| track.track_name.clone()).collect()
+        };
+
+        let stored = set_excluded_genres(&[" Comedy ".to_string(), "".to_string()]).await;
+        assert_eq!(stored, vec!["comedy"]);
+        let recently_played = filter_by_genre(items(), &artist_genres, &GenreFilter::current());
+        assert_eq!(kept(&recently_played), vec!["Tune"]);
+        TRACKS_CACHE.insert(cache_key.clone(), recently_played).await;
+
+        // Changing the list drops the tracks filtered with the old one
+        set_excluded_genres(&[]).await;
+        assert!(TRACKS_CACHE.get(&cache_key).await.is_none());
+        let recently_played = filter_by_genre(items(), &artist_genres, &GenreFilter::current());
+        assert_eq!(kept(&recently_played), vec!["Joke", "Tune"]);
+    }
+
+    #[async_std::test]
+    async fn a_rate_limited_genre_lookup_is_retried() {
+        let artists = MockUpstream::start(|number, _| match number {
+            0 => test_support::empty("429 Too Many Requests", &[("Retry-After", "1")]),
+            _ => test_support::ok("application/json", r#"{"artists":[{"id":"a","name":"Artist","genres":["jazz"]}]}"#),
+        }).await;
+        let artists_url = artists.url("/v1/artists");
+
+        let started = Instant::now();
+        let genres = fetch_artist_genres(&artists_url, vec!["a".to_string()], "token").await.unwrap();
+
+        assert_eq!(genres["a"], vec!["jazz"]);
+        assert_eq!(artists.requests(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn exact_mode_excludes_pop_but_not_k_pop() {
+        let filter = GenreFilter { excluded: vec!["pop".to_string()], included: vec![], mode: GenreMatchMode::Exact, default_genre: None };
+
+        assert_eq!(filter.matched_excluded(&["Pop".to_string()]), Some("Pop".to_string()));
+        assert_eq!(filter.matched_excluded(&["k-pop".to_string()]), None);
+        assert_eq!(filter.matched_excluded(&["pop rock".to_string()]), None);
+    }
+
+    #[test]
+    fn prefix_mode_excludes_pop_rock_but_not_k_pop() {
+        let filter = GenreFilter { excluded: vec!["pop".to_string()], included: vec![], mode: GenreMatchMode::Prefix, default_genre: None };
+
+        assert_eq!(filter.matched_excluded(&["pop".to_string()]), Some("pop".to_string()));
+        assert_eq!(filter.matched_excluded(&["k-pop".to_string()]), None);
+        assert_eq!(filter.matched_excluded(&["Pop Rock".to_string()]), Some("Pop Rock".to_string()));
+    }
+
+    #[test]
+    fn contains_mode_excludes_k_pop_too() {
+        let filter = GenreFilter { excluded: vec!["pop".to_string()], included: vec![], mode: GenreMatchMode::Contains, default_genre: None };
+
+        assert_eq!(filter.matched_excluded(&["pop".to_string()]), Some("pop".to_string()));
+        assert_eq!(filter.matched_excluded(&["k-pop".to_string()]), Some("k-pop".to_string()));
+        assert_eq!(filter.matched_excluded(&["synthpop".to_string()]), Some("synthpop".to_string()));
+        assert_eq!(filter.matched_excluded(&["jazz".to_string()]), None);
+    }
+
+    #[test]
+    fn exclusion_wins_over_inclusion() {
+        let items = || -> Vec<PlayHistoryObject> {
+            serde_json::from_value(json!([
+                { "played_at": "2025-01-02T12:00:00Z", "track": { "name": "Jazz", "artists": [{ "name": "Trio", "id": "j" }] } },
+                { "played_at": "2025-01-02T11:00:00Z", "track": { "name": "Jazz comedy", "artists": [{ "name": "Trio", "id": "j" }, { "name": "Comic", "id": "c" }] } },
+                { "played_at": "2025-01-02T10:00:00Z", "track": { "name": "Rock", "artists": [{ "name": "Band", "id": "r" }] } },
+                { "played_at": "2025-01-02T09:00:00Z", "track": { "name": "Unknown", "artists": [{ "name": "Nobody", "id": "n" }] } },
+            ])).unwrap()
+        };
+        let artist_genres = HashMap::from([
+            ("j".to_string(), vec!["Jazz".to_string()]),
+            ("c".to_string(), vec!["comedy".to_string()]),
+            ("r".to_string(), vec!["rock".to_string()]),
+        ]);
+        let kept = |filter: &GenreFilter| -> Vec<String> {
+            filter_by_genre(items(), &artist_genres, filter).tracks.iter().map(|track| track.track_name.clone()).collect()
+        };
+        let filter = |included: &[&str], excluded: &[&str]| GenreFilter {
+            excluded: excluded.iter().map(|genre| genre.to_string()).collect(),
+            included: included.iter().map(|genre| genre.to_string()).collect(),
+            mode: GenreMatchMode::Exact,
+            default_genre: None,
+        };
+
+        // Both empty keeps everything, including tracks without genres
+        assert_eq!(kept(&filter(&[], &[])), vec!["Jazz", "Jazz comedy", "Rock", "Unknown"]);
+        // Inclusion alone keeps only tracks with an included genre
+        assert_eq!(kept(&filter(&["jazz"], &[])), vec!["Jazz", "Jazz comedy"]);
+        // Exclusion alone drops only tracks with an excluded genre
+        assert_eq!(kept(&filter(&[], &["comedy"])), vec!["Jazz", "Rock", "Unknown"]);
+        // A track with both an included and an excluded genre is excluded
+        let recently_played = filter_by_genre(items(), &artist_genres, &filter(&["jazz"], &["comedy"]));
+        let names: Vec<&str> = recently_played.tracks.iter().map(|track| track.track_name.as_str()).collect();
+        assert_eq!(names, vec!["Jazz"]);
+        assert_eq!(recently_played.excluded.len(), 1);
+        assert_eq!(recently_played.excluded[0].matched_genre, "comedy");
+    }
+}
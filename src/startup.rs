@@ -0,0 +1,124 @@
+use std::env;
+use tide::log;
+use tide::prelude::*;
+use crate::cache::DEFAULT_CACHE_DURATION_SECS;
+use crate::spotify;
+use crate::upstream;
+use crate::timeout::REQUEST_TIMEOUT;
+use crate::concurrency::MAX_CONCURRENT_REQUESTS;
+use crate::rate_limit::{RATE_LIMIT_MAX, RATE_LIMIT_WINDOW};
+
+/// Environment variables holding secrets; the summary only reports whether each is set
+const SECRETS: &[&str] = &[
+    "API_KEY",
+    "API_KEYS",
+    "SPOTIFY_CLIENT_ID",
+    "SPOTIFY_CLIENT_SECRET",
+    "SPOTIFY_REFRESH_TOKEN",
+    "REDIS_URL",
+];
+
+fn is_set(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| !value.is_empty())
+}
+
+fn present(name: &str) -> &'static str {
+    if is_set(name) { "set" } else { "missing" }
+}
+
+/// Log the effective configuration once at startup, as a single JSON object when
+/// `LOG_FORMAT=json` and as an aligned table otherwise. Secrets are never printed.
+pub fn log_summary(host: &str, port: &str, allowed_origin: &str) {
+    if *upstream::MAX_RETRY_AFTER_SECS > upstream::retry_budget().as_secs() {
+        log::warn!(
+            "MAX_RETRY_AFTER_SECS ({}) exceeds the {}s retry budget (REQUEST_TIMEOUT_SECS less HTTP_TIMEOUT_SECS); longer Retry-After waits are not taken",
+            *upstream::MAX_RETRY_AFTER_SECS, upstream::retry_budget().as_secs()
+        );
+    }
+
+    if *upstream::HTTP_TIMEOUT >= *REQUEST_TIMEOUT {
+        log::warn!(
+            "HTTP_TIMEOUT_SECS ({}) is not below REQUEST_TIMEOUT_SECS ({}); slow upstreams may time out whole requests",
+            upstream::HTTP_TIMEOUT.as_secs(), REQUEST_TIMEOUT.as_secs()
+        );
+    }
+
+    let as_json = env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    log::info!("{}", render_summary(summary_rows(host, port, allowed_origin), as_json));
+}
+
+// The reported settings, with secrets reduced to whether they are set
+fn summary_rows(host: &str, port: &str, allowed_origin: &str) -> Vec<(&'static str, String)> {
+    let mut sources = vec!["urls", "letterboxd"];
+    if spotify::is_configured() {
+        sources.push("spotify");
+    }
+
+    let cache_backend = if cfg!(feature = "redis") && is_set("REDIS_URL") { "redis" } else { "memory" };
+    let cache_max_bytes = env::var("CACHE_MAX_BYTES").unwrap_or_else(|_| "unbounded".to_string());
+
+    let mut rows: Vec<(&str, String)> = vec![
+        ("listen", format!("{}:{}", host, port)),
+        ("route_prefix", crate::route("/")),
+        ("sources", sources.join(", ")),
+        ("cors_origin", allowed_origin.to_string()),
+        ("api_key_header", crate::auth::API_KEY_HEADER.to_string()),
+        ("upstream_tls", if *upstream::ALLOW_INSECURE_UPSTREAM_TLS { "INSECURE (not validated)" } else { "validated" }.to_string()),
+        ("request_timeout_secs", REQUEST_TIMEOUT.as_secs().to_string()),
+        ("http_timeout_secs", upstream::HTTP_TIMEOUT.as_secs().to_string()),
+        ("retry_budget_secs", upstream::retry_budget().as_secs().to_string()),
+        ("rate_limit", RATE_LIMIT_MAX.map_or_else(|| "off".to_string(), |max| format!("{} per {}s", max, RATE_LIMIT_WINDOW.as_secs()))),
+        ("max_concurrent_requests", MAX_CONCURRENT_REQUESTS.map_or_else(|| "unlimited".to_string(), |max| max.to_string())),
+        ("cache_backend", cache_backend.to_string()),
+        ("cache_max_bytes", cache_max_bytes),
+        ("letterboxd_cache_ttl_secs", DEFAULT_CACHE_DURATION_SECS.to_string()),
+        ("spotify_cache_ttl_secs", spotify::CACHE_DURATION_SECS.to_string()),
+    ];
+    rows.extend(SECRETS.iter().map(|name| (*name, present(name).to_string())));
+    rows
+}
+
+// The summary as one log message: a JSON object, or an aligned table
+fn render_summary(rows: Vec<(&'static str, String)>, as_json: bool) -> String {
+    if as_json {
+        let summary: serde_json::Map<String, serde_json::Value> = rows.into_iter()
+            .map(|(key, value)| (key.to_lowercase(), json!(value)))
+            .collect();
+        json!({ "startup_summary": summary }).to_string()
+    } else {
+        let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+        let table = rows.iter()
+            .map(|(key, value)| format!("  {:<width$}  {}", key, value, width = width))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("Startup summary:\n{}", table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_reports_secrets_without_their_values() {
+        crate::test_support::use_test_key();
+        let key = &crate::auth::API_KEYS[0];
+        let rows = summary_rows("127.0.0.1", "8080", "*");
+
+        for secret in SECRETS {
+            let (_, value) = rows.iter().find(|(name, _)| name == secret).unwrap();
+            assert!(value == "set" || value == "missing", "{} reported as {}", secret, value);
+        }
+
+        let text = render_summary(rows.clone(), false);
+        assert!(text.starts_with("Startup summary:\n"));
+        assert!(!text.contains(key.as_str()));
+
+        // The JSON form is a single object on one line
+        let line = render_summary(rows, true);
+        assert!(!line.contains('\n'));
+        let summary: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(summary["startup_summary"]["listen"], "127.0.0.1:8080");
+        assert!(!line.contains(key.as_str()));
+    }
+}
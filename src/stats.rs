@@ -0,0 +1,112 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tide::{Request, Response, StatusCode};
+use tide::prelude::*;
+use crate::auth;
+use crate::letterboxd;
+use crate::spotify;
+
+// Items returned by successful reads since the server started
+static TRACKS_SERVED: AtomicU64 = AtomicU64::new(0);
+static MOVIES_SERVED: AtomicU64 = AtomicU64::new(0);
+static URLS_SERVED: AtomicU64 = AtomicU64::new(0);
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Start the uptime clock
+pub fn init() {
+    LazyLock::force(&STARTED);
+}
+
+/// Time since the server started
+pub fn uptime() -> std::time::Duration {
+    STARTED.elapsed()
+}
+
+/// Count tracks returned to a client
+pub fn tracks_served(count: usize) {
+    TRACKS_SERVED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Count movies returned to a client
+pub fn movies_served(count: usize) {
+    MOVIES_SERVED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Count URLs returned to a client
+pub fn urls_served(count: usize) {
+    URLS_SERVED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Endpoint returning the number of tracks, movies and URLs served since startup,
+/// e.g. for a "X songs and Y films shared" footer. This endpoint does not require authentication.
+pub async fn get_served(_req: Request<()>) -> tide::Result<Response> {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({
+        "tracks_served": TRACKS_SERVED.load(Ordering::Relaxed),
+        "movies_served": MOVIES_SERVED.load(Ordering::Relaxed),
+        "urls_served": URLS_SERVED.load(Ordering::Relaxed),
+        "uptime_secs": uptime().as_secs(),
+    }));
+    Ok(res)
+}
+
+/// Endpoint reporting hits, misses, evictions and entry counts for each cache since
+/// startup, for tuning cache TTLs. Requires the API key.
+pub async fn get_cache_stats(req: Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("application/json");
+    res.set_body(json!({
+        "letterboxd": {
+            "feeds": letterboxd::FEED_CACHE.stats().await,
+            "channels": letterboxd::CHANNEL_CACHE.stats().await,
+        },
+        "spotify": {
+            "tracks": spotify::TRACKS_CACHE.stats().await,
+            "top": spotify::TOP_CACHE.stats().await,
+            "now_playing": spotify::NOW_PLAYING_CACHE.stats().await,
+            "token": spotify::TOKEN_CACHE.stats().await,
+            "playable": spotify::PLAYABLE_CACHE.stats().await,
+        },
+    }));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn serving_items_increments_the_counters() {
+        let feed_url = "https://example.com/stats-served/rss";
+        let movies: Vec<letterboxd::LetterboxdMovie> = (0..2).map(|i| serde_json::from_value(json!({
+            "title": format!("Film {}", i), "link": "https://letterboxd.com/film/", "description": "",
+            "pub_date": null, "film_title": format!("Film {}", i), "rating": null, "rewatch": null,
+        })).unwrap()).collect();
+        letterboxd::FEED_CACHE.insert(feed_url.to_string(), movies).await;
+
+        let mut app = tide::new();
+        app.at("/stats/served").get(get_served);
+        app.at("/aggregated").get(crate::aggregator::get_aggregated_data);
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/stats/served")).await.unwrap();
+        let before: serde_json::Value = res.body_json().await.unwrap();
+
+        let req = tide::http::Request::get(format!("http://localhost/aggregated?sources=letterboxd&feed_url={}", feed_url).as_str());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // Other tests serve items concurrently, so the counters grow by at least what this request served
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/stats/served")).await.unwrap();
+        let after: serde_json::Value = res.body_json().await.unwrap();
+        assert!(after["movies_served"].as_u64().unwrap() >= before["movies_served"].as_u64().unwrap() + 2);
+        assert!(after["tracks_served"].as_u64().unwrap() >= before["tracks_served"].as_u64().unwrap());
+        assert!(after["uptime_secs"].is_u64());
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::{Arc, Once};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpListener;
+use crate::auth;
+
+/// API key the tests authenticate with
+const TEST_API_KEY: &str = "test-key";
+
+/// Set `API_KEY` to the test key unless a key is configured. `auth::API_KEYS` is read once,
+/// so any test that presents a key or reads the keys calls this (or `credential`) first.
+pub fn use_test_key() {
+    static SET_KEY: Once = Once::new();
+    SET_KEY.call_once(|| {
+        if std::env::var("API_KEY").is_err() && std::env::var("API_KEYS").is_err() {
+            // SAFETY: Rust's own environment access is synchronised, and no test reads the
+            // environment from outside Rust
+            unsafe { std::env::set_var("API_KEY", TEST_API_KEY) };
+        }
+    });
+}
+
+/// Header value presenting the first configured API key
+pub fn credential() -> String {
+    use_test_key();
+    auth::credential(&auth::API_KEYS[0])
+}
+
+/// A local HTTP server standing in for an upstream service. Each request gets the
+/// response built by the server's handler from the request's number (from 0) and text.
+pub struct MockUpstream {
+    base_url: String,
+    requests: Arc<AtomicU32>,
+    most_in_flight: Arc<AtomicU32>,
+}
+
+impl MockUpstream {
+    /// Start a server answering each request with `respond(number, request)`
+    pub async fn start(respond: impl Fn(u32, &str) -> String + Send + Sync + 'static) -> Self {
+        Self::start_with_delay(Duration::ZERO, respond).await
+    }
+
+    /// Start a server answering every request with the same response
+    pub async fn always(response: String) -> Self {
+        Self::start(move |_, _| response.clone()).await
+    }
+
+    /// Start a server like `start` that holds each response back for `delay`. Connections are
+    /// served concurrently, so `most_in_flight` shows how many requests overlapped.
+    pub async fn start_with_delay(delay: Duration, respond: impl Fn(u32, &str) -> String + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicU32::new(0));
+        let in_flight = Arc::new(AtomicU32::new(0));
+        let most_in_flight = Arc::new(AtomicU32::new(0));
+        let respond = Arc::new(respond);
+
+        let (served, served_most) = (Arc::clone(&requests), Arc::clone(&most_in_flight));
+        async_std::task::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (served, in_flight, most_in_flight) = (Arc::clone(&served), Arc::clone(&in_flight), Arc::clone(&served_most));
+                let respond = Arc::clone(&respond);
+                async_std::task::spawn(async move {
+                    let mut request = [0u8; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]).to_string();
+                    let number = served.fetch_add(1, Ordering::SeqCst);
+                    most_in_flight.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    async_std::task::sleep(delay).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(respond(number, &request).as_bytes()).await;
+                });
+            }
+        });
+
+        MockUpstream { base_url, requests, most_in_flight }
+    }
+
+    /// URL of `path` on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Number of requests received so far
+    pub fn requests(&self) -> u32 {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Largest number of requests that were being answered at the same time
+    pub fn most_in_flight(&self) -> u32 {
+        self.most_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// A URL on a local port nothing listens on, so requests to it are refused
+pub async fn refused_url(path: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("http://{}{}", listener.local_addr().unwrap(), path)
+}
+
+/// A 200 response with the given body and content type
+pub fn ok(content_type: &str, body: &str) -> String {
+    format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", content_type, body.len(), body)
+}
+
+/// A response with a status line such as `404 Not Found`, extra headers and no body
+pub fn empty(status: &str, headers: &[(&str, &str)]) -> String {
+    let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    format!("HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status, headers)
+}
@@ -0,0 +1,67 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+use tide::{log, Middleware, Next, Request, Response, StatusCode};
+use tide::prelude::*;
+
+/// Hard ceiling on the total time spent handling a request. This is a backstop
+/// and should stay well above the time upstream requests (and their retries) take.
+pub static REQUEST_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("REQUEST_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(20);
+    Duration::from_secs(secs)
+});
+
+/// Middleware that answers 503 Service Unavailable if a request takes longer than `REQUEST_TIMEOUT`
+pub struct RequestTimeout {
+    timeout: Duration,
+}
+
+impl RequestTimeout {
+    pub fn new() -> Self {
+        RequestTimeout { timeout: *REQUEST_TIMEOUT }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestTimeout {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        match async_std::future::timeout(self.timeout, next.run(req)).await {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                log::error!("Request to {} timed out after {:?}", path, self.timeout);
+                let mut res = Response::new(StatusCode::ServiceUnavailable);
+                res.set_content_type("application/json");
+                res.set_body(json!({ "error": "Request timed out" }));
+                Ok(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn a_slow_handler_gets_a_503() {
+        let mut app = tide::new();
+        app.with(RequestTimeout { timeout: Duration::from_millis(50) });
+        app.at("/slow").get(|_| async {
+            async_std::task::sleep(Duration::from_secs(5)).await;
+            Ok("done")
+        });
+        app.at("/fast").get(|_| async { Ok("done") });
+
+        let mut res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/slow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body, json!({ "error": "Request timed out" }));
+
+        let res: tide::http::Response = app.respond(tide::http::Request::get("http://localhost/fast")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+}
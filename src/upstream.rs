@@ -0,0 +1,283 @@
+use std::cell::Cell;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use http_client::isahc::IsahcClient;
+use isahc::config::{Configurable, SslOption};
+use tide::{log, Middleware, Next, Request, StatusCode};
+use crate::timeout::REQUEST_TIMEOUT;
+
+/// Longest `Retry-After` we are willing to wait (default 30); upstreams asking for more fail immediately
+pub static MAX_RETRY_AFTER_SECS: LazyLock<u64> = LazyLock::new(|| {
+    std::env::var("MAX_RETRY_AFTER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30)
+});
+
+/// Longest wait for an upstream response (or body) before the fetch fails, from
+/// `HTTP_TIMEOUT_SECS`. Kept below the overall request timeout so a slow upstream
+/// is reported as a failed source rather than a timed-out request.
+pub static HTTP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("HTTP_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(10);
+    Duration::from_secs(secs)
+});
+
+/// Total attempts per upstream request, including the first
+static MAX_ATTEMPTS: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("UPSTREAM_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|attempts| *attempts > 0)
+        .unwrap_or(3)
+});
+
+/// First backoff after a 5xx response, doubled on each further attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest backoff between attempts after 5xx responses
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// `Warning` header value for responses served from an expired cache entry after a failed refetch
+pub const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// Whether to skip TLS certificate validation for upstream requests. Dangerous:
+/// only meant for staging setups behind an intercepting proxy.
+pub static ALLOW_INSECURE_UPSTREAM_TLS: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("ALLOW_INSECURE_UPSTREAM_TLS").is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+});
+
+// HTTP client shared by all upstream requests
+static CLIENT: LazyLock<surf::Client> = LazyLock::new(|| build_client(*ALLOW_INSECURE_UPSTREAM_TLS));
+
+// TLS checks to turn off, or `None` to keep the default certificate validation
+fn insecure_ssl_options(allow_insecure_tls: bool) -> Option<SslOption> {
+    allow_insecure_tls.then_some(
+        SslOption::DANGER_ACCEPT_INVALID_CERTS
+            | SslOption::DANGER_ACCEPT_INVALID_HOSTS
+            | SslOption::DANGER_ACCEPT_REVOKED_CERTS,
+    )
+}
+
+// Configuration of the shared client. By default surf builds its own backend, which validates
+// certificates; only the opt-in swaps in a backend with the checks turned off.
+fn client_config(allow_insecure_tls: bool) -> surf::Config {
+    // HTTP_TIMEOUT is applied per request, so the client itself has no timeout (like `surf::Client::new`)
+    let config = surf::Config::new().set_timeout(None);
+    let Some(ssl_options) = insecure_ssl_options(allow_insecure_tls) else {
+        return config;
+    };
+
+    log::warn!("ALLOW_INSECURE_UPSTREAM_TLS is enabled: upstream TLS certificates will NOT be validated");
+    let insecure = isahc::HttpClient::builder()
+        .ssl_options(ssl_options)
+        .build();
+    match insecure {
+        Ok(client) => config.set_http_client(IsahcClient::from_client(client)),
+        Err(e) => {
+            log::error!("Failed to build insecure upstream client, keeping TLS validation: {}", e);
+            config
+        }
+    }
+}
+
+fn build_client(allow_insecure_tls: bool) -> surf::Client {
+    client_config(allow_insecure_tls).try_into().unwrap_or_else(|e| {
+        log::error!("Failed to build upstream client from its configuration, using the default: {}", e);
+        surf::Client::new()
+    })
+}
+
+/// Build the shared client up front so TLS configuration warnings appear at startup
+pub fn init_client() {
+    LazyLock::force(&CLIENT);
+}
+
+/// Start a GET request to an upstream on the shared client
+pub fn get(url: impl AsRef<str>) -> surf::RequestBuilder {
+    CLIENT.get(url.as_ref())
+}
+
+/// Start a HEAD request to an upstream on the shared client
+pub fn head(url: impl AsRef<str>) -> surf::RequestBuilder {
+    CLIENT.head(url.as_ref())
+}
+
+/// Start a POST request to an upstream on the shared client
+pub fn post(url: impl AsRef<str>) -> surf::RequestBuilder {
+    CLIENT.post(url.as_ref())
+}
+
+// Number of upstream requests made while handling the current incoming request.
+// Each connection is served on its own task, so a task-local gives a per-request count.
+async_std::task_local! {
+    static UPSTREAM_CALLS: Cell<u32> = Cell::new(0);
+}
+
+/// Send a request to an upstream service, counting it against the current incoming request
+pub async fn send(request: surf::RequestBuilder) -> surf::Result<surf::Response> {
+    // Outside of a request task (e.g. background work) there is nothing to count
+    let _ = UPSTREAM_CALLS.try_with(|calls| calls.set(calls.get() + 1));
+    request.await
+}
+
+/// Send a request like `send`, failing if no response arrives within `HTTP_TIMEOUT`
+pub async fn send_with_timeout(request: surf::RequestBuilder, url: &str) -> Result<surf::Response, String> {
+    match async_std::future::timeout(*HTTP_TIMEOUT, send(request)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("No response from {} within {:?}", url, *HTTP_TIMEOUT)),
+    }
+}
+
+/// Longest time a single upstream call may spend retrying: the request timeout less
+/// one `HTTP_TIMEOUT`, so the last attempt can still be answered before the request is cut off
+pub fn retry_budget() -> Duration {
+    REQUEST_TIMEOUT.saturating_sub(*HTTP_TIMEOUT)
+}
+
+/// Send a request built by `build`, retrying up to `UPSTREAM_MAX_ATTEMPTS` times.
+/// Each attempt fails if no response arrives within `HTTP_TIMEOUT`.
+/// - 429 Too Many Requests waits for `Retry-After`, up to `MAX_RETRY_AFTER_SECS`;
+///   a longer requested wait is returned as an error straight away rather than
+///   tying up the request
+/// - 5xx responses back off exponentially, capped at `MAX_BACKOFF`
+/// - other responses, including 4xx, are returned as they are
+///
+/// A wait that would run past `retry_budget()` is not taken, and the last response
+/// is returned instead.
+pub async fn send_with_retry(build: impl Fn() -> surf::RequestBuilder) -> Result<surf::Response, String> {
+    let max_attempts = *MAX_ATTEMPTS;
+    let deadline = Instant::now() + retry_budget();
+    let mut attempt = 1;
+    loop {
+        let response = match async_std::future::timeout(*HTTP_TIMEOUT, send(build())).await {
+            Ok(result) => result.map_err(|e| e.to_string())?,
+            Err(_) => return Err(format!("No upstream response within {:?}", *HTTP_TIMEOUT)),
+        };
+        if attempt >= max_attempts {
+            return Ok(response);
+        }
+
+        let wait = if response.status() == StatusCode::TooManyRequests {
+            let wait = retry_after(&response)?;
+            log::warn!("Upstream rate limited us (attempt {}/{}), retrying in {:?}", attempt, max_attempts, wait);
+            wait
+        } else if response.status().is_server_error() {
+            let wait = backoff(attempt);
+            log::warn!("Upstream returned {} (attempt {}/{}), retrying in {:?}", response.status(), attempt, max_attempts, wait);
+            wait
+        } else {
+            return Ok(response);
+        };
+
+        if Instant::now() + wait > deadline {
+            log::warn!("Not retrying: waiting {:?} would exceed the {:?} retry budget", wait, retry_budget());
+            return Ok(response);
+        }
+
+        async_std::task::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+// Exponential backoff before retrying after the given failed attempt
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_BACKOFF)
+}
+
+// Wait requested by a 429 response, in whole seconds (defaults to 1 if missing or unparseable)
+fn retry_after(response: &surf::Response) -> Result<Duration, String> {
+    let secs = response.header("Retry-After")
+        .and_then(|value| value.as_str().trim().parse::<u64>().ok())
+        .unwrap_or(1);
+
+    if secs > *MAX_RETRY_AFTER_SECS {
+        return Err(format!(
+            "Rate limited with Retry-After of {}s, above the {}s ceiling", secs, *MAX_RETRY_AFTER_SECS
+        ));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Middleware that reports how many upstream calls a request made in an `X-Upstream-Calls` header
+pub struct UpstreamCallCounter;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for UpstreamCallCounter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let _ = UPSTREAM_CALLS.try_with(|calls| calls.set(0));
+        let mut res = next.run(req).await;
+        let calls = UPSTREAM_CALLS.try_with(|calls| calls.get()).unwrap_or(0);
+        res.insert_header("X-Upstream-Calls", calls.to_string());
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockUpstream};
+
+    #[async_std::test]
+    async fn tls_is_validated_unless_opted_out() {
+        // Strict by default: no backend of our own, so none with the checks turned off
+        assert!(insecure_ssl_options(false).is_none());
+        assert!(client_config(false).http_client.is_none());
+        assert!(client_config(true).http_client.is_some());
+        assert!(!*ALLOW_INSECURE_UPSTREAM_TLS || std::env::var("ALLOW_INSECURE_UPSTREAM_TLS").is_ok());
+
+        // The client built from the default configuration makes requests
+        let upstream = MockUpstream::always(test_support::ok("text/plain", "ok")).await;
+        let mut response = build_client(false).get(upstream.url("/")).await.unwrap();
+        assert_eq!(response.body_string().await.unwrap(), "ok");
+    }
+
+    #[async_std::test]
+    async fn server_error_is_retried_after_a_backoff() {
+        let upstream = MockUpstream::start(|number, _| match number {
+            0 => test_support::empty("503 Service Unavailable", &[]),
+            _ => test_support::ok("text/plain", "ok"),
+        }).await;
+        let url = upstream.url("/");
+
+        let started = Instant::now();
+        let response = send_with_retry(|| get(&url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(upstream.requests(), 2);
+        assert!(started.elapsed() >= INITIAL_BACKOFF);
+    }
+
+    #[async_std::test]
+    async fn rate_limit_is_retried_after_retry_after() {
+        let upstream = MockUpstream::start(|number, _| match number {
+            0 => test_support::empty("429 Too Many Requests", &[("Retry-After", "1")]),
+            _ => test_support::ok("text/plain", "ok"),
+        }).await;
+        let url = upstream.url("/");
+
+        let started = Instant::now();
+        let response = send_with_retry(|| get(&url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(upstream.requests(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[async_std::test]
+    async fn absurd_retry_after_fails_without_sleeping() {
+        let upstream = MockUpstream::always(test_support::empty("429 Too Many Requests", &[("Retry-After", "86400")])).await;
+        let url = upstream.url("/");
+
+        let started = Instant::now();
+        let result = send_with_retry(|| get(&url)).await;
+
+        let error = result.err().unwrap();
+        assert!(error.contains("Retry-After of 86400s"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
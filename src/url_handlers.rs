@@ -1,53 +1,213 @@
 use tide::{log, prelude::*};
-use tide::{Response, StatusCode};
-use std::collections::VecDeque;
+use tide::{http::Headers, Response, StatusCode};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Mutex;
 use std::sync::LazyLock;
 use std::fs::File;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use async_std::channel::{bounded, Receiver, Sender};
+use url::Url;
 use crate::auth;
+use crate::stats;
 
-static QUEUE_SIZE: usize = 5;
-static URL_FILE_PATH: &str = "urls.json";
-
-// Fixed-size queue of 5 most recently read URLs
-pub static LAST_READ_URLS: LazyLock<Mutex<VecDeque<String>>> = LazyLock::new(|| {
-    // Try to load existing URLs from file
-    let mut queue = VecDeque::with_capacity(QUEUE_SIZE);
-    if let Ok(content) = std::fs::read_to_string(URL_FILE_PATH) {
-        if let Ok(saved_urls) = serde_json::from_str::<Vec<String>>(&content) {
-            for url in saved_urls {
-                if queue.len() < QUEUE_SIZE {
-                    queue.push_back(url);
-                }
-            }
-            log::info!("Loaded {} URLs from file", queue.len());
-        }
+/// Number of recent URLs kept, from `URL_QUEUE_SIZE` (default 5)
+static QUEUE_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("URL_QUEUE_SIZE")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(5)
+});
+/// Where the queue is saved, relative to the working directory
+pub const URL_FILE: &str = "urls.json";
+const PERSIST_DEBOUNCE_MS: u64 = 500; // write urls.json at most twice a second
+
+// Wakes the background writer when the queue changes. Capacity 1 so bursts coalesce.
+static PERSIST_SIGNAL: LazyLock<(Sender<()>, Receiver<()>)> = LazyLock::new(|| bounded(1));
+
+/// A logged URL, with an optional label for where the webhook came from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlEntry {
+    pub url: String,
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Values of the `URL_CAPTURE_HEADERS` headers sent with the webhook
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+// Webhook request headers stored with each URL, from the comma-separated `URL_CAPTURE_HEADERS`.
+// Nothing is captured by default.
+static CAPTURE_HEADERS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("URL_CAPTURE_HEADERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+});
+
+// Values of the named headers among a request's headers. Headers not named are not kept.
+fn capture_headers(headers: &Headers, names: &[String]) -> HashMap<String, String> {
+    names.iter()
+        .filter_map(|name| headers.get(name.as_str()).map(|value| (name.clone(), value.as_str().to_string())))
+        .collect()
+}
+
+// Whether a webhook value is an absolute http or https URL
+fn is_web_url(value: &str) -> bool {
+    Url::parse(value).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+// 400 response for a raw body that is not valid UTF-8 text
+fn unreadable_body_response() -> Response {
+    Response::builder(StatusCode::BadRequest)
+        .body(json!({"error": "Request body is not valid UTF-8 text"}))
+        .build()
+}
+
+// 400 response for a value that `is_web_url` rejects
+fn invalid_url_response() -> Response {
+    Response::builder(StatusCode::BadRequest)
+        .body(json!({"error": "Not a valid absolute http(s) URL"}))
+        .build()
+}
+
+// Parse the saved queue, accepting the older format of plain URL strings
+fn parse_saved_urls(content: &str) -> Option<Vec<UrlEntry>> {
+    if let Ok(entries) = serde_json::from_str::<Vec<UrlEntry>>(content) {
+        return Some(entries);
+    }
+    serde_json::from_str::<Vec<String>>(content).ok().map(|urls| {
+        urls.into_iter().map(|url| UrlEntry { url, source: None, headers: HashMap::new() }).collect()
+    })
+}
+
+// Fixed-size queue of the QUEUE_SIZE most recently read URLs, oldest first
+pub static LAST_READ_URLS: LazyLock<Mutex<VecDeque<UrlEntry>>> = LazyLock::new(|| Mutex::new(VecDeque::with_capacity(*QUEUE_SIZE)));
+
+/// Replace the queue with the URLs saved at `path`, if it holds any
+pub fn load_urls(path: &Path) {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return;
+    };
+    let Some(saved_urls) = parse_saved_urls(&content) else {
+        log::warn!("Ignoring unreadable saved URLs in {}", path.display());
+        return;
+    };
+    // Keep the most recent URLs if the file was saved with a larger queue
+    let skipped = saved_urls.len().saturating_sub(*QUEUE_SIZE);
+    if skipped > 0 {
+        log::info!("Dropping {} oldest saved URLs to fit URL_QUEUE_SIZE {}", skipped, *QUEUE_SIZE);
     }
-    Mutex::new(queue)
+    let mut queue = LAST_READ_URLS.lock().unwrap();
+    queue.clear();
+    queue.extend(saved_urls.into_iter().skip(skipped));
+    log::info!("Loaded {} URLs from file", queue.len());
+}
+
+// URL kept at the front of the list regardless of recency, outside the queue.
+// Starts from PINNED_URL and can be changed at runtime through /url-webhook/pin.
+static PINNED_URL: LazyLock<Mutex<Option<String>>> = LazyLock::new(|| {
+    let pinned = std::env::var("PINNED_URL").ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    if let Some(url) = &pinned
+        && !is_web_url(url) {
+        log::warn!("Ignoring PINNED_URL {:?}: not an absolute http(s) URL", url);
+        return Mutex::new(None);
+    }
+    Mutex::new(pinned)
 });
 
+/// The pinned URL followed by the queued URLs, keeping only the most recent `limit`
+/// of the queue. The pinned URL doesn't count towards the limit and isn't repeated.
+pub fn current_urls(limit: Option<usize>) -> Vec<UrlEntry> {
+    let pinned = PINNED_URL.lock().unwrap().clone();
+    let queue = LAST_READ_URLS.lock().unwrap();
+    let skip = limit.map_or(0, |limit| queue.len().saturating_sub(limit));
+
+    let mut urls: Vec<UrlEntry> = Vec::with_capacity(queue.len() + 1);
+    if let Some(pinned) = &pinned {
+        urls.push(UrlEntry { url: pinned.clone(), source: None, headers: HashMap::new() });
+    }
+    urls.extend(queue.iter()
+        .skip(skip)
+        .filter(|entry| pinned.as_ref() != Some(&entry.url))
+        .cloned());
+    urls
+}
+
 // Function to save URLs to file
-fn save_urls_to_file(urls: &VecDeque<String>) -> std::io::Result<()> {
-    let urls_vec: Vec<String> = urls.iter().cloned().collect();
+fn save_urls_to_file(urls: &VecDeque<UrlEntry>, path: &Path) -> std::io::Result<()> {
+    let urls_vec: Vec<UrlEntry> = urls.iter().cloned().collect();
     let json = serde_json::to_string_pretty(&urls_vec)?;
-    let mut file = File::create(URL_FILE_PATH)?;
+    let mut file = File::create(path)?;
     file.write_all(json.as_bytes())?;
     log::info!("Saved {} URLs to file", urls.len());
     Ok(())
 }
 
+// Ask the background writer to persist the queue. If a write is already pending
+// the signal is dropped, since that write will pick up this change as well.
+fn request_persist() {
+    let _ = PERSIST_SIGNAL.0.try_send(());
+}
+
+/// Write the current queue to `path` immediately
+pub fn flush_urls(path: &Path) {
+    let snapshot = LAST_READ_URLS.lock().unwrap().clone();
+    if let Err(e) = save_urls_to_file(&snapshot, path) {
+        log::error!("Failed to save URLs to file: {}", e);
+    }
+}
+
+/// Background task that persists the URL queue to `path`, debounced so that a burst of
+/// webhooks results in a single write of the latest state
+pub async fn run_url_writer(path: PathBuf) {
+    write_debounced(move || flush_urls(&path)).await;
+}
+
+// Call `write` after each change to the queue, once per debounce window
+async fn write_debounced(write: impl Fn() + Clone + Send + 'static) {
+    let receiver = PERSIST_SIGNAL.1.clone();
+    while receiver.recv().await.is_ok() {
+        async_std::task::sleep(Duration::from_millis(PERSIST_DEBOUNCE_MS)).await;
+        // Anything signalled during the debounce window is covered by this write
+        while receiver.try_recv().is_ok() {}
+        async_std::task::spawn_blocking(write.clone()).await;
+    }
+}
+
 pub async fn log_url(mut req: tide::Request<()>) -> tide::Result<Response> {
     // Check for API key in the request headers
     if !auth::validate_api_key(&req) {
-        return Ok(Response::new(StatusCode::Unauthorized));
+        return Ok(auth::unauthorized_response());
     }
     
+    // The source label may come from the X-Source header or the JSON body
+    let mut source = req.header("X-Source").map(|value| value.as_str().to_string());
+    
+    // Keep only the configured context headers
+    let headers = capture_headers(req.as_ref(), &CAPTURE_HEADERS);
+
     // Determine if the request is JSON or raw based on Content-Type header
     let url = if let Some(content_type) = req.header("Content-Type") {
         if content_type.as_str().contains("application/json") {
             // Handle JSON format
-            let body: serde_json::Value = req.body_json().await?;
+            let body: serde_json::Value = match req.body_json().await {
+                Ok(body) => body,
+                Err(_) => {
+                    return Ok(Response::builder(StatusCode::BadRequest)
+                        .body(json!({"error": "Request body is not valid JSON"}))
+                        .build());
+                }
+            };
+            if let Some(body_source) = body.get("source").and_then(|value| value.as_str()) {
+                source = Some(body_source.to_string());
+            }
             match body.get("url") {
                 Some(url_value) => {
                     if let Some(url_str) = url_value.as_str() {
@@ -66,50 +226,344 @@ pub async fn log_url(mut req: tide::Request<()>) -> tide::Result<Response> {
             }
         } else {
             // Handle raw format
-            req.body_string().await?
+            match req.body_string().await {
+                Ok(body) => body,
+                Err(_) => return Ok(unreadable_body_response()),
+            }
         }
     } else {
         // Default to raw format if no Content-Type header
-        req.body_string().await?
+        match req.body_string().await {
+            Ok(body) => body,
+            Err(_) => return Ok(unreadable_body_response()),
+        }
     };
+    let url = url.trim().to_string();
+    
+    if !is_web_url(&url) {
+        return Ok(invalid_url_response());
+    }
     
-    // Add the new URL to the queue, removing oldest if needed
+    log::info!("Received webhook: {} (source: {})", url, source.as_deref().unwrap_or("unknown"));
+    let queue_size = queue_url(UrlEntry { url, source, headers });
+
+    // Return a small JSON acknowledgement
+    let res = Response::builder(StatusCode::Ok)
+        .body(json!({"status": "ok", "queue_size": queue_size}))
+        .build();
+    Ok(res)
+}
+
+// Add a URL to the queue, removing the oldest if needed, and return the new queue length
+fn queue_url(entry: UrlEntry) -> usize {
     let mut urls = LAST_READ_URLS.lock().unwrap();
-    // Log the body and current URLs
-    log::info!("Received webhook: {}", url);
+
+    // A URL sent again moves to the back as the most recent instead of being duplicated
+    if let Some(index) = urls.iter().position(|queued| queued.url == entry.url) {
+        log::debug!("Moving repeated URL to the back: {}", entry.url);
+        urls.remove(index);
+    }
     
     // If at capacity, remove oldest before adding new one
     log::debug!("Current queue length: {}", urls.len());
-    if urls.len() >= QUEUE_SIZE {
+    if urls.len() >= *QUEUE_SIZE {
         log::debug!("Removing oldest URL: {:?}", urls.front());
         urls.pop_front();
     }
-    urls.push_back(url); // Add the new URL
+    urls.push_back(entry); // Add the new URL
 
     log::debug!("The list of updated webhooks: {:#?}", urls);
+    let queue_size = urls.len();
+    drop(urls);
     
-    // Save the updated URLs to file
-    if let Err(e) = save_urls_to_file(&urls) {
-        log::error!("Failed to save URLs to file: {}", e);
-    }
-
-    // Return a response
-    let res = Response::new(StatusCode::Ok);
-    Ok(res)
+    // Persist the updated URLs in the background
+    request_persist();
+    queue_size
 }
 
 pub async fn get_urls(req: tide::Request<()>) -> tide::Result<Response> {
     // Check for API key in the request headers
     if !auth::validate_api_key(&req) {
-        return Ok(Response::new(StatusCode::Unauthorized));
+        return Ok(auth::unauthorized_response());
     }
 
-    // Get the URLs from the queue
-    let urls = LAST_READ_URLS.lock().unwrap();
-    let urls_vec: Vec<String> = urls.iter().cloned().collect();
-    let json = json!({ "urls": urls_vec });
+    // Get the URLs (pinned first), both as plain strings and with their source labels
+    let entries = current_urls(None);
+    let urls_vec: Vec<&str> = entries.iter().map(|entry| entry.url.as_str()).collect();
+    stats::urls_served(urls_vec.len());
+    let pinned = PINNED_URL.lock().unwrap().clone();
+    let json = json!({ "urls": urls_vec, "entries": entries, "pinned": pinned });
     let mut res = Response::new(StatusCode::Ok);
     res.set_content_type("application/json");
     res.set_body(json);
     Ok(res)
-} 
\ No newline at end of file
+} 
+
+/// Pin a URL to the front of the list, or unpin with an empty `url`
+pub async fn pin_url(mut req: tide::Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    let body: serde_json::Value = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => {
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body(json!({"error": "Request body is not valid JSON"}))
+                .build());
+        }
+    };
+    let Some(url) = body.get("url").and_then(|value| value.as_str()) else {
+        return Ok(Response::builder(StatusCode::BadRequest)
+            .body(json!({"error": "Missing 'url' field in JSON"}))
+            .build());
+    };
+
+    let url = url.trim();
+    if !url.is_empty() && !is_web_url(url) {
+        return Ok(invalid_url_response());
+    }
+
+    let pinned = (!url.is_empty()).then(|| url.to_string());
+    log::info!("Pinned URL set to {:?}", pinned);
+    *PINNED_URL.lock().unwrap() = pinned.clone();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(json!({"status": "ok", "pinned": pinned}))
+        .build())
+}
+
+/// Remove the pinned URL
+pub async fn unpin_url(req: tide::Request<()>) -> tide::Result<Response> {
+    if !auth::validate_api_key(&req) {
+        return Ok(auth::unauthorized_response());
+    }
+
+    *PINNED_URL.lock().unwrap() = None;
+    log::info!("Pinned URL removed");
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(json!({"status": "ok", "pinned": null}))
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // The queue and its file are shared by every test in this module
+    static QUEUE_LOCK: async_std::sync::Mutex<()> = async_std::sync::Mutex::new(());
+
+    #[async_std::test]
+    async fn urls_are_stored_with_and_without_a_source_label() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/url-webhook").post(log_url);
+
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body(json!({"url": "https://example.com/labelled", "source": "ios-shortcut"}));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body("https://example.com/unlabelled");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let queue = LAST_READ_URLS.lock().unwrap().clone();
+        let sources: Vec<(&str, Option<&str>)> = queue.iter().rev().take(2)
+            .map(|entry| (entry.url.as_str(), entry.source.as_deref()))
+            .collect();
+        assert_eq!(sources, vec![
+            ("https://example.com/unlabelled", None),
+            ("https://example.com/labelled", Some("ios-shortcut")),
+        ]);
+    }
+
+    #[async_std::test]
+    async fn webhook_replies_with_status_and_queue_size() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/url-webhook").post(log_url);
+
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body("https://example.com/acknowledged");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let queue_size = LAST_READ_URLS.lock().unwrap().len();
+        assert_eq!(body, json!({ "status": "ok", "queue_size": queue_size }));
+
+        // A body that isn't UTF-8 gets the same JSON error shape
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body(vec![0xff, 0xfe, 0xfd]);
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body, json!({ "error": "Request body is not valid UTF-8 text" }));
+    }
+
+    #[async_std::test]
+    async fn a_url_limit_keeps_the_newest_urls() {
+        let _guard = QUEUE_LOCK.lock().await;
+        for i in 0..4 {
+            queue_url(UrlEntry { url: format!("https://example.com/limit/{}", i), source: None, headers: HashMap::new() });
+        }
+
+        let urls: Vec<String> = current_urls(Some(2)).into_iter().map(|entry| entry.url).collect();
+        let pinned = PINNED_URL.lock().unwrap().clone();
+        assert_eq!(urls, pinned.into_iter()
+            .chain(["https://example.com/limit/2".to_string(), "https://example.com/limit/3".to_string()])
+            .collect::<Vec<_>>());
+    }
+
+    #[async_std::test]
+    async fn a_burst_of_urls_is_written_once_with_the_final_queue() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let path = std::env::temp_dir().join(format!("api-agg-urls-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let writes = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let (counted, written) = (std::sync::Arc::clone(&writes), path.clone());
+        async_std::task::spawn(write_debounced(move || {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            flush_urls(&written);
+        }));
+
+        for i in 0..50 {
+            queue_url(UrlEntry { url: format!("https://example.com/burst/{}", i), source: None, headers: HashMap::new() });
+        }
+        // Nothing is written until the debounce window has passed
+        assert!(!path.exists());
+
+        async_std::task::sleep(Duration::from_millis(PERSIST_DEBOUNCE_MS * 3)).await;
+        assert_eq!(writes.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let saved = parse_saved_urls(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let queued: Vec<String> = LAST_READ_URLS.lock().unwrap().iter().map(|entry| entry.url.clone()).collect();
+        let saved: Vec<String> = saved.into_iter().map(|entry| entry.url).collect();
+        assert_eq!(saved, queued);
+        assert_eq!(saved.last().map(String::as_str), Some("https://example.com/burst/49"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[async_std::test]
+    async fn loading_a_larger_saved_queue_keeps_the_newest_urls() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let path = std::env::temp_dir().join(format!("api-agg-saved-urls-{}.json", std::process::id()));
+        let saved: Vec<String> = (0..*QUEUE_SIZE + 2).map(|i| format!("https://example.com/saved/{}", i)).collect();
+        std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        load_urls(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let queued: Vec<String> = LAST_READ_URLS.lock().unwrap().iter().map(|entry| entry.url.clone()).collect();
+        assert_eq!(queued, saved[2..]);
+    }
+
+    #[async_std::test]
+    async fn the_pinned_url_stays_first_as_urls_are_added() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/url-webhook").post(log_url).get(get_urls);
+        app.at("/url-webhook/pin").post(pin_url).delete(unpin_url);
+
+        let mut req = tide::http::Request::post("http://localhost/url-webhook/pin");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body(json!({"url": "  https://example.com/featured  "}));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        for i in 0..3 {
+            let mut req = tide::http::Request::post("http://localhost/url-webhook");
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            req.set_body(format!("https://example.com/after-pin/{}", i));
+            app.respond::<_, tide::http::Response>(req).await.unwrap();
+
+            let mut req = tide::http::Request::get("http://localhost/url-webhook");
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            let body: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(body["urls"][0], "https://example.com/featured");
+            assert_eq!(body["pinned"], "https://example.com/featured");
+        }
+
+        // A script URL can't be pinned, and leaves the current pin alone
+        let mut req = tide::http::Request::post("http://localhost/url-webhook/pin");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body(json!({"url": "javascript:alert(1)"}));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert_eq!(PINNED_URL.lock().unwrap().as_deref(), Some("https://example.com/featured"));
+
+        let mut req = tide::http::Request::delete("http://localhost/url-webhook/pin");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(current_urls(Some(1)).into_iter().map(|entry| entry.url).collect::<Vec<_>>(), vec!["https://example.com/after-pin/2"]);
+    }
+
+    #[test]
+    fn only_the_configured_headers_are_captured() {
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header("X-App-Name", "Safari");
+        req.insert_header("X-Device", "phone");
+        req.insert_header("User-Agent", "Shortcuts/1.0");
+
+        let names = vec!["x-app-name".to_string(), "X-Device".to_string(), "X-Missing".to_string()];
+        let headers = capture_headers(req.as_ref(), &names);
+        assert_eq!(headers, HashMap::from([
+            ("x-app-name".to_string(), "Safari".to_string()),
+            ("X-Device".to_string(), "phone".to_string()),
+        ]));
+
+        assert!(capture_headers(req.as_ref(), &[]).is_empty());
+    }
+
+    #[async_std::test]
+    async fn a_repeated_url_moves_to_the_back_instead_of_duplicating() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/url-webhook").post(log_url);
+        for name in ["first", "second", "third"] {
+            queue_url(UrlEntry { url: format!("https://example.com/dedup/{}", name), source: None, headers: HashMap::new() });
+        }
+
+        let mut req = tide::http::Request::post("http://localhost/url-webhook");
+        req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+        req.set_body(" https://example.com/dedup/first\n");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let urls: Vec<String> = LAST_READ_URLS.lock().unwrap().iter().map(|entry| entry.url.clone()).collect();
+        assert_eq!(urls[urls.len() - 3..], [
+            "https://example.com/dedup/second".to_string(),
+            "https://example.com/dedup/third".to_string(),
+            "https://example.com/dedup/first".to_string(),
+        ]);
+        assert_eq!(urls.iter().filter(|url| url.as_str() == "https://example.com/dedup/first").count(), 1);
+    }
+
+    #[async_std::test]
+    async fn values_that_are_not_web_urls_are_rejected() {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut app = tide::new();
+        app.at("/url-webhook").post(log_url);
+        let before: Vec<String> = LAST_READ_URLS.lock().unwrap().iter().map(|entry| entry.url.clone()).collect();
+
+        for value in ["not a url", "javascript:alert(1)", "ftp://example.com/file", "/relative/path", ""] {
+            let mut req = tide::http::Request::post("http://localhost/url-webhook");
+            req.insert_header(auth::API_KEY_HEADER.as_str(), test_support::credential());
+            req.set_body(value);
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BadRequest, "{}", value);
+            let body: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(body, json!({ "error": "Not a valid absolute http(s) URL" }));
+        }
+
+        let after: Vec<String> = LAST_READ_URLS.lock().unwrap().iter().map(|entry| entry.url.clone()).collect();
+        assert_eq!(after, before);
+    }
+}